@@ -1,8 +1,527 @@
-use wg_rs::device::Device;
+//! The wg-rs daemon: creates the interface, applies its configuration and serves the control
+//! socket until SIGINT or SIGTERM.
+//!
+//! The interface can be handed over already open as the descriptor in `WG_TUN_FD`, and the
+//! control socket as `WG_UAPI_FD`, like wireguard-go. Under systemd, sockets passed with
+//! socket activation are used for the listen port and the control socket, and readiness,
+//! reloads, shutdown and the watchdog are reported with `sd_notify`.
+//!
+//! Exits with 0 after a clean shutdown, 1 when the device cannot be set up or does not shut
+//! down in time, and 2 on invalid arguments.
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-    let _d = Device::new(String::from("utun99")).await.unwrap();
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::net::UnixListener,
+    },
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+    time::Duration,
+};
 
-    std::future::pending::<()>().await;
+use clap::{error::ErrorKind, CommandFactory, Parser, ValueEnum};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing_subscriber::EnvFilter;
+use wg_rs::{
+    device::{
+        api::{ApiAccess, ApiTcp, SOCK_DIR},
+        resolve::{AddressPreference, ResolveOptions},
+        sync::SyncSummary,
+        Device, DeviceConfig, DeviceOptions,
+    },
+    error::WgResult,
+    privileges::{self, Capability, Privileges},
+    systemd::{self, ActivatedSockets},
+    tun::stream::TunStream,
+};
+use zeroize::Zeroizing;
+
+#[derive(Parser)]
+#[command(name = "device", version, about = "Userspace WireGuard daemon")]
+struct Args {
+    /// Name of the TUN interface to create
+    #[arg(default_value = "utun99")]
+    interface: String,
+    /// Configuration in the `wg setconf` format, synced again on SIGHUP
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Runtime worker threads, 1 runs everything on the main thread
+    #[arg(short, long, default_value_t = 1)]
+    threads: usize,
+    /// Log filter such as `info` or `wg_rs=debug`, RUST_LOG when not given
+    #[arg(long)]
+    log_level: Option<String>,
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// Append the logs to this file rather than writing them to stderr
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+    /// Stay in the foreground instead of detaching once the device is up
+    #[arg(short, long)]
+    foreground: bool,
+    /// Directory of the control socket
+    #[arg(long, default_value = SOCK_DIR)]
+    socket_dir: String,
+    /// Path of the control socket, `<socket-dir>/<interface>.sock` by default
+    #[arg(long)]
+    socket: Option<String>,
+    /// Users, by name or id, allowed on the control socket besides root and the daemon's own
+    #[arg(long, value_delimiter = ',')]
+    allow_user: Vec<String>,
+    /// Groups, by name or id, whose members are allowed on the control socket
+    #[arg(long, value_delimiter = ',')]
+    allow_group: Vec<String>,
+    /// Also serve the control protocol over TCP on this address, e.g. 127.0.0.1:51821. Meant
+    /// for labs: the token is the only protection and nothing is encrypted
+    #[arg(long, requires = "uapi_token_file")]
+    uapi_tcp: Option<std::net::SocketAddr>,
+    /// File holding the token TCP clients must send, also the bearer token of the HTTP API
+    #[arg(long)]
+    uapi_token_file: Option<PathBuf>,
+    /// Also accept WireGuard over TCP on the listen port, as `listen_tcp=true` does
+    #[arg(long)]
+    listen_tcp: bool,
+    /// Switch to this user, by name or id, once the device is set up
+    #[arg(long)]
+    user: Option<String>,
+    /// Switch to this group, by name or id, once the device is set up; the user's by default
+    #[arg(long)]
+    group: Option<String>,
+    /// Capabilities to keep after switching user: net_bind_service to reopen the listen port
+    /// below 1024, net_admin to change the fwmark. Only with a single thread
+    #[arg(long, value_delimiter = ',', requires = "user")]
+    keep_caps: Vec<Capability>,
+    /// Refuse system calls the device never needs, such as execve, ptrace and mount
+    #[arg(long)]
+    seccomp: bool,
+    /// Keep the endpoints, handshake timestamps and counters of the peers in this file, so
+    /// they survive restarts
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+    /// Seconds between saves of the state file, which is also saved on shutdown
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    state_interval: u64,
+    /// Seconds between lookups of the hostname endpoints, 0 to look them up only when set or
+    /// when handshakes keep failing
+    #[arg(long, default_value_t = 300)]
+    resolve_interval: u64,
+    /// Unanswered handshake initiations after which a hostname endpoint is looked up again
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u64).range(1..))]
+    resolve_after_failures: u64,
+    /// Address family used for hostnames with both: system, ipv4 or ipv6
+    #[arg(long, default_value = "system")]
+    prefer_family: AddressPreference,
+    /// Serve Prometheus metrics on this address, e.g. 127.0.0.1:9586
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    metrics: Option<std::net::SocketAddr>,
+    /// Serve the JSON management API on this address, e.g. 127.0.0.1:51822, to clients
+    /// sending the token of --uapi-token-file
+    #[cfg(feature = "http-api")]
+    #[arg(long, requires = "uapi_token_file")]
+    http_api: Option<std::net::SocketAddr>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    if !args.keep_caps.is_empty() && args.threads > 1 {
+        // Capabilities are per thread, the runtime workers would not have them
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--keep-caps needs a single thread",
+            )
+            .exit();
+    }
+    if let Err(e) = init_logging(&args) {
+        eprintln!("device: {e}");
+        return ExitCode::FAILURE;
+    }
+
+    // Checked against the pid, so before forking
+    let inherited = match Inherited::take() {
+        Ok(inherited) => inherited,
+        Err(e) => {
+            eprintln!("device: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Forking must happen before the runtime starts its threads
+    let ready = if args.foreground {
+        None
+    } else {
+        match daemonize() {
+            Ok(ready) => Some(ready),
+            Err(e) => {
+                eprintln!("device: failed to detach: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    };
+
+    let runtime = match args.threads {
+        0 | 1 => tokio::runtime::Builder::new_current_thread(),
+        threads => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            builder.worker_threads(threads);
+            builder
+        }
+    }
+    .enable_all()
+    .build();
+    match runtime {
+        Ok(runtime) => runtime.block_on(run(args, inherited, ready)),
+        Err(e) => {
+            tracing::error!(message = "Failed to start the runtime", error = %e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// What whoever started the daemon already opened
+#[derive(Default)]
+struct Inherited {
+    tun: Option<OwnedFd>,
+    sockets: ActivatedSockets,
+    watchdog: Option<Duration>,
+}
+
+impl Inherited {
+    fn take() -> Result<Self, String> {
+        let mut sockets =
+            systemd::activated_sockets().map_err(|e| format!("Invalid socket activation: {e}"))?;
+        if let Some(fd) = inherited_fd("WG_UAPI_FD")? {
+            if sockets.uapi.is_some() {
+                return Err("WG_UAPI_FD given with an activated control socket".to_owned());
+            }
+            sockets.uapi = Some(UnixListener::from(fd));
+        }
+        Ok(Inherited {
+            tun: inherited_fd("WG_TUN_FD")?,
+            sockets,
+            watchdog: systemd::watchdog_interval(),
+        })
+    }
+}
+
+/// The descriptor whose number is in the variable `var`, which is removed
+fn inherited_fd(var: &str) -> Result<Option<OwnedFd>, String> {
+    let Ok(fd) = std::env::var(var) else {
+        return Ok(None);
+    };
+    std::env::remove_var(var);
+    match fd.parse() {
+        Ok(fd) if fd > libc::STDERR_FILENO && unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1 => {
+            // SAFETY: handed to this process for it to own
+            Ok(Some(unsafe { OwnedFd::from_raw_fd(fd) }))
+        }
+        _ => Err(format!("{var} {fd} is not an open descriptor")),
+    }
+}
+
+async fn run(args: Args, inherited: Inherited, ready: Option<File>) -> ExitCode {
+    let watchdog = inherited.watchdog;
+    let activated = !inherited.sockets.udp.is_empty();
+    let device = match setup(&args, inherited).await {
+        Ok(device) => device,
+        Err(e) => {
+            tracing::error!(message = "Failed to set up the device", error = %e);
+            return ExitCode::FAILURE;
+        }
+    };
+    tracing::info!(message = "Device is up", interface = %args.interface);
+    let mut status = "READY=1".to_owned();
+    if let Some(ready) = ready {
+        if let Err(e) = detach(ready) {
+            tracing::warn!(message = "Failed to detach from the terminal", error = %e);
+        }
+        status.push_str(&format!("\nMAINPID={}", std::process::id()));
+    }
+    notify(&status);
+
+    let signals = (
+        signal(SignalKind::hangup()),
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::terminate()),
+    );
+    let (Ok(mut hangup), Ok(mut interrupt), Ok(mut terminate)) = signals else {
+        tracing::error!("Failed to install the signal handlers");
+        device.shutdown().await.ok();
+        return ExitCode::FAILURE;
+    };
+    // Twice per interval, as systemd recommends
+    let mut watchdog = watchdog.map(|interval| tokio::time::interval(interval / 2));
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                if let Some(path) = &args.config {
+                    notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", systemd::monotonic_usec()));
+                    match reload(&device, path, activated).await {
+                        Ok(summary) => tracing::info!(message = "Configuration reloaded", path = %path.display(), %summary),
+                        Err(e) => tracing::warn!(message = "Failed to reload the configuration", error = %e),
+                    }
+                    notify("READY=1");
+                }
+            }
+            _ = async { watchdog.as_mut().unwrap().tick().await }, if watchdog.is_some() => {
+                if !device.is_closing() {
+                    notify("WATCHDOG=1");
+                }
+            }
+            _ = interrupt.recv() => break,
+            _ = terminate.recv() => break,
+        }
+    }
+    tracing::info!("Shutting down");
+    notify("STOPPING=1");
+    match device.shutdown().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            tracing::error!(message = "Failed to shut down cleanly", error = %e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn setup(args: &Args, inherited: Inherited) -> Result<Arc<Device>, String> {
+    let tun = match inherited.tun {
+        Some(fd) => {
+            let tun = TunStream::from_fd(fd).map_err(|e| format!("Invalid WG_TUN_FD: {e}"))?;
+            if tun.name != args.interface {
+                return Err(format!("WG_TUN_FD is {}, not {}", tun.name, args.interface));
+            }
+            Some(tun)
+        }
+        None => None,
+    };
+    let options = DeviceOptions {
+        sock_dir: args.socket_dir.clone(),
+        api_path: args.socket.clone(),
+        api_access: api_access(args)?,
+        api_tcp: api_tcp(args)?,
+        listen_tcp: args.listen_tcp,
+        tun,
+        api_listener: inherited.sockets.uapi,
+    };
+    let device = Device::with_options(args.interface.clone(), options)
+        .await
+        .map_err(|e| format!("Failed to create {}: {e}", args.interface))?;
+    let ActivatedSockets { udp, tcp, .. } = inherited.sockets;
+    let activated = !udp.is_empty();
+    let result = async {
+        device
+            .set_resolve_options(ResolveOptions {
+                interval: (args.resolve_interval > 0)
+                    .then(|| Duration::from_secs(args.resolve_interval)),
+                failed_handshakes: args.resolve_after_failures,
+                preference: args.prefer_family,
+            })
+            .await;
+        if activated {
+            device
+                .use_listen_sockets(udp, tcp)
+                .await
+                .map_err(|e| format!("Failed to use the activated sockets: {e}"))?;
+        }
+        if let Some(path) = &args.config {
+            let mut config = DeviceConfig::from_file(path)
+                .await
+                .map_err(|e| format!("Invalid configuration {}: {e}", path.display()))?;
+            if activated {
+                ignore_listen_port(&mut config);
+            }
+            device
+                .apply_config(config)
+                .await
+                .map_err(|e| format!("Failed to apply {}: {e}", path.display()))?;
+        }
+        if let Some(path) = &args.state_file {
+            let restored = device
+                .load_state_file(path)
+                .await
+                .map_err(|e| format!("Failed to load the state {}: {e}", path.display()))?;
+            tracing::info!(message = "Restored the state", path = %path.display(), peers = restored);
+            device
+                .persist_state(path.clone(), Duration::from_secs(args.state_interval))
+                .await;
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(addr) = args.metrics {
+            let addr = device
+                .serve_metrics(addr)
+                .await
+                .map_err(|e| format!("Failed to serve metrics: {e}"))?;
+            tracing::info!(message = "Serving metrics", url = %format!("http://{addr}/metrics"));
+        }
+        #[cfg(feature = "http-api")]
+        if let (Some(addr), Some(path)) = (args.http_api, &args.uapi_token_file) {
+            let addr = device
+                .serve_http_api(addr, read_token(path)?)
+                .await
+                .map_err(|e| format!("Failed to serve the management API: {e}"))?;
+            tracing::info!(message = "Serving the management API", url = %format!("http://{addr}/v1"));
+        }
+        let privileges = Privileges {
+            user: args.user.clone(),
+            group: args.group.clone(),
+            keep: args.keep_caps.clone(),
+        };
+        privileges::drop_privileges(&privileges)
+            .map_err(|e| format!("Failed to drop privileges: {e}"))?;
+        if args.seccomp {
+            privileges::apply_seccomp().map_err(|e| format!("Failed to apply seccomp: {e}"))?;
+        }
+        Ok(())
+    }
+    .await;
+    match result {
+        Ok(()) => Ok(device),
+        Err(e) => {
+            device.shutdown().await.ok();
+            Err(e)
+        }
+    }
+}
+
+/// Apply the changes to the configuration file, the peers it still lists keep their sessions
+async fn reload(device: &Arc<Device>, path: &Path, activated: bool) -> WgResult<SyncSummary> {
+    let mut config = DeviceConfig::from_file(path).await?;
+    if activated {
+        ignore_listen_port(&mut config);
+    }
+    let filter = std::mem::take(&mut config.filter);
+    let _config = device.lock_config().await;
+    let summary = device.sync_config(config).await?;
+    device.set_filter(filter).await;
+    Ok(summary)
+}
+
+fn api_access(args: &Args) -> Result<ApiAccess, String> {
+    let mut access = ApiAccess::default();
+    for user in &args.allow_user {
+        let (uid, _) = privileges::lookup_user(user).map_err(|e| e.to_string())?;
+        access.uids.push(uid);
+    }
+    for group in &args.allow_group {
+        access
+            .gids
+            .push(privileges::lookup_group(group).map_err(|e| e.to_string())?);
+    }
+    Ok(access)
+}
+
+fn api_tcp(args: &Args) -> Result<Option<ApiTcp>, String> {
+    let (Some(addr), Some(path)) = (args.uapi_tcp, &args.uapi_token_file) else {
+        return Ok(None);
+    };
+    let token = read_token(path)?;
+    Ok(Some(ApiTcp { addr, token }))
+}
+
+fn read_token(path: &Path) -> Result<Zeroizing<String>, String> {
+    let token = std::fs::read_to_string(path)
+        .map(Zeroizing::new)
+        .map_err(|e| format!("Failed to read the token {}: {e}", path.display()))?;
+    let token = Zeroizing::new(token.trim().to_owned());
+    if token.is_empty() {
+        return Err(format!("Empty token in {}", path.display()));
+    }
+    Ok(token)
+}
+
+/// The activated sockets decide the listen port
+fn ignore_listen_port(config: &mut DeviceConfig) {
+    if let Some(port) = config.listen_port.take() {
+        tracing::info!(
+            message = "Ignoring ListenPort, the sockets are activated",
+            port
+        );
+    }
+}
+
+/// Tell systemd, when it started the daemon
+fn notify(state: &str) {
+    if let Err(e) = systemd::notify(state) {
+        tracing::warn!(message = "Failed to notify systemd", error = %e);
+    }
+}
+
+fn init_logging(args: &Args) -> Result<(), String> {
+    let filter = match &args.log_level {
+        Some(level) => EnvFilter::try_new(level),
+        None => EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new("info")),
+    }
+    .map_err(|e| format!("Invalid log level: {e}"))?;
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let result = match (&args.log_file, args.log_format) {
+        (Some(path), format) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("Failed to open {}: {e}", path.display()))?;
+            let builder = builder.with_ansi(false).with_writer(Arc::new(file));
+            match format {
+                LogFormat::Text => builder.try_init(),
+                LogFormat::Json => builder.json().try_init(),
+            }
+        }
+        (None, LogFormat::Text) => builder.with_writer(io::stderr).try_init(),
+        (None, LogFormat::Json) => builder.with_writer(io::stderr).json().try_init(),
+    };
+    result.map_err(|e| e.to_string())
+}
+
+/// Fork into the background. The parent waits until the child reports through the returned
+/// pipe that the device is up, and exits with 0, or with 1 if the child failed first.
+fn daemonize() -> io::Result<File> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (mut reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            drop(reader);
+            if unsafe { libc::setsid() } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(writer)
+        }
+        _ => {
+            drop(writer);
+            let mut status = [0u8];
+            let code = match reader.read(&mut status) {
+                Ok(1) if status[0] == 1 => 0,
+                _ => 1,
+            };
+            std::process::exit(code)
+        }
+    }
+}
+
+/// Release the waiting parent and point the standard streams at /dev/null
+fn detach(mut ready: File) -> io::Result<()> {
+    ready.write_all(&[1])?;
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
 }
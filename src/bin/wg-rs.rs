@@ -0,0 +1,152 @@
+use std::io::Read;
+
+use wg_rs::{
+    device::DeviceConfig,
+    error::{WgError, WgResult},
+    key_bytes::KeyBytes,
+    uapi::{self, set::set_args, show, Client},
+};
+
+const USAGE: &str = "Usage: wg-rs <cmd> [<args>]
+
+  show      Show the current configuration and device information
+  showconf  Show the current configuration of a given interface
+  set       Change the current configuration, add peers, remove peers, or change peers
+  setconf   Apply a configuration file to an interface
+  addconf   Append a configuration file to an interface
+  syncconf  Synchronize a configuration file with an interface
+  genkey    Print a new private key
+  pubkey    Read a private key from stdin and print its public key
+  genpsk    Print a new preshared key
+
+A device serving the control protocol over TCP is reached with WG_RS_UAPI_ADDR=<host:port>
+and its token in WG_RS_UAPI_TOKEN.";
+
+const SHOW_USAGE: &str = "Usage: wg-rs show { <interface> | all | interfaces } \
+[public-key | private-key | listen-port | fwmark | peers | preshared-keys | endpoints | \
+allowed-ips | latest-handshakes | transfer | persistent-keepalive | dump]";
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        [] => show_cmd(&["all"]).await,
+        ["show", rest @ ..] => show_cmd(rest).await,
+        ["showconf", name] => client(name)
+            .get()
+            .await
+            .map(|state| print!("{}", show::showconf(&state)))
+            .map_err(|e| format!("Unable to access interface: {e}")),
+        ["set", name, rest @ ..] => set_cmd(name, rest).await,
+        [cmd @ ("setconf" | "addconf" | "syncconf"), name, path] => conf_cmd(cmd, name, path).await,
+        ["genkey"] => {
+            println!("{}", KeyBytes::generate_private());
+            Ok(())
+        }
+        ["genpsk"] => {
+            println!("{}", KeyBytes::generate_preshared());
+            Ok(())
+        }
+        ["pubkey"] => pubkey().map(|key| println!("{key}")),
+        ["-h" | "--help" | "help"] => {
+            println!("{USAGE}");
+            return;
+        }
+        _ => {
+            eprintln!("{USAGE}");
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("wg-rs: {e}");
+        std::process::exit(1);
+    }
+}
+
+/// The client of device `name`, over TCP when WG_RS_UAPI_ADDR is set
+fn client(name: &str) -> Client {
+    match std::env::var("WG_RS_UAPI_ADDR") {
+        Ok(addr) => Client::tcp(
+            name,
+            addr,
+            std::env::var("WG_RS_UAPI_TOKEN").unwrap_or_default(),
+        ),
+        Err(_) => Client::new(name),
+    }
+}
+
+async fn show_cmd(args: &[&str]) -> Result<(), String> {
+    let (target, field) = match args {
+        [] => ("all", None),
+        [target] => (*target, None),
+        [target, field] if show::FIELDS.contains(field) => (*target, Some(*field)),
+        _ => return Err(SHOW_USAGE.to_owned()),
+    };
+    let names = match target {
+        "interfaces" | "all" => uapi::interfaces()
+            .await
+            .map_err(|e| format!("Unable to list interfaces: {e}"))?,
+        name => vec![name.to_owned()],
+    };
+    if target == "interfaces" {
+        if !names.is_empty() {
+            println!("{}", names.join(" "));
+        }
+        return Ok(());
+    }
+    for (i, name) in names.iter().enumerate() {
+        let state = client(name)
+            .get()
+            .await
+            .map_err(|e| format!("Unable to access interface {name}: {e}"))?;
+        match field {
+            Some(field) => {
+                let output = show::show_field(&state, field, target == "all");
+                print!("{}", output.map_err(|e| e.to_string())?);
+            }
+            None => {
+                if i > 0 {
+                    println!();
+                }
+                print!("{}", show::show(&state));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn set_cmd(name: &str, args: &[&str]) -> Result<(), String> {
+    let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+    let lines = set_args(&args).map_err(|e| e.to_string())?;
+    client(name)
+        .set(&lines)
+        .await
+        .map_err(|e| format!("Unable to modify interface: {e}"))
+}
+
+async fn conf_cmd(cmd: &str, name: &str, path: &str) -> Result<(), String> {
+    let config = DeviceConfig::from_file(path)
+        .await
+        .map_err(|e| format!("Invalid configuration {path}: {e}"))?;
+    let client = client(name);
+    let result: WgResult<()> = match cmd {
+        "setconf" => client.set_config(&config, true).await,
+        "addconf" => client.set_config(&config, false).await,
+        "syncconf" => client.sync_config(&config).await,
+        _ => Err(WgError::InvalidValue(cmd.to_owned())),
+    };
+    result.map_err(|e| format!("Unable to modify interface: {e}"))
+}
+
+fn pubkey() -> Result<KeyBytes, String> {
+    let mut input = String::new();
+    std::io::stdin()
+        .read_to_string(&mut input)
+        .map_err(|e| e.to_string())?;
+    let private_key: KeyBytes = input
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid private key, {e}"))?;
+    Ok(private_key.public_key())
+}
@@ -0,0 +1,325 @@
+//! Configuration file in the `wg setconf` format, extended with `[Rule]` sections for the
+//! packet filter.
+//!
+//! ```conf
+//! [Interface]
+//! PrivateKey = <PRIVATE_KEY>
+//! ListenPort = 51820
+//! FilterPolicy = deny
+//!
+//! [Peer]
+//! PublicKey = <PUBLIC_KEY>
+//! AllowedIPs = 10.0.0.2/32
+//!
+//! [Rule]
+//! Peer = <PUBLIC_KEY>
+//! Destination = 10.1.0.5/32
+//! Protocol = tcp
+//! DestinationPort = 443
+//! Action = allow
+//! ```
+
+use std::{path::Path, str::FromStr};
+
+use ip_network::IpNetwork;
+
+use crate::{
+    device::{
+        allowed_ip::AllowedIP,
+        filter::{parse_port_range, parse_protocol, Action, Filter, Rule},
+        peer::PeerConfig,
+        DeviceConfig,
+    },
+    error::{WgError, WgResult},
+    key_bytes::KeyBytes,
+    secret::Secret,
+};
+
+/// wg-quick settings that are not ours to handle
+const WG_QUICK_KEYS: &[&str] = &[
+    "address",
+    "dns",
+    "mtu",
+    "table",
+    "preup",
+    "postup",
+    "predown",
+    "postdown",
+    "saveconfig",
+];
+
+enum Section {
+    Interface,
+    Peer(PeerConfig),
+    Rule(Rule),
+}
+
+impl DeviceConfig {
+    pub async fn from_file<P: AsRef<Path>>(path: P) -> WgResult<Self> {
+        tokio::fs::read_to_string(path).await?.parse()
+    }
+
+    /// The `set=1` request applying this config. With `replace_peers` the peers it does not
+    /// list are removed, like `wg setconf` does, otherwise they are kept like `wg addconf`.
+    pub fn to_uapi(&self, replace_peers: bool) -> Vec<String> {
+        let mut lines = Vec::new();
+        if let Some(private_key) = &self.private_key {
+            lines.push(format!(
+                "private_key={}",
+                KeyBytes(*private_key.expose()).to_hex()
+            ));
+        }
+        if let Some(listen_port) = self.listen_port {
+            lines.push(format!("listen_port={listen_port}"));
+        }
+        if replace_peers {
+            lines.push("replace_peers=true".to_owned());
+        }
+        for peer in &self.peers {
+            lines.extend(peer.to_uapi());
+        }
+        lines
+    }
+
+    fn finish(&mut self, section: Option<Section>) -> Result<(), String> {
+        match section {
+            Some(Section::Peer(peer)) if peer.pub_key.as_bytes() == &[0; 32] => {
+                return Err("Peer without PublicKey".to_owned())
+            }
+            Some(Section::Peer(peer)) => self.peers.push(peer),
+            Some(Section::Rule(rule)) => self.filter.rules.push(rule),
+            Some(Section::Interface) | None => {}
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for DeviceConfig {
+    type Err = WgError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = DeviceConfig {
+            peers: Vec::new(),
+            private_key: None,
+            public_key: None,
+            listen_port: None,
+            filter: Filter::default(),
+        };
+        let mut section = None;
+        let mut section_line = 0;
+        for (number, line) in s.lines().enumerate() {
+            let invalid = |message: String| WgError::InvalidConfig(number + 1, message);
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                config
+                    .finish(section.take())
+                    .map_err(|e| WgError::InvalidConfig(section_line, e))?;
+                section_line = number + 1;
+                section = Some(match name.trim().to_ascii_lowercase().as_str() {
+                    "interface" => Section::Interface,
+                    "peer" => {
+                        // Like `wg setconf`, the file lists all allowed IPs of the peer
+                        let mut peer = PeerConfig::new([0; 32].into());
+                        peer.replace_ips(true);
+                        Section::Peer(peer)
+                    }
+                    "rule" => Section::Rule(Rule::new(Action::Allow)),
+                    _ => return Err(invalid(format!("Unknown section {name}"))),
+                });
+                continue;
+            }
+            let (key, val) = line
+                .split_once('=')
+                .map(|(key, val)| (key.trim().to_ascii_lowercase(), val.trim()))
+                .ok_or_else(|| invalid("Expected key = value".to_owned()))?;
+            match &mut section {
+                None => return Err(invalid("Setting outside of a section".to_owned())),
+                Some(Section::Interface) => {
+                    set_interface(&mut config, &key, val).map_err(invalid)?
+                }
+                Some(Section::Peer(peer)) => set_peer(peer, &key, val).map_err(invalid)?,
+                Some(Section::Rule(rule)) => set_rule(rule, &key, val).map_err(invalid)?,
+            }
+        }
+        config
+            .finish(section)
+            .map_err(|e| WgError::InvalidConfig(section_line, e))?;
+        Ok(config)
+    }
+}
+
+fn parse_key(val: &str) -> Result<[u8; 32], String> {
+    val.parse::<KeyBytes>()
+        .map(|key| key.0)
+        .map_err(|e| e.to_owned())
+}
+
+fn parse_network(val: &str) -> Result<IpNetwork, String> {
+    let AllowedIP { addr, cidr } = val.parse()?;
+    IpNetwork::new_truncate(addr, cidr).map_err(|e| e.to_string())
+}
+
+fn set_interface(config: &mut DeviceConfig, key: &str, val: &str) -> Result<(), String> {
+    match key {
+        "privatekey" => {
+            let private_key = val.parse::<KeyBytes>()?;
+            config.public_key = Some(private_key.public_key().0);
+            config.private_key = Some(Secret::new(private_key.0));
+        }
+        "listenport" => {
+            config.listen_port = Some(val.parse().map_err(|_| "Invalid ListenPort")?);
+        }
+        "filterpolicy" => config.filter.policy = val.parse()?,
+        "fwmark" => {}
+        _ if WG_QUICK_KEYS.contains(&key) => {}
+        _ => return Err(format!("Unknown Interface setting {key}")),
+    }
+    Ok(())
+}
+
+fn set_peer(peer: &mut PeerConfig, key: &str, val: &str) -> Result<(), String> {
+    match key {
+        "publickey" => peer.pub_key = parse_key(val)?.into(),
+        "presharedkey" => peer.preshared_key(parse_key(val)?),
+        "endpoint" => peer.parse_endpoint(val)?,
+        "allowedips" => {
+            for ip in val.split(',').map(str::trim).filter(|ip| !ip.is_empty()) {
+                peer.allowed_ips.push(ip.parse()?);
+            }
+        }
+        "persistentkeepalive" => match val {
+            "off" => {}
+            _ => peer.keepalive(val.parse().map_err(|_| "Invalid PersistentKeepalive")?),
+        },
+        _ => return Err(format!("Unknown Peer setting {key}")),
+    }
+    Ok(())
+}
+
+fn set_rule(rule: &mut Rule, key: &str, val: &str) -> Result<(), String> {
+    match key {
+        "action" => rule.action = val.parse()?,
+        "peer" => rule.peer = Some(parse_key(val)?.into()),
+        "direction" => rule.direction = Some(val.parse()?),
+        "source" => rule.source = Some(parse_network(val)?),
+        "destination" => rule.destination = Some(parse_network(val)?),
+        "protocol" => rule.protocol = Some(parse_protocol(val)?),
+        "sourceport" => rule.source_port = Some(parse_port_range(val)?),
+        "destinationport" | "port" => rule.destination_port = Some(parse_port_range(val)?),
+        _ => return Err(format!("Unknown Rule setting {key}")),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{device::filter::Direction, tun::header::PROTOCOL_TCP};
+
+    use super::*;
+
+    const KEY_A: &str = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=";
+    const KEY_B: &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=";
+
+    #[test]
+    fn parse_config() {
+        let config: DeviceConfig = format!(
+            "[Interface]
+            PrivateKey = {KEY_A}
+            ListenPort = 51820
+            Address = 10.0.0.1/24 # wg-quick only
+            FilterPolicy = deny
+
+            [Peer]
+            PublicKey = {KEY_B}
+            Endpoint = tcp://192.0.2.1:443
+            AllowedIPs = 10.0.0.2/32, fd00::2/128
+            PersistentKeepalive = 25
+
+            [Rule]
+            Peer = {KEY_B}
+            Direction = in
+            Destination = 10.1.0.5/32
+            Protocol = tcp
+            DestinationPort = 443
+            Action = allow"
+        )
+        .parse()
+        .unwrap();
+
+        assert_eq!(config.listen_port, Some(51820));
+        assert_eq!(
+            config.private_key.unwrap().expose(),
+            &parse_key(KEY_A).unwrap()
+        );
+        assert_eq!(config.peers.len(), 1);
+        let peer = &config.peers[0];
+        assert_eq!(peer.pub_key.to_bytes(), parse_key(KEY_B).unwrap());
+        assert_eq!(peer.endpoint, Some("tcp://192.0.2.1:443".parse().unwrap()));
+        assert_eq!(peer.allowed_ips.len(), 2);
+        assert_eq!(peer.keepalive, Some(25));
+
+        assert_eq!(config.filter.policy, Action::Deny);
+        let rule = &config.filter.rules[0];
+        assert_eq!(rule.direction, Some(Direction::In));
+        assert_eq!(rule.protocol, Some(PROTOCOL_TCP));
+        assert_eq!(rule.destination_port, Some(443..=443));
+    }
+
+    #[test]
+    fn hostname_endpoint() {
+        let config: DeviceConfig = format!(
+            "[Peer]
+            PublicKey = {KEY_B}
+            Endpoint = vpn.example.com:51820"
+        )
+        .parse()
+        .unwrap();
+        let peer = &config.peers[0];
+        assert_eq!(peer.endpoint, None);
+        assert_eq!(
+            peer.hostname,
+            Some("vpn.example.com:51820".parse().unwrap())
+        );
+        let missing_port = format!("[Peer]\nPublicKey = {KEY_B}\nEndpoint = vpn.example.com");
+        assert!(missing_port.parse::<DeviceConfig>().is_err());
+    }
+
+    #[test]
+    fn candidate_endpoints() {
+        let config: DeviceConfig = format!(
+            "[Peer]
+            PublicKey = {KEY_B}
+            Endpoint = [2001:db8::1]:51820, 192.0.2.1:51820"
+        )
+        .parse()
+        .unwrap();
+        let peer = &config.peers[0];
+        assert_eq!(peer.endpoint, Some("[2001:db8::1]:51820".parse().unwrap()));
+        assert_eq!(peer.candidates.len(), 2);
+        let with_host =
+            format!("[Peer]\nPublicKey = {KEY_B}\nEndpoint = vpn.example.com:1, 192.0.2.1:1");
+        assert!(with_host.parse::<DeviceConfig>().is_err());
+    }
+
+    #[test]
+    fn reject_invalid() {
+        for config in [
+            "ListenPort = 1",
+            "[Interface]\nListenPort = x",
+            "[Peer]\nBogus = 1",
+            "[Rule]\nAction = maybe",
+            "[Wat]",
+            "[Peer]\nAllowedIPs = 10.0.0.2/32",
+        ] {
+            assert!(config.parse::<DeviceConfig>().is_err(), "{config}");
+        }
+        let err = "[Interface]\n\n[Rule]\nPort = 2-1"
+            .parse::<DeviceConfig>()
+            .err()
+            .unwrap();
+        assert!(matches!(err, WgError::InvalidConfig(4, _)));
+    }
+}
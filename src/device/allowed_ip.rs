@@ -1,4 +1,6 @@
-use std::{net::IpAddr, str::FromStr};
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use ip_network::IpNetwork;
 
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
 pub struct AllowedIP {
@@ -6,6 +8,19 @@ pub struct AllowedIP {
     pub cidr: u8,
 }
 
+impl AllowedIP {
+    /// The network, `None` when the prefix is longer than the address
+    pub fn network(&self) -> Option<IpNetwork> {
+        IpNetwork::new_truncate(self.addr, self.cidr).ok()
+    }
+}
+
+impl fmt::Display for AllowedIP {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.cidr)
+    }
+}
+
 impl FromStr for AllowedIP {
     type Err = String;
 
@@ -1,41 +1,506 @@
-use futures_util::stream::SplitStream;
+use std::{future::Future, net::SocketAddr, os::unix::fs::PermissionsExt};
+
+use futures_util::{stream::SplitStream, Stream};
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
-    net::UnixListener,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    sync::Semaphore,
 };
+use tokio_util::codec::LinesCodecError;
+use zeroize::Zeroizing;
+
+use crate::{key_bytes::KeyBytes, secret::Secret, x25519};
 
-use crate::{key_bytes::KeyBytes, x25519};
+use super::{
+    capture::CaptureConfig,
+    obfuscation::ObfuscationConfig,
+    relay::{Relay, RelayMode},
+};
 
 use super::*;
 
-const SOCK_DIR: &str = "/var/run/wireguard/";
+/// Where the control sockets of the devices are created
+pub const SOCK_DIR: &str = "/var/run/wireguard";
 
-impl Device {
-    pub async fn create_api_listener(&self) -> WgResult<(UnixListener, String)> {
-        let _ = tokio::fs::create_dir_all(SOCK_DIR).await;
-        let path = format!("{}/{}.sock", SOCK_DIR, self.name);
-        let _ = tokio::fs::remove_file(&path).await;
-        let api_listener = tokio::net::UnixListener::bind(&path)?;
-        Ok((api_listener, path))
+/// The control socket of device `name`
+pub fn socket_path(name: &str) -> String {
+    socket_path_in(SOCK_DIR, name)
+}
+
+/// The control socket of device `name` in `sock_dir` instead of [`SOCK_DIR`]
+pub fn socket_path_in(sock_dir: &str, name: &str) -> String {
+    format!("{sock_dir}/{name}.sock")
+}
+
+/// Answer `EACCES` without reading the request, which is drained for a moment so that the
+/// client gets to read the answer rather than a reset connection
+async fn refuse<S: AsyncRead + AsyncWrite + Unpin>(mut conn: S) {
+    let answer = format!("errno={}\n\n", libc::EACCES);
+    if conn.write_all(answer.as_bytes()).await.is_err() || conn.shutdown().await.is_err() {
+        return;
+    }
+    let mut sink = tokio::io::sink();
+    let _ = tokio::time::timeout(TOKEN_TIMEOUT, tokio::io::copy(&mut conn, &mut sink)).await;
+}
+
+/// The output of `future`, none if the device is closed first
+pub(super) async fn unless_closed<F: Future>(
+    close_receiver: &mut tokio::sync::broadcast::Receiver<()>,
+    future: F,
+) -> Option<F::Output> {
+    tokio::select! {
+        output = future => Some(output),
+        _ = close_receiver.recv() => None,
+    }
+}
+
+/// Wait for the client of a capture to hang up or the capture to stop, whatever the client
+/// sends is ignored
+async fn capture_ended<R: AsyncRead + Unpin>(mut reader: R, stopped: impl Future<Output = ()>) {
+    let mut sink = tokio::io::sink();
+    tokio::select! {
+        _ = tokio::io::copy(&mut reader, &mut sink) => {}
+        _ = stopped => {}
+    }
+}
+
+/// Read the lines of a request up to the empty one ending it
+async fn read_request<R>(reader: &mut R) -> Result<Vec<String>, i32>
+where
+    R: Stream<Item = Result<String, LinesCodecError>> + Unpin,
+{
+    let (mut lines, mut size) = (Vec::new(), 0);
+    loop {
+        match reader.next().await {
+            Some(Ok(line)) if line.is_empty() => return Ok(lines),
+            Some(Ok(line)) => {
+                size += line.len() + 1;
+                if size > MAX_API_REQUEST {
+                    return Err(libc::EMSGSIZE);
+                }
+                lines.push(line);
+            }
+            Some(Err(_)) | None => return Err(libc::EPROTO),
+        }
     }
+}
 
-    pub async fn api_get<S: AsyncRead + AsyncWrite>(
+/// Write the lines answering a request and its status, false if the client is gone or does
+/// not read them within [`API_TIMEOUT`]
+async fn send_reply<S>(
+    writer: &mut SplitSink<Framed<S, LinesCodec>, String>,
+    lines: Vec<String>,
+    status: i32,
+) -> bool
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let lines = lines
+        .into_iter()
+        .chain(Some(format!("errno={}\n", status)))
+        .map(Ok);
+    let mut lines = futures_util::stream::iter(lines);
+    let send = writer.send_all(&mut lines);
+    matches!(tokio::time::timeout(API_TIMEOUT, send).await, Ok(Ok(())))
+}
+
+/// Read the `token=` line a byte at a time, so nothing after it is consumed
+async fn check_token(conn: &mut TcpStream, token: &str) -> std::io::Result<bool> {
+    let mut line = Zeroizing::new(Vec::new());
+    loop {
+        let byte = conn.read_u8().await?;
+        if byte == b'\n' {
+            break;
+        }
+        if line.len() == MAX_TOKEN_LINE {
+            return Ok(false);
+        }
+        line.push(byte);
+    }
+    let Some(given) = line.strip_prefix(b"token=") else {
+        return Ok(false);
+    };
+    Ok(ring::constant_time::verify_slices_are_equal(given, token.as_bytes()).is_ok())
+}
+
+/// How long a control connection may stay idle between requests
+pub const API_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a client has to send the rest of a request once it started, and to read the answer
+pub const API_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest line of a control request
+const MAX_API_LINE: usize = 4096;
+/// Largest control request, in bytes
+const MAX_API_REQUEST: usize = 16 << 20;
+/// How long a control connection over TCP has to send its token
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest token line accepted
+const MAX_TOKEN_LINE: usize = 512;
+/// Control connections over TCP that may be waiting to send their token at once
+const MAX_UNAUTHENTICATED: usize = 16;
+/// Longest wait before accepting again after accepting a control connection failed
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Who may use the control socket, checked with the credentials of the connecting process.
+///
+/// Root and the user running the device always may. When no uid or gid is listed, anyone
+/// who can open the socket may, and the socket is only accessible to its owner.
+#[derive(Clone, Debug, Default)]
+pub struct ApiAccess {
+    pub uids: Vec<u32>,
+    /// Primary groups of the connecting processes
+    pub gids: Vec<u32>,
+}
+
+impl ApiAccess {
+    /// Whether anyone besides root and the device's user is allowed
+    fn is_shared(&self) -> bool {
+        !self.uids.is_empty() || !self.gids.is_empty()
+    }
+
+    /// Whether a process running as `uid` with the primary group `gid` may connect
+    pub fn allows(&self, uid: u32, gid: u32) -> bool {
+        !self.is_shared()
+            || uid == 0
+            || uid == unsafe { libc::geteuid() }
+            || self.uids.contains(&uid)
+            || self.gids.contains(&gid)
+    }
+}
+
+/// The control protocol over TCP, for remote management of lab setups. The connection must
+/// start with a `token=<TOKEN>` line; there is no encryption.
+#[derive(Clone)]
+pub struct ApiTcp {
+    pub addr: SocketAddr,
+    pub token: Zeroizing<String>,
+}
+
+impl Device {
+    /// Bind the control socket at `path`. The directories created for it are only accessible
+    /// to their owner, and the socket too unless `access` lets other users in.
+    pub async fn create_api_listener(
         &self,
-        _writer: &mut SplitSink<Framed<S, LinesCodec>, String>,
-    ) -> i32 {
-        // TODO
-        println!("api_get");
-        0
+        path: &str,
+        access: &ApiAccess,
+    ) -> WgResult<UnixListener> {
+        let (dir_mode, sock_mode) = match access.is_shared() {
+            true => (0o755, 0o666),
+            false => (0o700, 0o600),
+        };
+        if let Some(dir) = std::path::Path::new(path).parent() {
+            let _ = tokio::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(dir_mode)
+                .create(dir)
+                .await;
+        }
+        // Left behind by a device that could not remove it on exit
+        match tokio::fs::remove_file(path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                tracing::warn!(message = "Failed to remove the old control socket", path, error = %e);
+            }
+            _ => {}
+        }
+        let api_listener = tokio::net::UnixListener::bind(path)?;
+        tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(sock_mode)).await?;
+        Ok(api_listener)
     }
 
-    pub async fn api_set<S: AsyncRead + AsyncWrite>(
-        self: &Arc<Self>,
+    /// Accept control connections on `listener`, serving the ones that send the right token.
+    /// At most [`MAX_UNAUTHENTICATED`] of them are waiting for their token check at once, and a
+    /// failed accept is retried after a backoff.
+    pub(super) fn serve_api_tcp(self: &Arc<Self>, listener: TcpListener, token: Zeroizing<String>) {
+        let device = Arc::clone(self);
+        let mut close = self.close_sender.subscribe();
+        let token = Arc::new(token);
+        let unauthenticated = Arc::new(Semaphore::new(MAX_UNAUTHENTICATED));
+        self.tasks.spawn(async move {
+            let mut backoff = Duration::from_millis(10);
+            loop {
+                // Leave further connections in the backlog until a token check is done
+                let permit = tokio::select! {
+                    permit = Arc::clone(&unauthenticated).acquire_owned() => match permit {
+                        Ok(permit) => permit,
+                        Err(_) => break,
+                    },
+                    _ = close.recv() => break,
+                };
+                let accepted = tokio::select! {
+                    accepted = listener.accept() => accepted,
+                    _ = close.recv() => break,
+                };
+                let (mut conn, addr) = match accepted {
+                    Ok(accepted) => {
+                        backoff = Duration::from_millis(10);
+                        accepted
+                    }
+                    Err(e) => {
+                        tracing::warn!(message = "Control accept failed", error = ?e, retry_in = ?backoff);
+                        tokio::select! {
+                            _ = tokio::time::sleep(backoff) => {}
+                            _ = close.recv() => break,
+                        }
+                        backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                        continue;
+                    }
+                };
+                let (device, token) = (Arc::clone(&device), Arc::clone(&token));
+                device.tasks.clone().spawn(async move {
+                    let checked = tokio::time::timeout(TOKEN_TIMEOUT, check_token(&mut conn, &token));
+                    match checked.await {
+                        Ok(Ok(true)) => {
+                            drop(permit);
+                            device.handle_api_conn(conn).await;
+                        }
+                        _ => {
+                            tracing::warn!(message = "Control connection refused, wrong token", %addr);
+                            refuse(conn).await;
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    /// Serve a connection to the control socket in its own task, if `api_access` allows its
+    /// process
+    pub(super) fn accept_api_conn(self: &Arc<Self>, api_conn: UnixStream) {
+        match api_conn.peer_cred() {
+            Ok(cred) if self.api_access.allows(cred.uid(), cred.gid()) => {
+                self.tasks.spawn(Arc::clone(self).handle_api_conn(api_conn));
+            }
+            cred => {
+                let uid = cred.ok().map(|cred| cred.uid());
+                tracing::warn!(message = "Control connection refused", ?uid);
+                self.tasks.spawn(refuse(api_conn));
+            }
+        }
+    }
+
+    /// Serve the requests on a control connection until the client hangs up, stays idle for
+    /// [`API_IDLE_TIMEOUT`] or the device is closed. Each request is read whole before it is
+    /// applied under the configuration lock, so a slow client holds up neither the device nor
+    /// the other clients.
+    pub(super) async fn handle_api_conn<S>(self: Arc<Self>, api_conn: S)
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let codec = LinesCodec::new_with_max_length(MAX_API_LINE);
+        let (mut api_writer, mut api_reader) = Framed::new(api_conn, codec).split::<String>();
+        let mut close_receiver = self.close_sender.subscribe();
+        if self.is_closing() {
+            return;
+        }
+        loop {
+            let next = api_reader.next();
+            let operation = unless_closed(
+                &mut close_receiver,
+                tokio::time::timeout(API_IDLE_TIMEOUT, next),
+            );
+            let Some(Ok(Some(Ok(operation)))) = operation.await else {
+                return;
+            };
+            let request = tokio::time::timeout(API_TIMEOUT, read_request(&mut api_reader));
+            let mut lines = match unless_closed(&mut close_receiver, request).await {
+                Some(Ok(Ok(lines))) => lines.into_iter(),
+                Some(Ok(Err(status))) => {
+                    send_reply(&mut api_writer, Vec::new(), status).await;
+                    return;
+                }
+                Some(Err(_)) => {
+                    tracing::warn!(message = "Control request timed out", device = %self.name);
+                    return;
+                }
+                None => return,
+            };
+            let (reply, status) = match operation.as_str() {
+                // Streams until the client hangs up
+                "subscribe=1" if lines.as_slice().is_empty() => {
+                    let status = self.api_subscribe(&mut api_writer, &mut api_reader).await;
+                    api_writer.send(format!("errno={}\n", status)).await.ok();
+                    return;
+                }
+                "subscribe=1" => (Vec::new(), libc::EINVAL),
+                "capture=1" => {
+                    match self.api_capture(&mut lines) {
+                        Ok(config) => {
+                            if let Ok(conn) = api_writer.reunite(api_reader) {
+                                let (reader, writer) = tokio::io::split(conn.into_inner());
+                                let capture = self.start_capture(config, writer).await;
+                                if let Some(stopped) = capture.upgrade().map(|c| c.stopped()) {
+                                    let ended = capture_ended(reader, stopped);
+                                    unless_closed(&mut close_receiver, ended).await;
+                                }
+                                self.end_capture(&capture).await;
+                            }
+                        }
+                        Err(status) => {
+                            send_reply(&mut api_writer, Vec::new(), status).await;
+                        }
+                    }
+                    // The pcapng stream replaces the line protocol
+                    return;
+                }
+                "get=1" => match self.api_get_options(&mut lines) {
+                    Ok(reveal_keys) => {
+                        let _config = self.config_lock.lock().await;
+                        (self.api_get(reveal_keys).await, 0)
+                    }
+                    Err(status) => (Vec::new(), status),
+                },
+                "set=1" => {
+                    let _config = self.config_lock.lock().await;
+                    (Vec::new(), self.api_set(&mut lines).await)
+                }
+                "sync=1" => {
+                    let _config = self.config_lock.lock().await;
+                    (Vec::new(), self.api_sync(&mut lines).await)
+                }
+                _ => (Vec::new(), libc::EIO),
+            };
+            let sent = send_reply(&mut api_writer, reply, status);
+            if unless_closed(&mut close_receiver, sent).await != Some(true) {
+                return;
+            }
+        }
+    }
+
+    /// Wait for the configuration changes in progress, such as control requests, and hold off
+    /// new ones until the guard is dropped
+    pub async fn lock_config(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.config_lock.lock().await
+    }
+
+    /// Read the options of a get, returns whether the private and preshared keys are wanted
+    pub fn api_get_options(&self, lines: &mut impl Iterator<Item = String>) -> Result<bool, i32> {
+        let mut reveal_keys = false;
+        for cmd in lines {
+            match cmd.split_once('=').ok_or(libc::EPROTO)? {
+                ("reveal_keys", val) => reveal_keys = val.parse().map_err(|_| libc::EINVAL)?,
+                _ => return Err(libc::EINVAL),
+            }
+        }
+        Ok(reveal_keys)
+    }
+
+    /// The device configuration and peer statistics, the private and preshared keys only with
+    /// `reveal_keys`
+    pub async fn api_get(&self, reveal_keys: bool) -> Vec<String> {
+        let mut lines = Vec::new();
+        match self.key_pair.read().await.as_ref() {
+            Some((private_key, _)) if reveal_keys => {
+                lines.push(format!(
+                    "private_key={}",
+                    KeyBytes(private_key.to_bytes()).to_hex()
+                ));
+            }
+            _ => {}
+        }
+        lines.push(format!(
+            "listen_port={}",
+            self.listen_port.load(std::sync::atomic::Ordering::Relaxed)
+        ));
+
+        let peers: Vec<_> = self.peers.iter().map(|e| e.value().clone()).collect();
+        for peer in peers {
+            let p = peer.lock().await;
+            let stats = p.tunnel.stats();
+            lines.push(format!(
+                "public_key={}",
+                KeyBytes(p.pub_key.to_bytes()).to_hex()
+            ));
+            match &p.preshared_key {
+                Some(preshared_key) if reveal_keys => {
+                    lines.push(format!(
+                        "preshared_key={}",
+                        KeyBytes(*preshared_key.expose()).to_hex()
+                    ));
+                }
+                _ => {}
+            }
+            lines.push("protocol_version=1".to_owned());
+            if let Some(endpoint) = p.addr {
+                lines.push(format!("endpoint={endpoint}"));
+            }
+            let last_handshake = stats
+                .last_handshake
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .unwrap_or_default();
+            lines.push(format!(
+                "last_handshake_time_sec={}",
+                last_handshake.as_secs()
+            ));
+            lines.push(format!(
+                "last_handshake_time_nsec={}",
+                last_handshake.subsec_nanos()
+            ));
+            lines.push(format!("rx_bytes={}", stats.rx_bytes));
+            lines.push(format!("tx_bytes={}", stats.tx_bytes));
+            lines.push(format!(
+                "persistent_keepalive_interval={}",
+                p.tunnel.persistent_keepalive().unwrap_or(0)
+            ));
+            for (network, _) in p.allowed_ips.iter() {
+                lines.push(format!("allowed_ip={network}"));
+            }
+        }
+
+        lines
+    }
+
+    /// Write every event as a block of lines ended by an empty one, until the client sends
+    /// anything or hangs up, or the device is closed
+    pub async fn api_subscribe<S: AsyncRead + AsyncWrite>(
+        &self,
+        writer: &mut SplitSink<Framed<S, LinesCodec>, String>,
         reader: &mut SplitStream<Framed<S, LinesCodec>>,
     ) -> i32 {
-        while let Some(Ok(cmd)) = reader.next().await {
-            if cmd.is_empty() {
-                return 0; // Done
+        let events = self.subscribe();
+        futures_util::pin_mut!(events);
+        let mut close_receiver = self.close_sender.subscribe();
+        if self.is_closing() {
+            return 0;
+        }
+        loop {
+            tokio::select! {
+                Some(event) = events.next() => {
+                    for line in event.to_uapi().into_iter().chain(Some(String::new())) {
+                        if writer.send(line).await.is_err() {
+                            return libc::EIO;
+                        }
+                    }
+                }
+                _ = reader.next() => return 0,
+                _ = close_receiver.recv() => return 0,
+                else => return 0,
+            }
+        }
+    }
+
+    /// Read the options of a capture, the pcapng stream then replaces the line protocol
+    pub fn api_capture(
+        &self,
+        lines: &mut impl Iterator<Item = String>,
+    ) -> Result<CaptureConfig, i32> {
+        let mut config = CaptureConfig::default();
+        for cmd in lines {
+            let (key, val) = cmd.split_once('=').ok_or(libc::EPROTO)?;
+            match key {
+                "plaintext" => config.plaintext = val.parse().map_err(|_| libc::EINVAL)?,
+                "ciphertext" => config.ciphertext = val.parse().map_err(|_| libc::EINVAL)?,
+                "key_log" => config.key_log = val.parse().map_err(|_| libc::EINVAL)?,
+                "public_key" => match val.parse::<KeyBytes>() {
+                    Ok(key_bytes) => config.peer = Some(key_bytes.0.into()),
+                    Err(_) => return Err(libc::EINVAL),
+                },
+                _ => return Err(libc::EINVAL),
             }
+        }
+        Ok(config)
+    }
+
+    pub async fn api_set(self: &Arc<Self>, lines: &mut impl Iterator<Item = String>) -> i32 {
+        while let Some(cmd) = lines.next() {
             let parsed_cmd: Vec<&str> = cmd.split('=').collect();
             if parsed_cmd.len() != 2 {
                 return libc::EPROTO;
@@ -50,7 +515,7 @@ impl Device {
                 "listen_port" => match val.parse::<u16>() {
                     Ok(port) => match self.open_listen_port(port).await {
                         Ok(()) => {}
-                        Err(_) => return libc::EADDRINUSE,
+                        Err(e) => return e.errno(),
                     },
                     Err(_) => return libc::EINVAL,
                 },
@@ -62,6 +527,17 @@ impl Device {
                     },
                     Err(_) => return libc::EINVAL,
                 },
+                "listen_tcp" => match val.parse::<bool>() {
+                    Ok(enabled) => match self.set_listen_tcp(enabled).await {
+                        Ok(()) => {}
+                        Err(e) => return e.errno(),
+                    },
+                    Err(_) => return libc::EINVAL,
+                },
+                "relay" => match val.parse::<RelayMode>() {
+                    Ok(mode) => self.relay.write().await.mode = mode,
+                    Err(_) => return libc::EINVAL,
+                },
                 "replace_peers" => match val.parse::<bool>() {
                     Ok(true) => self.clear_peers().await,
                     Ok(false) => {}
@@ -71,7 +547,7 @@ impl Device {
                     // Indicates a new peer section
                     Ok(key_bytes) => {
                         return self
-                            .api_set_peer(reader, x25519::PublicKey::from(key_bytes.0))
+                            .api_set_peer(lines, x25519::PublicKey::from(key_bytes.0))
                             .await
                     }
                     Err(_) => return libc::EINVAL,
@@ -82,17 +558,58 @@ impl Device {
         0
     }
 
-    pub async fn api_set_peer<S: AsyncRead + AsyncWrite>(
+    /// Read a whole configuration in the `set=1` format and apply it with
+    /// [`Device::sync_config`]: the peers it does not list are removed, the others keep their
+    /// sessions
+    pub async fn api_sync(self: &Arc<Self>, lines: &mut impl Iterator<Item = String>) -> i32 {
+        let mut config = DeviceConfig {
+            peers: Vec::new(),
+            private_key: None,
+            public_key: None,
+            listen_port: None,
+            filter: Default::default(),
+        };
+        for cmd in lines {
+            let Some((key, val)) = cmd.split_once('=') else {
+                return libc::EPROTO;
+            };
+            let result = match (key, config.peers.last_mut()) {
+                ("private_key", None) => val
+                    .parse::<KeyBytes>()
+                    .map(|key_bytes| config.private_key = Some(Secret::new(key_bytes.0)))
+                    .map_err(|_| libc::EINVAL),
+                ("listen_port", None) => val
+                    .parse()
+                    .map(|port| config.listen_port = Some(port))
+                    .map_err(|_| libc::EINVAL),
+                ("public_key", _) => val
+                    .parse::<KeyBytes>()
+                    .map(|key_bytes| config.peers.push(PeerConfig::new(key_bytes.0.into())))
+                    .map_err(|_| libc::EINVAL),
+                (_, Some(peer)) => set_peer_option(peer, key, val),
+                _ => Err(libc::EINVAL),
+            };
+            if let Err(status) = result {
+                return status;
+            }
+        }
+        match self.sync_config(config).await {
+            Ok(summary) => {
+                tracing::info!(message = "Configuration synced", device = %self.name, %summary);
+                0
+            }
+            Err(e) => e.errno(),
+        }
+    }
+
+    pub async fn api_set_peer(
         &self,
-        reader: &mut SplitStream<Framed<S, LinesCodec>>,
+        lines: &mut impl Iterator<Item = String>,
         pub_key: x25519::PublicKey,
     ) -> i32 {
         let mut config = PeerConfig::new(pub_key);
-        while let Some(Ok(cmd)) = reader.next().await {
-            if cmd.is_empty() {
-                self.update_peer(config).await;
-                return 0; // Done
-            }
+        let mut relay = RelayChanges::default();
+        for cmd in lines {
             let parsed_cmd: Vec<&str> = cmd.splitn(2, '=').collect();
             if parsed_cmd.len() != 2 {
                 return libc::EPROTO;
@@ -104,42 +621,368 @@ impl Device {
                     Ok(false) => config.remove = false,
                     Err(_) => return libc::EINVAL,
                 },
-                "preshared_key" => match val.parse::<KeyBytes>() {
-                    Ok(key_bytes) => config.preshared_key = Some(key_bytes.0),
-                    Err(_) => return libc::EINVAL,
-                },
-                "endpoint" => match val.parse::<SocketAddr>() {
-                    Ok(addr) => config.endpoint = Some(addr),
-                    Err(_) => return libc::EINVAL,
-                },
-                "persistent_keepalive_interval" => match val.parse::<u16>() {
-                    Ok(interval) => config.keepalive = Some(interval),
-                    Err(_) => return libc::EINVAL,
-                },
-                "replace_allowed_ips" => match val.parse::<bool>() {
-                    Ok(true) => config.replace_ips = true,
-                    Ok(false) => config.replace_ips = false,
-                    Err(_) => return libc::EINVAL,
-                },
-                "allowed_ip" => match val.parse::<AllowedIP>() {
-                    Ok(ip) => config.allowed_ips.push(ip),
-                    Err(_) => return libc::EINVAL,
-                },
                 "public_key" => {
                     // Indicates a new peer section. Commit changes for current peer, and continue to next peer
-                    self.update_peer(config).await;
+                    if let Err(e) = self.commit_peer(config, std::mem::take(&mut relay)).await {
+                        return e.errno();
+                    }
                     match val.parse::<KeyBytes>() {
                         Ok(key_bytes) => config = PeerConfig::new(key_bytes.0.into()),
                         Err(_) => return libc::EINVAL,
                     }
                 }
-                "protocol_version" => match val.parse::<u32>() {
-                    Ok(1) => {} // Only version 1 is legal
-                    _ => return libc::EINVAL,
+                "replace_relay" => match val.parse::<bool>() {
+                    Ok(replace) => relay.replace = replace,
+                    Err(_) => return libc::EINVAL,
                 },
-                _ => return libc::EINVAL,
+                "relay_allow" | "relay_deny" => match val.parse::<KeyBytes>() {
+                    Ok(key_bytes) => relay.pairs.push((key_bytes.0.into(), key == "relay_allow")),
+                    Err(_) => return libc::EINVAL,
+                },
+                _ => {
+                    if let Err(status) = set_peer_option(&mut config, key, val) {
+                        return status;
+                    }
+                }
             }
         }
-        0
+        match self.commit_peer(config, relay).await {
+            Ok(()) => 0, // Done
+            Err(e) => e.errno(),
+        }
+    }
+
+    /// Apply a peer section of `set=1`. Its relay ACL changes only take effect once the peer
+    /// itself was accepted, and are moot if it was removed.
+    async fn commit_peer(&self, config: PeerConfig, relay: RelayChanges) -> WgResult<()> {
+        let (pub_key, remove) = (config.pub_key, config.remove);
+        self.update_peer(config).await?;
+        if !remove {
+            relay.apply(&mut *self.relay.write().await, &pub_key);
+        }
+        Ok(())
+    }
+}
+
+/// The `replace_relay`, `relay_allow` and `relay_deny` lines of a peer section, in order
+#[derive(Default)]
+struct RelayChanges {
+    replace: bool,
+    /// The other peer, and whether forwarding to it is allowed
+    pairs: Vec<(x25519::PublicKey, bool)>,
+}
+
+impl RelayChanges {
+    fn apply(self, relay: &mut Relay, from: &x25519::PublicKey) {
+        if self.replace {
+            relay.remove_from(from);
+        }
+        for (to, allow) in self.pairs {
+            match allow {
+                true => relay.allow(from, &to),
+                false => relay.deny(from, &to),
+            }
+        }
+    }
+}
+
+/// Apply a peer setting that is part of its configuration, shared by `set=1` and `sync=1`
+pub(super) fn set_peer_option(config: &mut PeerConfig, key: &str, val: &str) -> Result<(), i32> {
+    match key {
+        "preshared_key" => match val.parse::<KeyBytes>() {
+            Ok(key_bytes) => config.preshared_key = Some(Secret::new(key_bytes.0)),
+            Err(_) => return Err(libc::EINVAL),
+        },
+        "endpoint" => {
+            if config.parse_endpoint(val).is_err() {
+                return Err(libc::EINVAL);
+            }
+        }
+        "persistent_keepalive_interval" => match val.parse::<u16>() {
+            Ok(interval) => config.keepalive = Some(interval),
+            Err(_) => return Err(libc::EINVAL),
+        },
+        "replace_allowed_ips" => match val.parse::<bool>() {
+            Ok(true) => config.replace_ips = true,
+            Ok(false) => config.replace_ips = false,
+            Err(_) => return Err(libc::EINVAL),
+        },
+        "allowed_ip" => match val.parse::<AllowedIP>() {
+            Ok(ip) => config.allowed_ips.push(ip),
+            Err(_) => return Err(libc::EINVAL),
+        },
+        "obfuscation_key" => match val.parse::<KeyBytes>() {
+            Ok(key_bytes) => config.obfuscation(ObfuscationConfig::new(key_bytes.0)),
+            Err(_) => return Err(libc::EINVAL),
+        },
+        "obfuscation_max_padding" => match (config.obfuscation.as_mut(), val.parse()) {
+            (Some(obfuscation), Ok(max_padding)) => obfuscation.max_padding = max_padding,
+            _ => return Err(libc::EINVAL),
+        },
+        "obfuscation_junk_packets" => match (config.obfuscation.as_mut(), val.parse()) {
+            (Some(obfuscation), Ok(junk_packets)) => obfuscation.junk_packets = junk_packets,
+            _ => return Err(libc::EINVAL),
+        },
+        "obfuscation_junk_max_size" => match (config.obfuscation.as_mut(), val.parse()) {
+            (Some(obfuscation), Ok(max_size)) => obfuscation.junk_max_size = max_size,
+            _ => return Err(libc::EINVAL),
+        },
+        "protocol_version" => match val.parse::<u32>() {
+            Ok(1) => {} // Only version 1 is legal
+            _ => return Err(libc::EINVAL),
+        },
+        _ => return Err(libc::EINVAL),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        device::{
+            testing::{device, peer},
+            transport::loopback::LoopbackNetwork,
+            DeviceOptions,
+        },
+        uapi::Client,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn get_reveals_keys_on_request() {
+        let network = LoopbackNetwork::new();
+        let addr_a: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let (a, _) = device("wgloop19", &network, addr_a).await;
+        let key_b = x25519::PublicKey::from([9; 32]);
+        let mut peer_b = peer(key_b, "192.0.2.2:51820".parse().unwrap(), "10.0.0.2/32");
+        peer_b.preshared_key([5; 32]);
+        a.update_peer(peer_b).await.unwrap();
+
+        let get = |request: &'static [u8]| async move {
+            let socket = "/var/run/wireguard/wgloop19.sock";
+            let mut conn = tokio::net::UnixStream::connect(socket).await.unwrap();
+            conn.write_all(request).await.unwrap();
+            // Otherwise the connection stays open for more requests
+            conn.shutdown().await.unwrap();
+            let mut response = String::new();
+            conn.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let hidden = get(b"get=1\n\n").await;
+        assert!(hidden.contains(&format!("public_key={}\n", "09".repeat(32))));
+        assert!(!hidden.contains("private_key="));
+        assert!(!hidden.contains("preshared_key="));
+        assert!(hidden.ends_with("errno=0\n\n"));
+        let revealed = get(b"get=1\nreveal_keys=true\n\n").await;
+        assert!(revealed.contains("private_key="));
+        assert!(revealed.contains(&format!("preshared_key={}\n", "05".repeat(32))));
+
+        a.close();
+    }
+
+    #[tokio::test]
+    async fn control_access() {
+        use std::os::unix::fs::PermissionsExt;
+        use zeroize::Zeroizing;
+
+        use crate::device::api::{ApiAccess, ApiTcp};
+
+        let dir = std::env::temp_dir().join(format!("wg-rs-access-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mode = |path: &std::path::Path| std::fs::metadata(path).unwrap().permissions().mode();
+        let tcp_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let path = dir.join("private/wgloop25.sock");
+        let options = DeviceOptions {
+            api_path: Some(path.to_str().unwrap().to_owned()),
+            api_tcp: Some(ApiTcp {
+                addr: ([127, 0, 0, 1], tcp_port).into(),
+                token: Zeroizing::new("secret".to_owned()),
+            }),
+            ..Default::default()
+        };
+        let device = Device::with_options("wgloop25".to_owned(), options)
+            .await
+            .unwrap();
+        assert_eq!(mode(&path) & 0o777, 0o600);
+        assert_eq!(mode(path.parent().unwrap()) & 0o777, 0o700);
+        let state = Client::with_path("wgloop25", &path).get().await.unwrap();
+
+        let addr = format!("127.0.0.1:{tcp_port}");
+        let remote = Client::tcp("wgloop25", &addr, "secret")
+            .get()
+            .await
+            .unwrap();
+        assert_eq!(remote.listen_port, state.listen_port);
+        let err = Client::tcp("wgloop25", &addr, "guess").get().await.err();
+        assert!(matches!(err, Some(WgError::IO(e)) if e.raw_os_error() == Some(libc::EACCES)));
+        // Connections that never send a token hold up the others, but only so many of them
+        let mut silent = Vec::new();
+        for _ in 0..super::MAX_UNAUTHENTICATED {
+            silent.push(tokio::net::TcpStream::connect(&addr).await.unwrap());
+        }
+        let client = Client::tcp("wgloop25", &addr, "secret");
+        let held = tokio::time::timeout(Duration::from_millis(500), client.get()).await;
+        assert!(held.is_err());
+        drop(silent);
+        client.get().await.unwrap();
+        device.shutdown().await.unwrap();
+        assert!(!path.exists());
+
+        let access = ApiAccess {
+            uids: vec![1000],
+            gids: vec![100],
+        };
+        assert!(access.allows(0, 0));
+        assert!(access.allows(1000, 1000));
+        assert!(access.allows(1001, 100));
+        assert!(!access.allows(1001, 1001));
+        assert!(ApiAccess::default().allows(1001, 1001));
+        // Other users get through the file permissions, the credentials are checked instead
+        let path = dir.join("shared/wgloop25.sock");
+        let options = DeviceOptions {
+            api_path: Some(path.to_str().unwrap().to_owned()),
+            api_access: access,
+            ..Default::default()
+        };
+        let device = Device::with_options("wgloop25".to_owned(), options)
+            .await
+            .unwrap();
+        assert_eq!(mode(&path) & 0o777, 0o666);
+        assert_eq!(mode(path.parent().unwrap()) & 0o777, 0o755);
+        device.shutdown().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_control_requests() {
+        let network = LoopbackNetwork::new();
+        let (a, _) = device("wgloop26", &network, "192.0.2.1:51820".parse().unwrap()).await;
+        let socket = "/var/run/wireguard/wgloop26.sock";
+        // Never finishes its request
+        let mut stalled = tokio::net::UnixStream::connect(socket).await.unwrap();
+        stalled.write_all(b"set=1\nlisten_port=").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let client = Client::new("wgloop26");
+        let key_b = "09".repeat(32);
+        let lines = [
+            format!("public_key={key_b}"),
+            "allowed_ip=10.0.0.2/32".to_owned(),
+        ];
+        let set = client.set(&lines);
+        tokio::time::timeout(Duration::from_secs(2), set)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Several requests on one connection, each answered in turn
+        let mut conn = tokio::net::UnixStream::connect(socket).await.unwrap();
+        let request = format!(
+            "set=1\npublic_key={key_b}\nallowed_ip=10.0.0.3/32\n\nget=1\n\nset=1\nbogus=1\n\n"
+        );
+        conn.write_all(request.as_bytes()).await.unwrap();
+        conn.shutdown().await.unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("errno=0\n\n"));
+        assert!(response.contains("allowed_ip=10.0.0.2/32\nallowed_ip=10.0.0.3/32\nerrno=0\n\n"));
+        assert!(response.ends_with(&format!("errno={}\n\n", libc::EINVAL)));
+
+        // The stalled connection does not hold up the shutdown
+        a.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        stalled.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn relay_acl_lines() {
+        let device = Device::new("wgloop34".to_owned()).await.unwrap();
+        device
+            .set_key(x25519::StaticSecret::random_from_rng(rand_core::OsRng))
+            .await;
+        let (a, b, c) = ("01".repeat(32), "02".repeat(32), "03".repeat(32));
+        let key = |hex: &str| x25519::PublicKey::from(hex.parse::<KeyBytes>().unwrap().0);
+        let set = |request: String| {
+            let device = Arc::clone(&device);
+            async move {
+                let mut lines = request.lines().map(str::to_owned);
+                device.api_set(&mut lines).await
+            }
+        };
+        let allows = |from: &str, to: &str| {
+            let (from, to) = (key(from), key(to));
+            let device = Arc::clone(&device);
+            async move { device.relay.read().await.allows(&from, &to) }
+        };
+
+        // The peer is rejected, so are its pairs
+        let rejected = set(format!(
+            "relay=acl\npublic_key={a}\nrelay_allow={b}\nallowed_ip=10.0.0.0/33"
+        ));
+        assert_eq!(rejected.await, libc::EINVAL);
+        assert!(!allows(&a, &b).await);
+
+        let request = format!("public_key={a}\nrelay_allow={b}\nrelay_allow={c}");
+        assert_eq!(set(request).await, 0);
+        assert!(allows(&a, &b).await && allows(&a, &c).await);
+        assert_eq!(set(format!("public_key={a}\nrelay_deny={c}")).await, 0);
+        assert!(allows(&a, &b).await && !allows(&a, &c).await);
+        let request = format!("public_key={a}\nreplace_relay=true\nrelay_allow={c}");
+        assert_eq!(set(request).await, 0);
+        assert!(!allows(&a, &b).await && allows(&a, &c).await);
+        assert_eq!(set(format!("public_key={a}\nremove=true")).await, 0);
+        assert!(!allows(&a, &c).await);
+
+        device.close();
+    }
+
+    #[tokio::test]
+    async fn subscribe_request() {
+        let network = LoopbackNetwork::new();
+        let (a, _) = device("wgloop36", &network, "192.0.2.1:51820".parse().unwrap()).await;
+        let socket = "/var/run/wireguard/wgloop36.sock";
+        let mut conn = tokio::net::UnixStream::connect(socket).await.unwrap();
+        // The blank line ends the request, it does not stop the stream
+        conn.write_all(b"subscribe=1\n\n").await.unwrap();
+        while a.events.receiver_count() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let key_b = x25519::PublicKey::from([9; 32]);
+        a.update_peer(peer(
+            key_b,
+            "192.0.2.2:51820".parse().unwrap(),
+            "10.0.0.2/32",
+        ))
+        .await
+        .unwrap();
+        let event = format!("event=peer_added\npublic_key={}\n\n", "09".repeat(32));
+        let mut received = vec![0u8; event.len()];
+        let read = tokio::time::timeout(Duration::from_secs(5), conn.read_exact(&mut received));
+        read.await.unwrap().unwrap();
+        assert_eq!(String::from_utf8(received).unwrap(), event);
+
+        conn.write_all(b"\n").await.unwrap();
+        let mut rest = String::new();
+        conn.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "errno=0\n\n");
+
+        let mut conn = tokio::net::UnixStream::connect(socket).await.unwrap();
+        conn.write_all(b"subscribe=1\nbogus=1\n\n").await.unwrap();
+        conn.shutdown().await.unwrap();
+        let mut rest = String::new();
+        conn.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, format!("errno={}\n\n", libc::EINVAL));
+
+        a.close();
     }
 }
@@ -0,0 +1,616 @@
+//! Packet capture in the pcapng format, see [`Device::start_capture`].
+//!
+//! Interface 0 carries the plaintext IP packets exchanged with the interface, interface 1 the
+//! WireGuard messages exchanged with the peers. Whatever their transport, messages are wrapped
+//! in a synthesized IP and UDP header so that Wireshark dissects them. With `key_log` the
+//! capture embeds decryption secrets blocks in the WireGuard key log format, which Wireshark
+//! uses to decrypt the messages.
+
+use std::{
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Weak},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::Engine;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, watch},
+};
+use tokio_util::task::TaskTracker;
+
+use crate::x25519;
+
+use super::{endpoint::Endpoint, filter::Direction, metrics::encode_key, peer::Peer, Device};
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BLOCK_DECRYPTION_SECRETS: u32 = 0x0000_000A;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const LINKTYPE_RAW: u16 = 101;
+const SECRETS_WIREGUARD_KEY_LOG: u32 = 0x5747_4B4C;
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+
+const INTERFACE_PLAINTEXT: u32 = 0;
+const INTERFACE_CIPHERTEXT: u32 = 1;
+
+/// Blocks queued for the writer, beyond that packets are left out of the capture
+const CAPTURE_QUEUE_DEPTH: usize = 1024;
+
+#[derive(Clone, Debug)]
+pub struct CaptureConfig {
+    /// Capture the IP packets exchanged with the interface
+    pub plaintext: bool,
+    /// Capture the WireGuard messages exchanged with the peers
+    pub ciphertext: bool,
+    /// Only capture the traffic of this peer
+    pub peer: Option<x25519::PublicKey>,
+    /// Embed the keys needed to decrypt the ciphertext. Anyone with the capture can then read
+    /// the traffic and impersonate the device.
+    pub key_log: bool,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        CaptureConfig {
+            plaintext: true,
+            ciphertext: true,
+            peer: None,
+            key_log: false,
+        }
+    }
+}
+
+pub struct Capture {
+    pub config: CaptureConfig,
+    sender: mpsc::Sender<Vec<u8>>,
+    /// Changes never, the writer task drops the sender when it stops
+    stopped: watch::Receiver<()>,
+}
+
+impl Capture {
+    /// Start writing a pcapng section with the interfaces of device `name` to `writer`, from a
+    /// task spawned on `tasks`
+    pub fn new<W>(config: CaptureConfig, name: &str, writer: W, tasks: &TaskTracker) -> Self
+    where
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(CAPTURE_QUEUE_DEPTH);
+        let header = [
+            section_header(),
+            interface_description(name),
+            interface_description(&format!("{name} (wireguard)")),
+        ]
+        .concat();
+        let (stopping, stopped) = watch::channel(());
+        tasks.spawn(async move {
+            let _stopping = stopping;
+            let mut writer = writer;
+            let mut result = writer.write_all(&header).await;
+            while result.is_ok() {
+                let block = match receiver.recv().await {
+                    Some(block) => block,
+                    None => break,
+                };
+                result = writer.write_all(&block).await;
+                if result.is_ok() && receiver.is_empty() {
+                    result = writer.flush().await;
+                }
+            }
+            match result {
+                Ok(()) => {
+                    let _ = writer.shutdown().await;
+                }
+                Err(e) => tracing::debug!(message = "Capture stopped", error = ?e),
+            }
+        });
+        Capture {
+            config,
+            sender,
+            stopped,
+        }
+    }
+
+    /// Whether the writer stopped, because it failed or the reader went away
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// Resolves once the writer stopped. Unlike the capture, the future does not keep it going.
+    pub fn stopped(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut stopped = self.stopped.clone();
+        async move { while stopped.changed().await.is_ok() {} }
+    }
+
+    fn wants(&self, peer: Option<&x25519::PublicKey>) -> bool {
+        self.config.peer.is_none_or(|p| Some(&p) == peer)
+    }
+
+    fn write(&self, block: Vec<u8>) {
+        if self.sender.try_send(block).is_err() {
+            tracing::trace!("Capture queue full");
+        }
+    }
+
+    /// An IP packet exchanged with the interface, decrypted from or to be encrypted to `peer`
+    pub fn plaintext(&self, peer: &x25519::PublicKey, direction: Direction, packet: &[u8]) {
+        if self.config.plaintext && self.wants(Some(peer)) {
+            let comment = encode_key(peer);
+            self.write(enhanced_packet(
+                INTERFACE_PLAINTEXT,
+                direction,
+                Some(&comment),
+                packet,
+            ));
+        }
+    }
+
+    /// A WireGuard message exchanged with `endpoint`, before obfuscation
+    pub fn ciphertext(
+        &self,
+        peer: Option<&x25519::PublicKey>,
+        direction: Direction,
+        local_port: u16,
+        endpoint: &Endpoint,
+        message: &[u8],
+    ) {
+        if self.config.ciphertext && self.wants(peer) {
+            let comment = peer.map(encode_key);
+            let datagram = udp_datagram(direction, local_port, endpoint.addr(), message);
+            self.write(enhanced_packet(
+                INTERFACE_CIPHERTEXT,
+                direction,
+                comment.as_deref(),
+                &datagram,
+            ));
+        }
+    }
+
+    /// Lines of the WireGuard key log concerning `peer`
+    pub fn key_log(&self, peer: &x25519::PublicKey, lines: &str) {
+        if self.config.key_log && self.wants(Some(peer)) {
+            self.write(decryption_secrets(lines));
+        }
+    }
+}
+
+/// A block of `kind` around `body`, whose length is a multiple of 4
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let len = (12 + body.len()) as u32;
+    let mut block = Vec::with_capacity(len as usize);
+    block.extend_from_slice(&kind.to_le_bytes());
+    block.extend_from_slice(&len.to_le_bytes());
+    block.extend_from_slice(body);
+    block.extend_from_slice(&len.to_le_bytes());
+    block
+}
+
+fn pad(body: &mut Vec<u8>) {
+    body.resize(body.len().next_multiple_of(4), 0);
+}
+
+fn option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+fn section_header() -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // Section length unknown
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    block(BLOCK_SECTION_HEADER, &body)
+}
+
+fn interface_description(name: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // No snapshot length limit
+    body.extend_from_slice(&0u32.to_le_bytes());
+    option(&mut body, OPT_IF_NAME, name.as_bytes());
+    option(&mut body, OPT_END, &[]);
+    block(BLOCK_INTERFACE_DESCRIPTION, &body)
+}
+
+fn enhanced_packet(
+    interface: u32,
+    direction: Direction,
+    comment: Option<&str>,
+    data: &[u8],
+) -> Vec<u8> {
+    // Microseconds, the default timestamp resolution
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let mut body = Vec::with_capacity(data.len() + 64);
+    body.extend_from_slice(&interface.to_le_bytes());
+    body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    body.extend_from_slice(data);
+    pad(&mut body);
+    let flags: u32 = match direction {
+        Direction::In => 0b01,
+        Direction::Out => 0b10,
+    };
+    option(&mut body, OPT_EPB_FLAGS, &flags.to_le_bytes());
+    if let Some(comment) = comment {
+        option(&mut body, OPT_COMMENT, comment.as_bytes());
+    }
+    option(&mut body, OPT_END, &[]);
+    block(BLOCK_ENHANCED_PACKET, &body)
+}
+
+fn decryption_secrets(key_log: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&SECRETS_WIREGUARD_KEY_LOG.to_le_bytes());
+    body.extend_from_slice(&(key_log.len() as u32).to_le_bytes());
+    body.extend_from_slice(key_log.as_bytes());
+    pad(&mut body);
+    block(BLOCK_DECRYPTION_SECRETS, &body)
+}
+
+/// Wrap a message in IP and UDP headers between the unspecified local address and `endpoint`
+fn udp_datagram(
+    direction: Direction,
+    local_port: u16,
+    endpoint: SocketAddr,
+    message: &[u8],
+) -> Vec<u8> {
+    let local = match endpoint.ip() {
+        IpAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), local_port),
+        IpAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), local_port),
+    };
+    let (src, dst) = match direction {
+        Direction::In => (endpoint, local),
+        Direction::Out => (local, endpoint),
+    };
+    let udp_len = (8 + message.len()) as u16;
+    let mut datagram = Vec::with_capacity(48 + message.len());
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            datagram.extend_from_slice(&[0x45, 0]);
+            datagram.extend_from_slice(&(20 + udp_len).to_be_bytes());
+            // Identification, don't fragment, TTL, UDP, checksum
+            datagram.extend_from_slice(&[0, 0, 0x40, 0, 64, 17, 0, 0]);
+            datagram.extend_from_slice(&src.octets());
+            datagram.extend_from_slice(&dst.octets());
+            let checksum = ipv4_checksum(&datagram);
+            datagram[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        (src, dst) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            datagram.extend_from_slice(&[0x60, 0, 0, 0]);
+            datagram.extend_from_slice(&udp_len.to_be_bytes());
+            // UDP, hop limit
+            datagram.extend_from_slice(&[17, 64]);
+            datagram.extend_from_slice(&to_v6(src).octets());
+            datagram.extend_from_slice(&to_v6(dst).octets());
+        }
+    }
+    datagram.extend_from_slice(&src.port().to_be_bytes());
+    datagram.extend_from_slice(&dst.port().to_be_bytes());
+    datagram.extend_from_slice(&udp_len.to_be_bytes());
+    // No UDP checksum
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(message);
+    datagram
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+impl Device {
+    /// Capture the device traffic to `writer` in the pcapng format, replacing the current
+    /// capture if any
+    pub async fn start_capture<W>(&self, config: CaptureConfig, writer: W) -> Weak<Capture>
+    where
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        self.stop_capture().await;
+        let key_log = config.key_log;
+        let capture = Arc::new(Capture::new(config, &self.name, writer, &self.tasks));
+        if key_log {
+            let peers: Vec<_> = self.peers.iter().map(|e| e.value().clone()).collect();
+            for peer in peers {
+                let mut p = peer.lock().await;
+                p.tunnel.set_key_log(true);
+                capture.key_log(&p.pub_key, &peer_key_log(self, &p).await);
+            }
+        }
+        let started = Arc::downgrade(&capture);
+        *self.capture.write().await = Some(capture);
+        started
+    }
+
+    /// Stop capturing, the capture is flushed and its writer shut down
+    pub async fn stop_capture(&self) {
+        let capture = self.capture.write().await.take();
+        self.capture_stopped(capture).await;
+    }
+
+    /// Stop `capture` unless another one replaced it in the meantime
+    pub async fn end_capture(&self, capture: &Weak<Capture>) {
+        let mut current = self.capture.write().await;
+        let ended = match current.as_ref() {
+            Some(c) if std::ptr::eq(Arc::as_ptr(c), capture.as_ptr()) => current.take(),
+            _ => None,
+        };
+        drop(current);
+        self.capture_stopped(ended).await;
+    }
+
+    async fn capture_stopped(&self, capture: Option<Arc<Capture>>) {
+        let Some(capture) = capture else {
+            return;
+        };
+        if capture.config.key_log {
+            let peers: Vec<_> = self.peers.iter().map(|e| e.value().clone()).collect();
+            for peer in peers {
+                peer.lock().await.tunnel.set_key_log(false);
+            }
+        }
+    }
+
+    pub(crate) async fn capture(&self) -> Option<Arc<Capture>> {
+        self.capture
+            .read()
+            .await
+            .as_ref()
+            .filter(|capture| !capture.is_closed())
+            .cloned()
+    }
+
+    pub(crate) async fn capture_plaintext(
+        &self,
+        peer: &x25519::PublicKey,
+        direction: Direction,
+        packet: &[u8],
+    ) {
+        if let Some(capture) = self.capture().await {
+            capture.plaintext(peer, direction, packet);
+        }
+    }
+
+    pub(crate) async fn capture_ciphertext(
+        &self,
+        peer: Option<&x25519::PublicKey>,
+        direction: Direction,
+        endpoint: &Endpoint,
+        message: &[u8],
+    ) {
+        if let Some(capture) = self.capture().await {
+            let local_port = self.listen_port.load(std::sync::atomic::Ordering::Relaxed);
+            capture.ciphertext(peer, direction, local_port, endpoint, message);
+        }
+    }
+
+    /// Log the ephemeral key of the handshake message `peer` just formatted, if any
+    pub(crate) async fn log_ephemeral_key(&self, peer: &mut Peer) {
+        let ephemeral = match peer.tunnel.take_logged_ephemeral() {
+            Some(ephemeral) => ephemeral,
+            None => return,
+        };
+        if let Some(capture) = self.capture().await {
+            let line = key_log_line("LOCAL_EPHEMERAL_PRIVATE_KEY", ephemeral.expose());
+            capture.key_log(&peer.pub_key, &line);
+        }
+    }
+}
+
+/// The static keys Wireshark needs to decrypt the handshakes with `peer`
+pub(crate) async fn peer_key_log(device: &Device, peer: &Peer) -> String {
+    let mut lines = String::new();
+    if let Some((private_key, _)) = device.key_pair.read().await.as_ref() {
+        lines.push_str(&key_log_line(
+            "LOCAL_STATIC_PRIVATE_KEY",
+            private_key.as_bytes(),
+        ));
+    }
+    lines.push_str(&key_log_line(
+        "REMOTE_STATIC_PUBLIC_KEY",
+        peer.pub_key.as_bytes(),
+    ));
+    if let Some(preshared_key) = &peer.preshared_key {
+        lines.push_str(&key_log_line("PRESHARED_KEY", preshared_key.expose()));
+    }
+    lines
+}
+
+fn key_log_line(name: &str, key: &[u8; 32]) -> String {
+    format!(
+        "{name} = {}\n",
+        base64::engine::general_purpose::STANDARD.encode(key)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::device::{
+        testing::{device, peer, wait_for_stats},
+        transport::loopback::LoopbackNetwork,
+    };
+
+    use super::*;
+
+    /// Blocks of a pcapng stream as (type, body)
+    fn blocks(mut data: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut blocks = Vec::new();
+        while data.len() >= 12 {
+            let kind = u32::from_le_bytes(data[0..4].try_into().unwrap());
+            let len = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(data[len - 4..len], data[4..8]);
+            blocks.push((kind, data[8..len - 4].to_vec()));
+            data = &data[len..];
+        }
+        assert!(data.is_empty());
+        blocks
+    }
+
+    #[tokio::test]
+    async fn pcapng_layout() {
+        let (writer, mut reader) = tokio::io::duplex(4096);
+        let peer = x25519::PublicKey::from([1; 32]);
+        let capture = Capture::new(
+            CaptureConfig {
+                key_log: true,
+                ..Default::default()
+            },
+            "wg0",
+            writer,
+            &TaskTracker::new(),
+        );
+        let endpoint: Endpoint = "192.0.2.1:51820".parse().unwrap();
+        capture.ciphertext(Some(&peer), Direction::In, 51821, &endpoint, &[4, 0, 0, 0]);
+        capture.key_log(&peer, "LOCAL_EPHEMERAL_PRIVATE_KEY = x\n");
+        drop(capture);
+        let mut data = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut data)
+            .await
+            .unwrap();
+
+        let blocks = blocks(&data);
+        let kinds: Vec<_> = blocks.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                BLOCK_SECTION_HEADER,
+                BLOCK_INTERFACE_DESCRIPTION,
+                BLOCK_INTERFACE_DESCRIPTION,
+                BLOCK_ENHANCED_PACKET,
+                BLOCK_DECRYPTION_SECRETS
+            ]
+        );
+        let packet = &blocks[3].1;
+        assert_eq!(packet[0..4], INTERFACE_CIPHERTEXT.to_le_bytes());
+        assert_eq!(packet[12..16], 32u32.to_le_bytes());
+        let datagram = &packet[20..52];
+        assert_eq!(ipv4_checksum(&datagram[..20]), 0);
+        assert_eq!(datagram[12..16], [192, 0, 2, 1]);
+        assert_eq!(datagram[20..24], [0xca, 0x6c, 0xca, 0x6d]); // 51820 -> 51821
+        assert_eq!(datagram[28..], [4, 0, 0, 0]);
+        assert_eq!(blocks[4].1[0..4], SECRETS_WIREGUARD_KEY_LOG.to_le_bytes());
+    }
+
+    #[tokio::test]
+    async fn peer_filter() {
+        let (writer, _reader) = tokio::io::duplex(64);
+        let alice = x25519::PublicKey::from([1; 32]);
+        let capture = Capture::new(
+            CaptureConfig {
+                peer: Some(alice),
+                ..Default::default()
+            },
+            "wg0",
+            writer,
+            &TaskTracker::new(),
+        );
+        assert!(capture.wants(Some(&alice)));
+        assert!(!capture.wants(Some(&x25519::PublicKey::from([2; 32]))));
+        assert!(!capture.wants(None));
+    }
+
+    #[tokio::test]
+    async fn capture_with_key_log() {
+        let network = LoopbackNetwork::new();
+        let addr_a: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let addr_b: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let (a, key_a) = device("wgloop15", &network, addr_a).await;
+        let (b, key_b) = device("wgloop16", &network, addr_b).await;
+        let (writer, mut reader) = tokio::io::duplex(1 << 16);
+        let config = CaptureConfig {
+            key_log: true,
+            ..Default::default()
+        };
+        a.start_capture(config, writer).await;
+
+        let mut peer_b = peer(key_b, addr_b, "10.0.0.2/32");
+        peer_b.keepalive(1);
+        a.update_peer(peer_b).await.unwrap();
+        b.update_peer(peer(key_a, addr_a, "10.0.0.1/32"))
+            .await
+            .unwrap();
+        let handshake = wait_for_stats(&a, &key_b, |stats| stats.last_handshake.is_some()).await;
+        assert!(handshake, "handshake did not complete");
+        a.stop_capture().await;
+
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data[..4], [0x0a, 0x0d, 0x0d, 0x0a]);
+        let contains = |needle: &[u8]| data.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(b"LOCAL_STATIC_PRIVATE_KEY = "));
+        assert!(contains(b"LOCAL_EPHEMERAL_PRIVATE_KEY = "));
+
+        a.close();
+        b.close();
+    }
+
+    #[tokio::test]
+    async fn capture_ends_with_connection() {
+        let network = LoopbackNetwork::new();
+        let (a, _) = device("wgloop35", &network, "192.0.2.1:51820".parse().unwrap()).await;
+        let key_b = x25519::PublicKey::from([9; 32]);
+        a.update_peer(peer(
+            key_b,
+            "192.0.2.2:51820".parse().unwrap(),
+            "10.0.0.2/32",
+        ))
+        .await
+        .unwrap();
+
+        let socket = "/var/run/wireguard/wgloop35.sock";
+        let mut conn = tokio::net::UnixStream::connect(socket).await.unwrap();
+        conn.write_all(b"capture=1\nkey_log=true\n\n")
+            .await
+            .unwrap();
+        let mut magic = [0u8; 4];
+        conn.read_exact(&mut magic).await.unwrap();
+        assert_eq!(magic, [0x0a, 0x0d, 0x0d, 0x0a]);
+        assert!(a.capture.read().await.is_some());
+
+        // No traffic to fail a write on, the hang-up alone stops the capture
+        drop(conn);
+        let stopped = tokio::time::timeout(Duration::from_secs(5), async {
+            while a.capture.read().await.is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        stopped.await.unwrap();
+        let peer = a.peers.get(&key_b).unwrap().value().clone();
+        let mut p = peer.lock().await;
+        let mut buf = vec![0u8; 256];
+        let _ = p.tunnel.format_handshake_initiation(&mut buf, true);
+        assert!(p.tunnel.take_logged_ephemeral().is_none());
+        drop(p);
+
+        a.close();
+    }
+}
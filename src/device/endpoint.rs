@@ -0,0 +1,81 @@
+use std::{fmt, net::SocketAddr, str::FromStr};
+
+/// Where the encrypted side of a peer lives.
+///
+/// Plain `ip:port` (or `udp://ip:port`) is the regular WireGuard UDP endpoint,
+/// `tcp://ip:port` carries the same message stream over a framed TCP connection.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Endpoint {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+}
+
+impl Endpoint {
+    pub fn addr(&self) -> SocketAddr {
+        match self {
+            Endpoint::Udp(addr) | Endpoint::Tcp(addr) => *addr,
+        }
+    }
+
+    pub fn is_tcp(&self) -> bool {
+        matches!(self, Endpoint::Tcp(_))
+    }
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(addr: SocketAddr) -> Self {
+        Endpoint::Udp(addr)
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, addr) = match s.split_once("://") {
+            Some((scheme, addr)) => (scheme, addr),
+            None => ("udp", s),
+        };
+        let addr = addr
+            .parse::<SocketAddr>()
+            .map_err(|_| "Invalid endpoint address".to_owned())?;
+        match scheme {
+            "udp" => Ok(Endpoint::Udp(addr)),
+            "tcp" => Ok(Endpoint::Tcp(addr)),
+            _ => Err(format!("Unsupported endpoint scheme {scheme}")),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Udp(addr) => write!(f, "{addr}"),
+            Endpoint::Tcp(addr) => write!(f, "tcp://{addr}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_endpoint() {
+        let udp: Endpoint = "10.0.0.1:51820".parse().unwrap();
+        assert_eq!(udp, Endpoint::Udp("10.0.0.1:51820".parse().unwrap()));
+        let udp: Endpoint = "udp://[::1]:51820".parse().unwrap();
+        assert_eq!(udp, Endpoint::Udp("[::1]:51820".parse().unwrap()));
+        let tcp: Endpoint = "tcp://10.0.0.1:443".parse().unwrap();
+        assert_eq!(tcp, Endpoint::Tcp("10.0.0.1:443".parse().unwrap()));
+        assert!("quic://10.0.0.1:443".parse::<Endpoint>().is_err());
+        assert!("tcp://example".parse::<Endpoint>().is_err());
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for s in ["10.0.0.1:51820", "tcp://[fe80::1]:443"] {
+            assert_eq!(s.parse::<Endpoint>().unwrap().to_string(), s);
+        }
+    }
+}
@@ -0,0 +1,185 @@
+//! Peer lifecycle notifications, see [`Device::subscribe`].
+
+use futures_util::Stream;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{key_bytes::KeyBytes, x25519};
+
+use super::{endpoint::Endpoint, Device};
+
+/// Events buffered for each subscriber before it starts lagging
+pub(crate) const EVENT_CAPACITY: usize = 256;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceEvent {
+    PeerAdded {
+        public_key: x25519::PublicKey,
+    },
+    PeerRemoved {
+        public_key: x25519::PublicKey,
+    },
+    /// A handshake with the peer completed and a new session is in use
+    HandshakeCompleted {
+        public_key: x25519::PublicKey,
+        endpoint: Endpoint,
+    },
+    /// The peer's sessions expired or the handshake was given up on
+    SessionExpired {
+        public_key: x25519::PublicKey,
+    },
+    /// An authenticated message arrived from an address other than the peer's endpoint
+    EndpointChanged {
+        public_key: x25519::PublicKey,
+        old: Option<Endpoint>,
+        new: Endpoint,
+    },
+    /// The subscriber fell behind and this many events were lost
+    Lagged(u64),
+}
+
+impl DeviceEvent {
+    /// The event as `key=value` lines of the control socket protocol
+    pub fn to_uapi(&self) -> Vec<String> {
+        let key = |public_key: &x25519::PublicKey| {
+            format!("public_key={}", KeyBytes(public_key.to_bytes()).to_hex())
+        };
+        match self {
+            DeviceEvent::PeerAdded { public_key } => {
+                vec!["event=peer_added".to_owned(), key(public_key)]
+            }
+            DeviceEvent::PeerRemoved { public_key } => {
+                vec!["event=peer_removed".to_owned(), key(public_key)]
+            }
+            DeviceEvent::HandshakeCompleted {
+                public_key,
+                endpoint,
+            } => vec![
+                "event=handshake_completed".to_owned(),
+                key(public_key),
+                format!("endpoint={endpoint}"),
+            ],
+            DeviceEvent::SessionExpired { public_key } => {
+                vec!["event=session_expired".to_owned(), key(public_key)]
+            }
+            DeviceEvent::EndpointChanged {
+                public_key,
+                old,
+                new,
+            } => {
+                let mut lines = vec!["event=endpoint_changed".to_owned(), key(public_key)];
+                if let Some(old) = old {
+                    lines.push(format!("old_endpoint={old}"));
+                }
+                lines.push(format!("endpoint={new}"));
+                lines
+            }
+            DeviceEvent::Lagged(missed) => {
+                vec!["event=lagged".to_owned(), format!("missed={missed}")]
+            }
+        }
+    }
+}
+
+impl Device {
+    /// Stream of the events from now on, it ends when the device is dropped
+    pub fn subscribe(&self) -> impl Stream<Item = DeviceEvent> + Send + 'static {
+        futures_util::stream::unfold(self.events.subscribe(), |mut receiver| async move {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => DeviceEvent::Lagged(missed),
+                Err(RecvError::Closed) => return None,
+            };
+            Some((event, receiver))
+        })
+    }
+
+    pub(crate) fn emit(&self, event: DeviceEvent) {
+        tracing::debug!(message = "Device event", ?event);
+        // Nobody listening is fine
+        let _ = self.events.send(event);
+    }
+}
+
+pub(crate) fn channel() -> broadcast::Sender<DeviceEvent> {
+    broadcast::channel(EVENT_CAPACITY).0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use futures_util::StreamExt;
+
+    use crate::device::{
+        testing::{device, peer},
+        transport::loopback::LoopbackNetwork,
+    };
+
+    use super::*;
+
+    #[test]
+    fn uapi_lines() {
+        let public_key = x25519::PublicKey::from([1; 32]);
+        let event = DeviceEvent::EndpointChanged {
+            public_key,
+            old: None,
+            new: "tcp://192.0.2.1:443".parse().unwrap(),
+        };
+        assert_eq!(
+            event.to_uapi(),
+            vec![
+                "event=endpoint_changed".to_owned(),
+                format!("public_key={}", "01".repeat(32)),
+                "endpoint=tcp://192.0.2.1:443".to_owned(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn device_events() {
+        let network = LoopbackNetwork::new();
+        let addr_a: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let addr_b: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let (a, key_a) = device("wgloop13", &network, addr_a).await;
+        let (b, key_b) = device("wgloop14", &network, addr_b).await;
+        let events = b.subscribe();
+        futures_util::pin_mut!(events);
+
+        // A keepalive starts the handshake, a data packet would block on B's TUN, which is down
+        let mut peer_b = peer(key_b, addr_b, "10.0.0.2/32");
+        peer_b.keepalive(1);
+        a.update_peer(peer_b).await.unwrap();
+        // B learns where A is from its handshake
+        let mut peer_a = peer(key_a, addr_a, "10.0.0.1/32");
+        peer_a.endpoint = None;
+        b.update_peer(peer_a).await.unwrap();
+        let endpoint = Endpoint::Udp(addr_a);
+        let mut expected = vec![
+            DeviceEvent::PeerAdded { public_key: key_a },
+            DeviceEvent::EndpointChanged {
+                public_key: key_a,
+                old: None,
+                new: endpoint,
+            },
+            DeviceEvent::HandshakeCompleted {
+                public_key: key_a,
+                endpoint,
+            },
+        ];
+        for event in expected.drain(..) {
+            let received = tokio::time::timeout(Duration::from_secs(5), events.next()).await;
+            assert_eq!(received.unwrap(), Some(event));
+        }
+        // B is the responder, the session is confirmed by the data packet that follows
+        assert!(b.peer_stats(&key_a).await.unwrap().rx_packets > 0);
+        b.remove_peer(&key_a).await;
+        let received = tokio::time::timeout(Duration::from_secs(5), events.next()).await;
+        assert_eq!(
+            received.unwrap(),
+            Some(DeviceEvent::PeerRemoved { public_key: key_a })
+        );
+
+        a.close();
+        b.close();
+    }
+}
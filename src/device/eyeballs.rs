@@ -0,0 +1,229 @@
+//! Peers with several candidate endpoints, typically an IPv4 and an IPv6 address of the same
+//! host. Like happy eyeballs (RFC 8305), every handshake initiation goes to the candidates one
+//! after the other until one answers, and the peer settles on whichever completes first.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::noise::{Packet, Tunn};
+
+use super::{endpoint::Endpoint, metrics::DropReason, peer::Peer, Device};
+
+/// How long a candidate has to answer before the initiation also goes to the next one
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// The active path keeps its head start while authenticated packets came over it this recently.
+/// It is KEEPALIVE_TIMEOUT + REKEY_TIMEOUT, after which the tunnel initiates a new handshake.
+const PATH_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A handshake initiation on its way to the candidates of a peer
+#[derive(Debug)]
+pub(crate) struct Race {
+    packet: Vec<u8>,
+    pending: VecDeque<Endpoint>,
+    next: Instant,
+}
+
+/// The order the candidates are tried in: address families alternate, starting with the one
+/// of the first candidate, and otherwise the configured order is kept. The active endpoint goes
+/// first while its path is alive, so a working path is only left when it stops answering.
+fn order(candidates: &[Endpoint], active: Option<Endpoint>, alive: bool) -> Vec<Endpoint> {
+    let Some(first) = candidates.first() else {
+        return Vec::new();
+    };
+    let preferred = first.addr().is_ipv4();
+    let (mut same, mut other): (VecDeque<Endpoint>, VecDeque<Endpoint>) = candidates
+        .iter()
+        .partition(|endpoint| endpoint.addr().is_ipv4() == preferred);
+    let mut ordered = Vec::with_capacity(candidates.len());
+    while !same.is_empty() || !other.is_empty() {
+        ordered.extend(same.pop_front());
+        ordered.extend(other.pop_front());
+    }
+    if let (Some(active), true) = (active, alive) {
+        if let Some(pos) = ordered.iter().position(|endpoint| *endpoint == active) {
+            ordered[..=pos].rotate_right(1);
+        }
+    }
+    ordered
+}
+
+fn is_initiation(packet: &[u8]) -> bool {
+    matches!(
+        Tunn::parse_incoming_packet(packet),
+        Ok(Packet::HandshakeInit(_))
+    )
+}
+
+impl Device {
+    /// Send a message from the tunnel of `peer` to where the peer is. A handshake initiation to a
+    /// peer with candidate endpoints goes to the first of them and starts a race over the others.
+    pub(super) async fn send_to_peer(&self, peer: &mut Peer, packet: &[u8]) {
+        let endpoint = match is_initiation(packet) && peer.candidates.len() > 1 {
+            true => {
+                let alive = peer.tunnel.time_since_last_received() < PATH_TIMEOUT;
+                let mut pending: VecDeque<_> = order(&peer.candidates, peer.addr, alive).into();
+                let first = pending.pop_front();
+                peer.race = Some(Race {
+                    packet: packet.to_vec(),
+                    pending,
+                    next: Instant::now() + ATTEMPT_DELAY,
+                });
+                first
+            }
+            false => peer.addr,
+        };
+        let Some(endpoint) = endpoint else {
+            self.drop_packet(DropReason::NoEndpoint, Some(&peer.pub_key), None);
+            return;
+        };
+        let _: Result<_, _> = self
+            .send_obfuscated(
+                packet,
+                &endpoint,
+                peer.obfuscator.as_deref(),
+                Some(&peer.pub_key),
+            )
+            .await;
+    }
+
+    /// Send the initiation of a race on to the next candidate once the previous one had its
+    /// time. The race ends with the handshake, see `handle_incoming_packet`.
+    pub(super) async fn advance_race(&self, peer: &mut Peer) {
+        let Some(mut race) = peer.race.take() else {
+            return;
+        };
+        let now = Instant::now();
+        if now < race.next {
+            peer.race = Some(race);
+            return;
+        }
+        let Some(endpoint) = race.pending.pop_front() else {
+            return;
+        };
+        tracing::debug!(message = "Trying the next candidate endpoint", device = %self.name, %endpoint);
+        let _: Result<_, _> = self
+            .send_obfuscated(
+                &race.packet,
+                &endpoint,
+                peer.obfuscator.as_deref(),
+                Some(&peer.pub_key),
+            )
+            .await;
+        if !race.pending.is_empty() {
+            race.next = now + ATTEMPT_DELAY;
+            peer.race = Some(race);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::device::{
+        peer::PeerConfig,
+        testing::{device, peer, wait_for_addr, wait_for_stats},
+        transport::{loopback::LoopbackNetwork, Transport},
+        DeviceConfig,
+    };
+
+    use super::*;
+
+    fn endpoints(list: &[&str]) -> Vec<Endpoint> {
+        list.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn candidate_order() {
+        let candidates = endpoints(&[
+            "[2001:db8::1]:51820",
+            "[2001:db8::2]:51820",
+            "192.0.2.1:51820",
+            "192.0.2.2:51820",
+        ]);
+        let interleaved = endpoints(&[
+            "[2001:db8::1]:51820",
+            "192.0.2.1:51820",
+            "[2001:db8::2]:51820",
+            "192.0.2.2:51820",
+        ]);
+        assert_eq!(order(&candidates, None, true), interleaved);
+
+        // A live path is tried first, the rest keep their order
+        let active = candidates[3];
+        let ordered = order(&candidates, Some(active), true);
+        assert_eq!(ordered[0], active);
+        assert_eq!(ordered[1..], interleaved[..3]);
+        // A silent one loses its head start
+        assert_eq!(order(&candidates, Some(active), false), interleaved);
+
+        let v4_first = endpoints(&["192.0.2.1:51820", "[2001:db8::1]:51820"]);
+        assert_eq!(order(&v4_first, None, false), v4_first);
+        assert!(order(&[], None, true).is_empty());
+    }
+
+    #[tokio::test]
+    async fn candidate_endpoints() {
+        let network = LoopbackNetwork::new();
+        let addr_a: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let addr_b: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let addr_b6: SocketAddr = "[2001:db8::2]:51820".parse().unwrap();
+        let (a, key_a) = device("wgloop30", &network, addr_a).await;
+        let (b, key_b) = device("wgloop31", &network, addr_b).await;
+
+        // Only the IPv4 address of B is reachable yet. A keepalive starts the handshake, a data
+        // packet would block on B's TUN
+        let mut peer_b = PeerConfig::new(key_b);
+        peer_b
+            .parse_endpoint("[2001:db8::2]:51820, 192.0.2.2:51820")
+            .unwrap();
+        peer_b.allowed_ips.push("10.0.0.2/32".parse().unwrap());
+        peer_b.keepalive(1);
+        assert_eq!(
+            peer_b.to_uapi()[1],
+            "endpoint=[2001:db8::2]:51820,192.0.2.2:51820"
+        );
+        a.update_peer(peer_b.clone()).await.unwrap();
+        b.update_peer(peer(key_a, addr_a, "10.0.0.1/32"))
+            .await
+            .unwrap();
+        assert!(wait_for_stats(&a, &key_b, |stats| stats.last_handshake.is_some()).await);
+        assert!(wait_for_addr(&a, &key_b, "192.0.2.2:51820").await);
+        let stats = a.peer_stats(&key_b).await.unwrap();
+        assert_eq!(stats.handshake_attempts, 1);
+
+        let config = DeviceConfig {
+            peers: vec![peer_b],
+            private_key: None,
+            public_key: None,
+            listen_port: None,
+            filter: Default::default(),
+        };
+        assert_eq!(a.sync_config(config).await.unwrap().unchanged, 1);
+
+        // The IPv6 address works now, but the working path keeps its head start
+        let probe = network.bind(addr_b6);
+        let peer = a.peers.get(&key_b).unwrap().value().clone();
+        let mut p = peer.lock().await;
+        let mut buf = vec![0u8; 256];
+        match p.tunnel.format_handshake_initiation(&mut buf, true) {
+            crate::noise::TunnResult::WriteToNetwork(packet) => {
+                a.send_to_peer(&mut p, packet).await
+            }
+            _ => panic!("no handshake initiation"),
+        }
+        drop(p);
+        assert!(wait_for_stats(&a, &key_b, |stats| stats.handshake_attempts == 2).await);
+        let probed = tokio::time::timeout(Duration::from_millis(600), probe.recv_from(&mut buf));
+        assert!(probed.await.is_err(), "the initiation was raced");
+        let p = peer.lock().await;
+        assert_eq!(p.addr, Some(Endpoint::Udp(addr_b)));
+        assert!(p.race.is_none());
+        drop(p);
+
+        a.close();
+        b.close();
+    }
+}
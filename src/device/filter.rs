@@ -0,0 +1,275 @@
+use std::{
+    ops::RangeInclusive,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use ip_network::IpNetwork;
+
+use crate::{
+    tun::header::{
+        IpHeader, PROTOCOL_ICMP, PROTOCOL_ICMPV6, PROTOCOL_SCTP, PROTOCOL_TCP, PROTOCOL_UDP,
+    },
+    x25519,
+};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Action {
+    Allow,
+    Deny,
+    /// Log the packet and keep evaluating the following rules
+    Log,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Action::Allow),
+            "deny" => Ok(Action::Deny),
+            "log" => Ok(Action::Log),
+            _ => Err(format!("Unsupported action {s}")),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// Decrypted from a peer, on its way to the interface
+    In,
+    /// Read from the interface, on its way to a peer
+    Out,
+}
+
+impl FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "in" => Ok(Direction::In),
+            "out" => Ok(Direction::Out),
+            _ => Err(format!("Unsupported direction {s}")),
+        }
+    }
+}
+
+/// Parse a protocol name or number
+pub fn parse_protocol(s: &str) -> Result<u8, String> {
+    match s {
+        "icmp" => Ok(PROTOCOL_ICMP),
+        "tcp" => Ok(PROTOCOL_TCP),
+        "udp" => Ok(PROTOCOL_UDP),
+        "icmpv6" => Ok(PROTOCOL_ICMPV6),
+        "sctp" => Ok(PROTOCOL_SCTP),
+        _ => s.parse().map_err(|_| format!("Unsupported protocol {s}")),
+    }
+}
+
+/// Parse a single port or an inclusive `first-last` range
+pub fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let invalid = || format!("Invalid port range {s}");
+    let (first, last) = s.split_once('-').unwrap_or((s, s));
+    let first = first.trim().parse::<u16>().map_err(|_| invalid())?;
+    let last = last.trim().parse::<u16>().map_err(|_| invalid())?;
+    if first > last {
+        return Err(invalid());
+    }
+    Ok(first..=last)
+}
+
+/// A rule matches when every field that is set matches, unset fields match anything
+#[derive(Debug)]
+pub struct Rule {
+    pub action: Action,
+    /// The peer the packet comes from or goes to
+    pub peer: Option<x25519::PublicKey>,
+    pub direction: Option<Direction>,
+    pub source: Option<IpNetwork>,
+    pub destination: Option<IpNetwork>,
+    pub protocol: Option<u8>,
+    pub source_port: Option<RangeInclusive<u16>>,
+    pub destination_port: Option<RangeInclusive<u16>>,
+    hits: AtomicU64,
+}
+
+impl Rule {
+    pub fn new(action: Action) -> Self {
+        Rule {
+            action,
+            peer: None,
+            direction: None,
+            source: None,
+            destination: None,
+            protocol: None,
+            source_port: None,
+            destination_port: None,
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of packets this rule has matched
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    fn matches(&self, peer: &x25519::PublicKey, direction: Direction, header: &IpHeader) -> bool {
+        let ports = header.ports();
+        // Deny rules on ports fail closed on packets whose ports cannot be read, such as
+        // fragments other than the first
+        let hidden = ports.is_none() && self.action == Action::Deny && header.ports_hidden();
+        self.peer.is_none_or(|p| p == *peer)
+            && self.direction.is_none_or(|d| d == direction)
+            && self.source.is_none_or(|n| n.contains(header.src_address()))
+            && self
+                .destination
+                .is_none_or(|n| n.contains(header.dst_address()))
+            && self.protocol.is_none_or(|p| p == header.protocol())
+            && self
+                .source_port
+                .as_ref()
+                .is_none_or(|range| hidden || ports.is_some_and(|(port, _)| range.contains(&port)))
+            && self
+                .destination_port
+                .as_ref()
+                .is_none_or(|range| hidden || ports.is_some_and(|(_, port)| range.contains(&port)))
+    }
+}
+
+/// Ordered rules evaluated between the tunnel and the interface, the first allow or deny wins
+#[derive(Debug)]
+pub struct Filter {
+    pub rules: Vec<Rule>,
+    /// What happens to packets no allow or deny rule matched
+    pub policy: Action,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter {
+            rules: Vec::new(),
+            policy: Action::Allow,
+        }
+    }
+}
+
+impl Filter {
+    /// Whether `packet`, exchanged with `peer`, may pass
+    pub fn check(&self, peer: &x25519::PublicKey, direction: Direction, packet: &[u8]) -> bool {
+        let header = match IpHeader::from_slice(packet) {
+            Some(header) => header,
+            None => return true, // keepalive
+        };
+        for (index, rule) in self.rules.iter().enumerate() {
+            if !rule.matches(peer, direction, &header) {
+                continue;
+            }
+            rule.hits.fetch_add(1, Ordering::Relaxed);
+            match rule.action {
+                Action::Allow => return true,
+                Action::Deny => return false,
+                Action::Log => tracing::info!(
+                    message = "Filter rule matched",
+                    rule = index,
+                    ?direction,
+                    src = %header.src_address(),
+                    dst = %header.dst_address(),
+                    protocol = header.protocol(),
+                    ports = ?header.ports(),
+                ),
+            }
+        }
+        self.policy != Action::Deny
+    }
+
+    /// Hit counters, in rule order
+    pub fn counters(&self) -> Vec<u64> {
+        self.rules.iter().map(Rule::hits).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp_packet(src: [u8; 4], dst: [u8; 4], dst_port: u16) -> Vec<u8> {
+        let mut packet = vec![0u8; 40];
+        packet[0] = 0x45;
+        packet[9] = PROTOCOL_TCP;
+        packet[12..16].copy_from_slice(&src);
+        packet[16..20].copy_from_slice(&dst);
+        packet[20..22].copy_from_slice(&40000u16.to_be_bytes());
+        packet[22..24].copy_from_slice(&dst_port.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let alice = x25519::PublicKey::from([1; 32]);
+        let bob = x25519::PublicKey::from([2; 32]);
+        let mut log = Rule::new(Action::Log);
+        log.protocol = Some(PROTOCOL_TCP);
+        let mut https = Rule::new(Action::Allow);
+        https.peer = Some(alice);
+        https.destination = Some(IpNetwork::new_truncate([10, 1, 0, 5], 32).unwrap());
+        https.destination_port = Some(parse_port_range("443").unwrap());
+        let filter = Filter {
+            rules: vec![log, https],
+            policy: Action::Deny,
+        };
+
+        let packet = tcp_packet([10, 0, 0, 2], [10, 1, 0, 5], 443);
+        assert!(filter.check(&alice, Direction::In, &packet));
+        assert!(!filter.check(&bob, Direction::In, &packet));
+        let packet = tcp_packet([10, 0, 0, 2], [10, 1, 0, 5], 22);
+        assert!(!filter.check(&alice, Direction::In, &packet));
+        assert!(filter.check(&alice, Direction::In, &[]));
+        assert_eq!(filter.counters(), vec![3, 1]);
+    }
+
+    #[test]
+    fn no_bypass_around_port_rules() {
+        let peer = x25519::PublicKey::from([1; 32]);
+        let mut ssh = Rule::new(Action::Deny);
+        ssh.protocol = Some(PROTOCOL_TCP);
+        ssh.destination_port = Some(parse_port_range("22").unwrap());
+        let mut allow_web = Rule::new(Action::Allow);
+        allow_web.destination_port = Some(parse_port_range("80").unwrap());
+        let filter = Filter {
+            rules: vec![allow_web, ssh],
+            policy: Action::Allow,
+        };
+        assert!(!filter.check(
+            &peer,
+            Direction::In,
+            &tcp_packet([10, 0, 0, 2], [10, 0, 0, 1], 22)
+        ));
+
+        // A later IPv4 fragment has no ports, the deny rule still applies
+        let mut fragment = tcp_packet([10, 0, 0, 2], [10, 0, 0, 1], 80);
+        fragment[6..8].copy_from_slice(&185u16.to_be_bytes());
+        assert!(!filter.check(&peer, Direction::In, &fragment));
+
+        // TCP behind an IPv6 hop-by-hop header
+        let mut packet = vec![0u8; 40 + 8 + 20];
+        packet[0] = 0x60;
+        packet[6] = 0; // hop-by-hop
+        packet[40] = PROTOCOL_TCP;
+        packet[48..52].copy_from_slice(&[0x9c, 0x40, 0x00, 0x16]);
+        assert!(!filter.check(&peer, Direction::In, &packet));
+        packet[50..52].copy_from_slice(&443u16.to_be_bytes());
+        assert!(filter.check(&peer, Direction::In, &packet));
+        assert_eq!(filter.counters(), vec![0, 3]);
+    }
+
+    #[test]
+    fn parse_fields() {
+        assert_eq!(parse_protocol("udp"), Ok(PROTOCOL_UDP));
+        assert_eq!(parse_protocol("47"), Ok(47));
+        assert!(parse_protocol("gre").is_err());
+        assert_eq!(parse_port_range("8000-8100"), Ok(8000..=8100));
+        assert!(parse_port_range("8100-8000").is_err());
+        assert_eq!("deny".parse::<Action>(), Ok(Action::Deny));
+        assert_eq!("out".parse::<Direction>(), Ok(Direction::Out));
+    }
+}
@@ -0,0 +1,738 @@
+//! JSON management API over HTTP, built with the `http-api` feature, see
+//! [`Device::serve_http_api`].
+//!
+//! It is another view of the control socket protocol rather than a second implementation:
+//! the device and its peers are read with [`Device::api_get`], peers are changed through the
+//! same settings as `set=1` and the events are those of `subscribe=1`, under the same
+//! configuration lock. The routes and bodies are described by [`OPENAPI`].
+
+use std::{net::SocketAddr, sync::Arc, time::UNIX_EPOCH};
+
+use futures_util::StreamExt;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{
+    error::{WgError, WgResult},
+    key_bytes::KeyBytes,
+    uapi::{InterfaceState, PeerState},
+    x25519,
+};
+
+use super::{
+    api::{set_peer_option, unless_closed, API_TIMEOUT},
+    event::DeviceEvent,
+    peer::PeerConfig,
+    Device,
+};
+
+/// The OpenAPI document describing the routes, also served at `/v1/openapi.json`
+pub const OPENAPI: &str = include_str!("openapi.json");
+
+/// Longest request line and headers
+const MAX_HEAD: u64 = 8 << 10;
+/// Largest request body
+const MAX_BODY: usize = 64 << 10;
+
+struct Request {
+    method: String,
+    path: String,
+    authorization: Option<Zeroizing<String>>,
+    body: Zeroizing<Vec<u8>>,
+}
+
+struct Response {
+    status: u16,
+    body: Option<Value>,
+}
+
+impl Response {
+    fn json(body: Value) -> Self {
+        Response {
+            status: 200,
+            body: Some(body),
+        }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Self {
+        Response {
+            status,
+            body: Some(json!({ "error": message.into() })),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            204 => "No Content",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            413 => "Payload Too Large",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+/// The settings a `PUT` changes, those left out are kept
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PeerUpdate {
+    endpoint: Option<String>,
+    /// Replaces the allowed IPs
+    allowed_ips: Option<Vec<String>>,
+    persistent_keepalive_interval: Option<u16>,
+    preshared_key: Option<String>,
+}
+
+impl Drop for PeerUpdate {
+    fn drop(&mut self) {
+        self.preshared_key.zeroize();
+    }
+}
+
+impl Device {
+    /// Serve the JSON management API on `addr` until the device is closed, to clients sending
+    /// `Authorization: Bearer <token>`. Returns the bound address.
+    pub async fn serve_http_api(
+        self: &Arc<Self>,
+        addr: SocketAddr,
+        token: Zeroizing<String>,
+    ) -> WgResult<SocketAddr> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let device = Arc::clone(self);
+        let token = Arc::new(token);
+        let mut close_receiver = self.close_sender.subscribe();
+        if self.is_closing() {
+            return Err(WgError::TransportClosed);
+        }
+        self.tasks.spawn(async move {
+            loop {
+                tokio::select! {
+                    Ok((stream, _)) = listener.accept() => {
+                        let (device, token) = (Arc::clone(&device), Arc::clone(&token));
+                        let tasks = device.tasks.clone();
+                        tasks.spawn(async move {
+                            if let Err(e) = device.serve_http_conn(stream, &token).await {
+                                tracing::debug!(message = "Management request failed", error = ?e);
+                            }
+                        });
+                    }
+                    _ = close_receiver.recv() => break,
+                }
+            }
+        });
+        Ok(local_addr)
+    }
+
+    async fn serve_http_conn(
+        self: Arc<Self>,
+        mut stream: TcpStream,
+        token: &str,
+    ) -> std::io::Result<()> {
+        let mut close_receiver = self.close_sender.subscribe();
+        let read = tokio::time::timeout(API_TIMEOUT, read_request(&mut stream));
+        let request = match unless_closed(&mut close_receiver, read).await {
+            Some(Ok(Ok(request))) => request,
+            Some(Ok(Err(response))) => return write_response(&mut stream, response).await,
+            _ => return Ok(()),
+        };
+        let authorized = request
+            .authorization
+            .as_deref()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| {
+                ring::constant_time::verify_slices_are_equal(given.as_bytes(), token.as_bytes())
+                    .is_ok()
+            });
+        if !authorized {
+            let response = Response::error(401, "missing or wrong bearer token");
+            return write_response(&mut stream, response).await;
+        }
+        if (request.method.as_str(), request.path.as_str()) == ("GET", "/v1/events") {
+            return self.http_events(stream).await;
+        }
+
+        let response = self.route(&request).await;
+        let write = tokio::time::timeout(API_TIMEOUT, write_response(&mut stream, response));
+        match unless_closed(&mut close_receiver, write).await {
+            Some(Ok(result)) => result,
+            _ => Ok(()),
+        }
+    }
+
+    async fn route(&self, request: &Request) -> Response {
+        let segments: Vec<_> = request.path.split('/').skip(1).collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["v1", "device"]) => self.http_device().await,
+            ("GET", ["v1", "peers"]) => match self.interface_state().await {
+                Ok(state) => Response::json(state.peers.iter().map(peer_json).collect()),
+                Err(e) => Response::error(500, e.to_string()),
+            },
+            ("GET", ["v1", "stats"]) => self.http_stats().await,
+            ("GET", ["v1", "openapi.json"]) => match serde_json::from_str(OPENAPI) {
+                Ok(schema) => Response::json(schema),
+                Err(e) => Response::error(500, e.to_string()),
+            },
+            (method, ["v1", "peers", key]) => {
+                let Some(pub_key) = path_key(key) else {
+                    return Response::error(400, "invalid public key");
+                };
+                match method {
+                    "GET" => self.http_peer(&pub_key).await,
+                    "PUT" => self.http_put_peer(pub_key, &request.body).await,
+                    "DELETE" => self.http_delete_peer(pub_key).await,
+                    _ => Response::error(404, "no such route"),
+                }
+            }
+            _ => Response::error(404, "no such route"),
+        }
+    }
+
+    /// What `get=1` reports, without the keys
+    async fn interface_state(&self) -> WgResult<InterfaceState> {
+        let lines = {
+            let _config = self.config_lock.lock().await;
+            self.api_get(false).await
+        };
+        InterfaceState::from_uapi(&self.name, &lines)
+    }
+
+    async fn http_device(&self) -> Response {
+        let state = match self.interface_state().await {
+            Ok(state) => state,
+            Err(e) => return Response::error(500, e.to_string()),
+        };
+        let public_key = self
+            .key_pair
+            .read()
+            .await
+            .as_ref()
+            .map(|(_, public_key)| KeyBytes(public_key.to_bytes()).to_string());
+        Response::json(json!({
+            "name": state.name,
+            "public_key": public_key,
+            "listen_port": state.listen_port,
+            "peers": state.peers.len(),
+        }))
+    }
+
+    async fn http_peer(&self, pub_key: &x25519::PublicKey) -> Response {
+        match self.interface_state().await {
+            Ok(state) => match state.peers.iter().find(|p| &p.public_key == pub_key) {
+                Some(peer) => Response::json(peer_json(peer)),
+                None => Response::error(404, "no such peer"),
+            },
+            Err(e) => Response::error(500, e.to_string()),
+        }
+    }
+
+    async fn http_put_peer(&self, pub_key: x25519::PublicKey, body: &[u8]) -> Response {
+        let update: PeerUpdate = match serde_json::from_slice(body) {
+            Ok(update) => update,
+            Err(e) => return Response::error(400, e.to_string()),
+        };
+        // The same settings as the lines of `set=1`
+        let mut options = Vec::new();
+        if let Some(endpoint) = &update.endpoint {
+            options.push(("endpoint", endpoint.clone()));
+        }
+        if let Some(allowed_ips) = &update.allowed_ips {
+            options.push(("replace_allowed_ips", "true".to_owned()));
+            options.extend(allowed_ips.iter().map(|ip| ("allowed_ip", ip.clone())));
+        }
+        if let Some(interval) = update.persistent_keepalive_interval {
+            options.push(("persistent_keepalive_interval", interval.to_string()));
+        }
+        let mut config = PeerConfig::new(pub_key);
+        for (key, val) in &options {
+            if set_peer_option(&mut config, key, val).is_err() {
+                return Response::error(400, format!("invalid {key} {val}"));
+            }
+        }
+        if let Some(preshared_key) = &update.preshared_key {
+            if set_peer_option(&mut config, "preshared_key", preshared_key).is_err() {
+                return Response::error(400, "invalid preshared_key");
+            }
+        }
+
+        let updated = {
+            let _config = self.config_lock.lock().await;
+            self.update_peer(config).await
+        };
+        match updated {
+            Ok(()) => self.http_peer(&pub_key).await,
+            Err(e) if e.errno() == libc::EINVAL => Response::error(400, e.to_string()),
+            Err(e) => Response::error(500, e.to_string()),
+        }
+    }
+
+    async fn http_delete_peer(&self, pub_key: x25519::PublicKey) -> Response {
+        let _config = self.config_lock.lock().await;
+        if !self.peers.contains_key(&pub_key) {
+            return Response::error(404, "no such peer");
+        }
+        let mut config = PeerConfig::new(pub_key);
+        config.remove = true;
+        match self.update_peer(config).await {
+            Ok(()) => Response {
+                status: 204,
+                body: None,
+            },
+            Err(e) => Response::error(500, e.to_string()),
+        }
+    }
+
+    async fn http_stats(&self) -> Response {
+        let stats = self
+            .all_stats()
+            .await
+            .into_iter()
+            .map(|(public_key, stats)| {
+                json!({
+                    "public_key": KeyBytes(public_key.to_bytes()).to_string(),
+                    "last_handshake": unix_secs(stats.last_handshake),
+                    "tx_bytes": stats.tx_bytes,
+                    "rx_bytes": stats.rx_bytes,
+                    "tx_wire_bytes": stats.tx_wire_bytes,
+                    "rx_wire_bytes": stats.rx_wire_bytes,
+                    "tx_packets": stats.tx_packets,
+                    "rx_packets": stats.rx_packets,
+                    "handshake_attempts": stats.handshake_attempts,
+                    "handshake_failures": stats.handshake_failures,
+                    "cookie_replies_sent": stats.cookie_replies_sent,
+                    "cookie_replies_received": stats.cookie_replies_received,
+                    "replay_failures": stats.replay_failures,
+                    "aead_failures": stats.aead_failures,
+                    "queue_drops": stats.queue_drops,
+                    "estimated_loss": stats.estimated_loss,
+                    "rtt_ms": stats.rtt,
+                })
+            });
+        Response::json(stats.collect())
+    }
+
+    /// Stream the events as JSON lines until the client hangs up or the device is closed
+    async fn http_events(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        let events = self.subscribe();
+        futures_util::pin_mut!(events);
+        let mut close_receiver = self.close_sender.subscribe();
+        if self.is_closing() {
+            return Ok(());
+        }
+        let (mut reader, mut writer) = stream.split();
+        writer
+            .write_all(
+                b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+            )
+            .await?;
+        let mut probe = [0; 64];
+        loop {
+            tokio::select! {
+                Some(event) = events.next() => {
+                    let mut line = event_json(&event).to_string();
+                    line.push('\n');
+                    writer.write_all(line.as_bytes()).await?;
+                }
+                read = reader.read(&mut probe) => {
+                    if read? == 0 {
+                        break;
+                    }
+                }
+                _ = close_receiver.recv() => break,
+                else => break,
+            }
+        }
+        writer.shutdown().await
+    }
+}
+
+fn peer_json(peer: &PeerState) -> Value {
+    json!({
+        "public_key": KeyBytes(peer.public_key.to_bytes()).to_string(),
+        "endpoint": peer.endpoint.as_ref().map(|endpoint| endpoint.to_string()),
+        "allowed_ips": peer.allowed_ips.iter().map(|ip| ip.to_string()).collect::<Vec<_>>(),
+        "persistent_keepalive_interval": peer.persistent_keepalive,
+        "last_handshake": unix_secs(peer.last_handshake),
+        "rx_bytes": peer.rx_bytes,
+        "tx_bytes": peer.tx_bytes,
+    })
+}
+
+/// The lines of `subscribe=1` as an object, with the key in base64
+fn event_json(event: &DeviceEvent) -> Value {
+    let mut object = Map::new();
+    for line in event.to_uapi() {
+        let Some((key, val)) = line.split_once('=') else {
+            continue;
+        };
+        let val = match key {
+            "public_key" => match val.parse::<KeyBytes>() {
+                Ok(key_bytes) => json!(key_bytes.to_string()),
+                Err(_) => json!(val),
+            },
+            "missed" => val.parse::<u64>().map_or_else(|_| json!(val), Value::from),
+            _ => json!(val),
+        };
+        object.insert(key.to_owned(), val);
+    }
+    Value::Object(object)
+}
+
+fn unix_secs(time: Option<std::time::SystemTime>) -> Option<u64> {
+    Some(time?.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+/// A public key in a path: hex, URL-safe base64, or base64 with `+`, `/` and `=`
+/// percent-encoded
+fn path_key(segment: &str) -> Option<x25519::PublicKey> {
+    let mut key = String::with_capacity(segment.len());
+    let mut chars = segment.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '%' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                key.push(u8::from_str_radix(&hex, 16).ok()? as char);
+            }
+            '-' => key.push('+'),
+            '_' => key.push('/'),
+            c => key.push(c),
+        }
+    }
+    if key.len() == 43 {
+        key.push('=');
+    }
+    key.parse::<KeyBytes>()
+        .ok()
+        .map(|key_bytes| x25519::PublicKey::from(key_bytes.0))
+}
+
+/// Read the request line, the headers that matter and the body, or the response refusing
+/// the request
+async fn read_request(stream: &mut TcpStream) -> Result<Request, Response> {
+    let bad_request = |_| Response::error(400, "malformed request");
+    let mut reader = BufReader::new(stream);
+    let (request_line, authorization, content_length) = {
+        let mut head = (&mut reader).take(MAX_HEAD);
+        let mut request_line = String::new();
+        head.read_line(&mut request_line)
+            .await
+            .map_err(bad_request)?;
+        let (mut authorization, mut content_length) = (None, 0);
+        loop {
+            let mut header = Zeroizing::new(String::new());
+            if head.read_line(&mut header).await.map_err(bad_request)? == 0 {
+                return Err(Response::error(400, "request head too long or truncated"));
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let Some((name, value)) = header.split_once(':') else {
+                return Err(Response::error(400, "malformed header"));
+            };
+            match name.to_ascii_lowercase().as_str() {
+                "authorization" => authorization = Some(Zeroizing::new(value.trim().to_owned())),
+                "content-length" => {
+                    content_length = value
+                        .trim()
+                        .parse()
+                        .map_err(|_| Response::error(400, "invalid content-length"))?
+                }
+                _ => {}
+            }
+        }
+        (request_line, authorization, content_length)
+    };
+    if content_length > MAX_BODY {
+        return Err(Response::error(413, "body too large"));
+    }
+    let mut body = Zeroizing::new(vec![0; content_length]);
+    reader.read_exact(&mut body).await.map_err(bad_request)?;
+
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(Response::error(400, "malformed request line"));
+    };
+    let path = target.split('?').next().unwrap_or_default();
+    Ok(Request {
+        method: method.to_owned(),
+        path: path.trim_end_matches('/').to_owned(),
+        authorization,
+        body,
+    })
+}
+
+async fn write_response(stream: &mut TcpStream, response: Response) -> std::io::Result<()> {
+    let body = response
+        .body
+        .as_ref()
+        .map(Value::to_string)
+        .unwrap_or_default();
+    let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, response.reason());
+    if response.body.is_some() {
+        head.push_str("Content-Type: application/json\r\n");
+    }
+    if response.status == 401 {
+        head.push_str("WWW-Authenticate: Bearer\r\n");
+    }
+    head.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    ));
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncBufReadExt;
+
+    use crate::uapi::Client;
+
+    use super::*;
+
+    /// Check `value` against the parts of the OpenAPI schema language the document uses
+    fn validate(schema: &Value, value: &Value, document: &Value) -> Result<(), String> {
+        if let Some(reference) = schema["$ref"].as_str() {
+            let pointer = reference.trim_start_matches('#');
+            return validate(&document.pointer(pointer).unwrap().clone(), value, document);
+        }
+        if value.is_null() {
+            return match schema["nullable"].as_bool() {
+                Some(true) => Ok(()),
+                _ => Err(format!("null where {schema} is expected")),
+            };
+        }
+        let valid_type = match schema["type"].as_str() {
+            Some("object") => value.is_object(),
+            Some("array") => value.is_array(),
+            Some("string") => value.is_string(),
+            Some("integer") => value.is_u64() || value.is_i64(),
+            Some("number") => value.is_number(),
+            _ => true,
+        };
+        if !valid_type {
+            return Err(format!("{value} is not of {schema}"));
+        }
+        if let Some(variants) = schema["enum"].as_array() {
+            if !variants.contains(value) {
+                return Err(format!("{value} is not one of {variants:?}"));
+            }
+        }
+        if let Some(items) = value.as_array() {
+            for item in items {
+                validate(&schema["items"], item, document)?;
+            }
+        }
+        if let Some(object) = value.as_object() {
+            for required in schema["required"].as_array().into_iter().flatten() {
+                if !object.contains_key(required.as_str().unwrap()) {
+                    return Err(format!("{value} has no {required}"));
+                }
+            }
+            for (key, property) in object {
+                match schema["properties"].get(key) {
+                    Some(property_schema) => validate(property_schema, property, document)?,
+                    None if schema["additionalProperties"] == false => {
+                        return Err(format!("{value} has the unknown {key}"))
+                    }
+                    None => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The schema of the JSON response to `method` on `path` with `status`
+    fn response_schema<'a>(
+        document: &'a Value,
+        path: &str,
+        method: &str,
+        status: u16,
+    ) -> &'a Value {
+        &document["paths"][path][method]["responses"][status.to_string()]["content"]
+            ["application/json"]["schema"]
+    }
+
+    async fn request(
+        addr: SocketAddr,
+        method: &str,
+        path: &str,
+        token: &str,
+        body: Option<Value>,
+    ) -> (u16, Value) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let body = body.map(|body| body.to_string()).unwrap_or_default();
+        let request = format!(
+            "{method} {path} HTTP/1.1\r\nHost: test\r\nAuthorization: Bearer {token}\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+        let body = match body {
+            "" => Value::Null,
+            body => serde_json::from_str(body).unwrap(),
+        };
+        (status, body)
+    }
+
+    #[test]
+    fn schema_references() {
+        let document: Value = serde_json::from_str(OPENAPI).unwrap();
+        let mut pending = vec![&document];
+        while let Some(value) = pending.pop() {
+            if let Some(reference) = value.get("$ref").and_then(Value::as_str) {
+                let pointer = reference.trim_start_matches('#');
+                assert!(document.pointer(pointer).is_some(), "{reference}");
+            }
+            match value {
+                Value::Object(object) => pending.extend(object.values()),
+                Value::Array(items) => pending.extend(items),
+                _ => {}
+            }
+        }
+        let update = &document["components"]["schemas"]["PeerUpdate"]["properties"];
+        let fields = [
+            "endpoint",
+            "allowed_ips",
+            "persistent_keepalive_interval",
+            "preshared_key",
+        ];
+        assert_eq!(update.as_object().unwrap().len(), fields.len());
+        for field in fields {
+            let body = json!({ field: Value::Null });
+            assert!(
+                serde_json::from_value::<PeerUpdate>(body).is_ok(),
+                "{field}"
+            );
+            assert!(update.get(field).is_some(), "{field}");
+        }
+        assert!(serde_json::from_value::<PeerUpdate>(json!({ "remove": true })).is_err());
+    }
+
+    #[test]
+    fn path_keys() {
+        let key = KeyBytes([0xfb; 32]);
+        let base64 = key.to_string();
+        assert!(base64.contains('+') && base64.contains('/'));
+        let expected = Some(x25519::PublicKey::from(key.0));
+        assert_eq!(path_key(&key.to_hex()), expected);
+        let url_safe = base64.replace('+', "-").replace('/', "_");
+        assert_eq!(path_key(url_safe.trim_end_matches('=')), expected);
+        let encoded = base64
+            .replace('+', "%2B")
+            .replace('/', "%2f")
+            .replace('=', "%3D");
+        assert_eq!(path_key(&encoded), expected);
+        assert_eq!(path_key("%zz"), None);
+    }
+
+    #[tokio::test]
+    async fn management_api() {
+        let document: Value = serde_json::from_str(OPENAPI).unwrap();
+        let device = Device::new("wgloop27".to_owned()).await.unwrap();
+        device.set_key(x25519::StaticSecret::from([3; 32])).await;
+        let addr = device
+            .serve_http_api(
+                "127.0.0.1:0".parse().unwrap(),
+                Zeroizing::new("secret".to_owned()),
+            )
+            .await
+            .unwrap();
+        let check = |path: &str, method: &str, status: u16, body: &Value| {
+            let schema = response_schema(&document, path, method, status);
+            validate(schema, body, &document).unwrap();
+        };
+
+        let (status, body) = request(addr, "GET", "/v1/device", "guess", None).await;
+        assert_eq!(status, 401);
+        assert!(body["error"].is_string());
+
+        let (status, body) = request(addr, "GET", "/v1/device", "secret", None).await;
+        assert_eq!(status, 200);
+        check("/v1/device", "get", 200, &body);
+        assert_eq!(body["name"], "wgloop27");
+        assert_eq!(body["peers"], 0);
+
+        let key_b = KeyBytes([9; 32]);
+        let path = format!("/v1/peers/{}", key_b.to_hex());
+        let update = json!({
+            "endpoint": "192.0.2.2:51820",
+            "allowed_ips": ["10.0.0.2/32"],
+            "persistent_keepalive_interval": 25,
+        });
+        let (status, body) = request(addr, "PUT", &path, "secret", Some(update)).await;
+        assert_eq!(status, 200);
+        check("/v1/peers/{public_key}", "put", 200, &body);
+        assert_eq!(body["public_key"], key_b.to_string());
+        assert_eq!(body["allowed_ips"], json!(["10.0.0.2/32"]));
+        assert_eq!(body["persistent_keepalive_interval"], 25);
+        let update = json!({ "allowed_ips": ["10.0.0.3/32"] });
+        let (_, body) = request(addr, "PUT", &path, "secret", Some(update)).await;
+        assert_eq!(body["allowed_ips"], json!(["10.0.0.3/32"]));
+        assert_eq!(body["endpoint"], "192.0.2.2:51820");
+        let invalid = json!({ "allowed_ips": ["10.0.0.300/32"] });
+        let (status, body) = request(addr, "PUT", &path, "secret", Some(invalid)).await;
+        assert_eq!(status, 400);
+        check("/v1/peers/{public_key}", "put", 400, &body);
+
+        // The control socket sees the same peers
+        let state = Client::new("wgloop27").get().await.unwrap();
+        assert_eq!(state.peers.len(), 1);
+        assert_eq!(state.peers[0].allowed_ips[0].to_string(), "10.0.0.3/32");
+
+        let (status, body) = request(addr, "GET", "/v1/peers", "secret", None).await;
+        assert_eq!(status, 200);
+        check("/v1/peers", "get", 200, &body);
+        let (status, body) = request(addr, "GET", "/v1/stats", "secret", None).await;
+        assert_eq!(status, 200);
+        check("/v1/stats", "get", 200, &body);
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        let (status, body) = request(addr, "GET", "/v1/openapi.json", "secret", None).await;
+        assert_eq!((status, &body), (200, &document));
+
+        let mut events = TcpStream::connect(addr).await.unwrap();
+        events
+            .write_all(b"GET /v1/events HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n")
+            .await
+            .unwrap();
+        let mut events = BufReader::new(events).lines();
+        while !events.next_line().await.unwrap().unwrap().is_empty() {}
+
+        let (status, body) = request(addr, "DELETE", &path, "secret", None).await;
+        assert_eq!((status, body), (204, Value::Null));
+        let (status, body) = request(addr, "DELETE", &path, "secret", None).await;
+        assert_eq!(status, 404);
+        check("/v1/peers/{public_key}", "delete", 404, &body);
+
+        let line = tokio::time::timeout(Duration::from_secs(2), events.next_line())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let event: Value = serde_json::from_str(&line).unwrap();
+        let schema = &document["paths"]["/v1/events"]["get"]["responses"]["200"]["content"]
+            ["application/x-ndjson"]["schema"];
+        validate(schema, &event, &document).unwrap();
+        assert_eq!(
+            event,
+            json!({ "event": "peer_removed", "public_key": key_b.to_string() })
+        );
+
+        device.shutdown().await.unwrap();
+        assert_eq!(events.next_line().await.unwrap(), None);
+    }
+}
@@ -0,0 +1,602 @@
+//! Device level counters, and their OpenMetrics rendering together with the peer statistics.
+//!
+//! With the `metrics` feature [`Device::serve_metrics`] exposes them over HTTP for Prometheus.
+
+use std::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicU64, Ordering},
+    time::UNIX_EPOCH,
+};
+
+use base64::Engine;
+
+use crate::{noise::errors::WireGuardError, x25519};
+
+use super::Device;
+
+/// Why a packet was discarded
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DropReason {
+    /// No peer has the destination in its allowed IPs
+    NoRoute,
+    /// The peer has no endpoint to send to yet
+    NoEndpoint,
+    /// No transport handles the peer's endpoint
+    NoTransport,
+    /// The message does not belong to any known peer
+    UnknownPeer,
+    /// The decrypted packet's source is not in the sending peer's allowed IPs
+    DisallowedSource,
+    /// The packet is malformed or unexpected
+    InvalidPacket,
+    /// The message's MAC is wrong, it is not meant for this device
+    BadMac,
+    /// The data message was already received or is too old
+    Replay,
+    /// The data message failed to decrypt
+    AeadFailure,
+    /// Answered with a cookie reply instead of being processed
+    RateLimited,
+    /// The queue of packets waiting for a session is full
+    QueueFull,
+    /// Denied by the packet filter
+    Filtered,
+    /// Denied by the relay ACL
+    RelayDenied,
+    /// Writing to the transport or the interface failed
+    SendFailed,
+    /// The device has no private key yet
+    KeyNotSet,
+    /// The message was not obfuscated the way its peer is configured to be
+    ObfuscationMismatch,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 16] = [
+        DropReason::NoRoute,
+        DropReason::NoEndpoint,
+        DropReason::NoTransport,
+        DropReason::UnknownPeer,
+        DropReason::DisallowedSource,
+        DropReason::InvalidPacket,
+        DropReason::BadMac,
+        DropReason::Replay,
+        DropReason::AeadFailure,
+        DropReason::RateLimited,
+        DropReason::QueueFull,
+        DropReason::Filtered,
+        DropReason::RelayDenied,
+        DropReason::SendFailed,
+        DropReason::KeyNotSet,
+        DropReason::ObfuscationMismatch,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DropReason::NoRoute => "no_route",
+            DropReason::NoEndpoint => "no_endpoint",
+            DropReason::NoTransport => "no_transport",
+            DropReason::UnknownPeer => "unknown_peer",
+            DropReason::DisallowedSource => "disallowed_source",
+            DropReason::InvalidPacket => "invalid_packet",
+            DropReason::BadMac => "bad_mac",
+            DropReason::Replay => "replay",
+            DropReason::AeadFailure => "aead_failure",
+            DropReason::RateLimited => "rate_limited",
+            DropReason::QueueFull => "queue_full",
+            DropReason::Filtered => "filtered",
+            DropReason::RelayDenied => "relay_denied",
+            DropReason::SendFailed => "send_failed",
+            DropReason::KeyNotSet => "key_not_set",
+            DropReason::ObfuscationMismatch => "obfuscation_mismatch",
+        }
+    }
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<&WireGuardError> for DropReason {
+    fn from(e: &WireGuardError) -> Self {
+        match e {
+            WireGuardError::InvalidMac => DropReason::BadMac,
+            WireGuardError::DuplicateCounter | WireGuardError::InvalidCounter => DropReason::Replay,
+            WireGuardError::InvalidAeadTag => DropReason::AeadFailure,
+            WireGuardError::UnderLoad => DropReason::RateLimited,
+            _ => DropReason::InvalidPacket,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DeviceMetrics {
+    /// Packets read from the interface
+    pub iface_rx_packets: AtomicU64,
+    /// Packets written to the interface
+    pub iface_tx_packets: AtomicU64,
+    /// WireGuard messages received from the transports
+    pub network_rx_packets: AtomicU64,
+    /// WireGuard messages sent through the transports
+    pub network_tx_packets: AtomicU64,
+    /// Packets re-encrypted to another peer in relay mode
+    pub relayed_packets: AtomicU64,
+    /// Cookie replies sent by the device rate limiter
+    pub cookie_replies_sent: AtomicU64,
+    dropped: [AtomicU64; DropReason::ALL.len()],
+}
+
+impl DeviceMetrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self, reason: DropReason) -> u64 {
+        self.dropped[reason as usize].load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn drop_packet(&self, reason: DropReason) {
+        Self::inc(&self.dropped[reason as usize]);
+    }
+}
+
+pub(crate) fn encode_key(key: &x25519::PublicKey) -> String {
+    base64::engine::general_purpose::STANDARD.encode(key.as_bytes())
+}
+
+fn counter(out: &mut String, name: &str, help: &str, samples: &[(String, u64)]) {
+    let _ = writeln!(out, "# TYPE {name} counter");
+    let _ = writeln!(out, "# HELP {name} {help}");
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{name}_total{labels} {value}");
+    }
+}
+
+fn gauge<T: std::fmt::Display>(out: &mut String, name: &str, help: &str, samples: &[(String, T)]) {
+    let _ = writeln!(out, "# TYPE {name} gauge");
+    let _ = writeln!(out, "# HELP {name} {help}");
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{name}{labels} {value}");
+    }
+}
+
+impl Device {
+    /// Count a discarded packet and trace why, along with the peer and the address involved
+    pub(crate) fn drop_packet(
+        &self,
+        reason: DropReason,
+        peer: Option<&x25519::PublicKey>,
+        addr: Option<&dyn fmt::Display>,
+    ) {
+        self.metrics.drop_packet(reason);
+        tracing::debug!(
+            message = "Packet dropped",
+            device = %self.name,
+            %reason,
+            peer = peer.map(|key| tracing::field::display(encode_key(key))),
+            addr = addr.map(tracing::field::display),
+        );
+    }
+
+    /// Device and peer metrics in the OpenMetrics text format
+    pub async fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        let m = &self.metrics;
+        let device = format!("{{device=\"{}\"}}", self.name);
+        let load = |c: &AtomicU64| vec![(device.clone(), c.load(Ordering::Relaxed))];
+
+        counter(
+            &mut out,
+            "wg_iface_rx_packets",
+            "Packets read from the interface",
+            &load(&m.iface_rx_packets),
+        );
+        counter(
+            &mut out,
+            "wg_iface_tx_packets",
+            "Packets written to the interface",
+            &load(&m.iface_tx_packets),
+        );
+        counter(
+            &mut out,
+            "wg_network_rx_packets",
+            "WireGuard messages received",
+            &load(&m.network_rx_packets),
+        );
+        counter(
+            &mut out,
+            "wg_network_tx_packets",
+            "WireGuard messages sent",
+            &load(&m.network_tx_packets),
+        );
+        counter(
+            &mut out,
+            "wg_relayed_packets",
+            "Packets relayed between peers",
+            &load(&m.relayed_packets),
+        );
+        counter(
+            &mut out,
+            "wg_cookie_replies_sent",
+            "Cookie replies sent by the device rate limiter",
+            &load(&m.cookie_replies_sent),
+        );
+        let under_load = match self.rate_limiter().await {
+            Some(rate_limiter) => rate_limiter.under_load_events(),
+            None => 0,
+        };
+        counter(
+            &mut out,
+            "wg_rate_limiter_under_load",
+            "Handshake messages received while under load",
+            &[(device.clone(), under_load)],
+        );
+        let dropped: Vec<_> = DropReason::ALL
+            .iter()
+            .map(|reason| {
+                (
+                    format!(
+                        "{{device=\"{}\",reason=\"{}\"}}",
+                        self.name,
+                        reason.as_str()
+                    ),
+                    m.dropped(*reason),
+                )
+            })
+            .collect();
+        counter(
+            &mut out,
+            "wg_dropped_packets",
+            "Discarded packets",
+            &dropped,
+        );
+        let hits: Vec<_> = self
+            .filter
+            .read()
+            .await
+            .counters()
+            .into_iter()
+            .enumerate()
+            .map(|(rule, hits)| {
+                (
+                    format!("{{device=\"{}\",rule=\"{rule}\"}}", self.name),
+                    hits,
+                )
+            })
+            .collect();
+        counter(
+            &mut out,
+            "wg_filter_rule_hits",
+            "Packets matched by each filter rule, numbered in rule order",
+            &hits,
+        );
+
+        let stats = self.all_stats().await;
+        let peer = |pub_key: &x25519::PublicKey| {
+            format!(
+                "{{device=\"{}\",public_key=\"{}\"}}",
+                self.name,
+                encode_key(pub_key)
+            )
+        };
+        let peer_counter = |out: &mut String, name, help, f: fn(&_) -> u64| {
+            let samples: Vec<_> = stats.iter().map(|(k, s)| (peer(k), f(s))).collect();
+            counter(out, name, help, &samples);
+        };
+        peer_counter(
+            &mut out,
+            "wg_peer_rx_bytes",
+            "Payload bytes received",
+            |s| s.rx_bytes,
+        );
+        peer_counter(&mut out, "wg_peer_tx_bytes", "Payload bytes sent", |s| {
+            s.tx_bytes
+        });
+        peer_counter(
+            &mut out,
+            "wg_peer_rx_wire_bytes",
+            "WireGuard message bytes received",
+            |s| s.rx_wire_bytes,
+        );
+        peer_counter(
+            &mut out,
+            "wg_peer_tx_wire_bytes",
+            "WireGuard message bytes sent",
+            |s| s.tx_wire_bytes,
+        );
+        peer_counter(
+            &mut out,
+            "wg_peer_rx_packets",
+            "Data messages received",
+            |s| s.rx_packets,
+        );
+        peer_counter(&mut out, "wg_peer_tx_packets", "Data messages sent", |s| {
+            s.tx_packets
+        });
+        peer_counter(
+            &mut out,
+            "wg_peer_handshake_attempts",
+            "Handshake initiations sent",
+            |s| s.handshake_attempts,
+        );
+        peer_counter(
+            &mut out,
+            "wg_peer_handshake_failures",
+            "Failed or abandoned handshakes",
+            |s| s.handshake_failures,
+        );
+        peer_counter(
+            &mut out,
+            "wg_peer_cookie_replies_received",
+            "Cookie replies received",
+            |s| s.cookie_replies_received,
+        );
+        peer_counter(
+            &mut out,
+            "wg_peer_replay_failures",
+            "Replayed data messages",
+            |s| s.replay_failures,
+        );
+        peer_counter(
+            &mut out,
+            "wg_peer_aead_failures",
+            "Data messages that failed to decrypt",
+            |s| s.aead_failures,
+        );
+        peer_counter(
+            &mut out,
+            "wg_peer_queue_drops",
+            "Packets dropped while waiting for a session",
+            |s| s.queue_drops,
+        );
+
+        let last_handshake: Vec<_> = stats
+            .iter()
+            .map(|(k, s)| {
+                let time = s
+                    .last_handshake
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0.0, |d| d.as_secs_f64());
+                (peer(k), time)
+            })
+            .collect();
+        gauge(
+            &mut out,
+            "wg_peer_last_handshake_seconds",
+            "Unix time of the last handshake, 0 if none",
+            &last_handshake,
+        );
+        let loss: Vec<_> = stats
+            .iter()
+            .map(|(k, s)| (peer(k), s.estimated_loss))
+            .collect();
+        gauge(
+            &mut out,
+            "wg_peer_estimated_loss",
+            "Estimated packet loss ratio",
+            &loss,
+        );
+        let rtt: Vec<_> = stats
+            .iter()
+            .filter_map(|(k, s)| Some((peer(k), s.rtt? as f64 / 1000.0)))
+            .collect();
+        gauge(
+            &mut out,
+            "wg_peer_rtt_seconds",
+            "Round trip time of the last handshake",
+            &rtt,
+        );
+
+        out.push_str("# EOF\n");
+        out
+    }
+}
+
+#[cfg(feature = "metrics")]
+mod http {
+    use std::{io, net::SocketAddr, sync::Arc};
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::{
+        device::{
+            api::{unless_closed, API_TIMEOUT},
+            Device,
+        },
+        error::{WgError, WgResult},
+    };
+
+    const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+    /// Longest request line and headers accepted
+    const MAX_HEAD: u64 = 8 << 10;
+
+    impl Device {
+        /// Serve `GET /metrics` on `addr` until the device is closed, returns the bound address
+        pub async fn serve_metrics(self: &Arc<Self>, addr: SocketAddr) -> WgResult<SocketAddr> {
+            let listener = TcpListener::bind(addr).await?;
+            let local_addr = listener.local_addr()?;
+            let device = Arc::clone(self);
+            let mut close_receiver = self.close_sender.subscribe();
+            if self.is_closing() {
+                return Err(WgError::TransportClosed);
+            }
+            self.tasks.spawn(async move {
+                loop {
+                    tokio::select! {
+                        Ok((stream, _)) = listener.accept() => {
+                            let device = Arc::clone(&device);
+                            let tasks = device.tasks.clone();
+                            tasks.spawn(async move {
+                                if let Err(e) = device.serve_metrics_conn(stream).await {
+                                    tracing::debug!(message = "Metrics request failed", error = ?e);
+                                }
+                            });
+                        }
+                        _ = close_receiver.recv() => break,
+                    }
+                }
+            });
+            Ok(local_addr)
+        }
+
+        async fn serve_metrics_conn(&self, mut stream: TcpStream) -> io::Result<()> {
+            let mut close_receiver = self.close_sender.subscribe();
+            let read = tokio::time::timeout(API_TIMEOUT, read_request_line(&mut stream));
+            let response = match unless_closed(&mut close_receiver, read).await {
+                Some(Ok(Ok(Some(request_line)))) => {
+                    let mut parts = request_line.split_whitespace();
+                    match (parts.next(), parts.next()) {
+                        (Some("GET"), Some("/metrics")) => {
+                            let body = self.render_metrics().await;
+                            format!(
+                                "HTTP/1.1 200 OK\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                                body.len()
+                            )
+                        }
+                        _ => empty_response("404 Not Found"),
+                    }
+                }
+                Some(Ok(Ok(None))) => empty_response("413 Payload Too Large"),
+                Some(Ok(Err(_))) => empty_response("400 Bad Request"),
+                // Timed out or the device is closing
+                _ => return Ok(()),
+            };
+            let write = async {
+                stream.write_all(response.as_bytes()).await?;
+                stream.shutdown().await
+            };
+            let write = tokio::time::timeout(API_TIMEOUT, write);
+            match unless_closed(&mut close_receiver, write).await {
+                Some(Ok(result)) => result,
+                _ => Ok(()),
+            }
+        }
+    }
+
+    fn empty_response(status: &str) -> String {
+        format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+    }
+
+    /// Read the request line and skip the headers, the request has no body. None when they do
+    /// not fit in MAX_HEAD.
+    async fn read_request_line(stream: &mut TcpStream) -> io::Result<Option<String>> {
+        let mut head = BufReader::new(stream).take(MAX_HEAD);
+        let mut request_line = String::new();
+        head.read_line(&mut request_line).await?;
+        loop {
+            let mut header = String::new();
+            if head.read_line(&mut header).await? == 0 {
+                return match head.limit() {
+                    0 => Ok(None),
+                    _ => Err(io::ErrorKind::UnexpectedEof.into()),
+                };
+            }
+            if header.trim_end().is_empty() {
+                return Ok(Some(request_line));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_counters() {
+        let metrics = DeviceMetrics::default();
+        metrics.drop_packet(DropReason::Filtered);
+        metrics.drop_packet(DropReason::Filtered);
+        metrics.drop_packet(DropReason::NoRoute);
+        assert_eq!(metrics.dropped(DropReason::Filtered), 2);
+        assert_eq!(metrics.dropped(DropReason::NoRoute), 1);
+        assert_eq!(metrics.dropped(DropReason::RelayDenied), 0);
+    }
+
+    #[test]
+    fn reasons_from_errors() {
+        for (i, reason) in DropReason::ALL.iter().enumerate() {
+            assert_eq!(*reason as usize, i);
+        }
+        assert_eq!(
+            DropReason::from(&WireGuardError::DuplicateCounter),
+            DropReason::Replay
+        );
+        assert_eq!(
+            DropReason::from(&WireGuardError::InvalidAeadTag),
+            DropReason::AeadFailure
+        );
+        assert_eq!(
+            DropReason::from(&WireGuardError::WrongIndex),
+            DropReason::InvalidPacket
+        );
+    }
+
+    #[tokio::test]
+    async fn filter_rule_hits() {
+        use crate::device::filter::{Action, Direction, Filter, Rule};
+
+        let device = Device::new("wgloop33".to_owned()).await.unwrap();
+        device
+            .set_filter(Filter {
+                rules: vec![Rule::new(Action::Log), Rule::new(Action::Deny)],
+                policy: Action::Allow,
+            })
+            .await;
+        let mut packet = vec![0u8; 20];
+        packet[0] = 0x45;
+        let peer = x25519::PublicKey::from([1; 32]);
+        assert!(!device
+            .filter
+            .read()
+            .await
+            .check(&peer, Direction::In, &packet));
+        let metrics = device.render_metrics().await;
+        assert!(metrics.contains("wg_filter_rule_hits_total{device=\"wgloop33\",rule=\"1\"} 1\n"));
+        device.close();
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn bounded_requests() {
+        use std::{net::SocketAddr, time::Duration};
+
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpStream,
+        };
+
+        use crate::device::Device;
+
+        async fn get(addr: SocketAddr, request: &[u8]) -> String {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        }
+
+        let device = Device::new("wgloop32".to_owned()).await.unwrap();
+        let addr = device
+            .serve_metrics("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let response = get(addr, b"GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        let mut long = b"GET /metrics HTTP/1.1\r\nX: ".to_vec();
+        long.resize(16 << 10, b'a');
+        long.extend_from_slice(b"\r\n\r\n");
+        let response = get(addr, &long).await;
+        assert!(response.starts_with("HTTP/1.1 413"), "{response}");
+
+        // A client that never sends its request does not hold up the shutdown
+        let _idle = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        device.shutdown().await.unwrap();
+    }
+}
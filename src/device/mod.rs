@@ -1,82 +1,228 @@
 use crate::noise::{
-    errors::WireGuardError, handshake::parse_handshake_anon, rate_limiter::RateLimiter, Packet,
-    TunnResult,
+    errors::WireGuardError, handshake::parse_handshake_anon, rate_limiter::RateLimiter,
+    stats::PeerStats, Packet, TunnResult,
 };
 use dashmap::DashMap;
 use rand_core::{OsRng, RngCore};
 use std::{
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
-    sync::{atomic::AtomicU16, Arc},
+    borrow::Cow,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU16, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use crate::{
-    error::WgResult,
+    error::{WgError, WgResult},
+    secret::Secret,
     tun::{codec::PacketCodec, header::IpHeader, stream::TunStream},
     x25519,
 };
 
 use self::{
     allowed_ip::AllowedIP,
+    api::{ApiAccess, ApiTcp},
+    capture::Capture,
+    endpoint::Endpoint,
+    event::DeviceEvent,
+    filter::{Direction, Filter},
+    metrics::{DeviceMetrics, DropReason},
+    obfuscation::Obfuscator,
     peer::{Peer, PeerConfig},
+    relay::Relay,
+    resolve::{ResolveOptions, Resolver, SystemResolver},
+    transport::{TcpTransport, Transport, UdpTransport},
 };
 use bytes::Bytes;
 use futures_util::{stream::SplitSink, SinkExt, StreamExt};
-use ip_network::IpNetwork;
 use ip_network_table::IpNetworkTable;
-use tokio::{
-    net::UdpSocket,
-    sync::{Mutex, RwLock},
+use tokio::sync::{Mutex, RwLock};
+use tokio_util::{
+    codec::{Framed, LinesCodec},
+    task::TaskTracker,
 };
-use tokio_util::codec::{Framed, LinesCodec};
 pub mod allowed_ip;
 pub mod api;
+pub mod capture;
+pub mod endpoint;
+pub mod event;
+pub mod eyeballs;
+pub mod filter;
+#[cfg(feature = "http-api")]
+pub mod http_api;
+pub mod metrics;
+pub mod obfuscation;
 pub mod peer;
+pub mod relay;
+pub mod resolve;
+pub mod state;
+pub mod sync;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod transport;
+
+/// How long [`Device::shutdown`] waits for the device's tasks
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long closing waits for packets already handed to the interface
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How [`Device::with_options`] gets its interface and control socket
+pub struct DeviceOptions {
+    /// Directory of the control socket, [`api::SOCK_DIR`] by default
+    pub sock_dir: String,
+    /// The control socket, `<sock_dir>/<name>.sock` by default
+    pub api_path: Option<String>,
+    pub api_access: ApiAccess,
+    /// Also serve the control protocol over TCP
+    pub api_tcp: Option<ApiTcp>,
+    /// Also accept WireGuard over TCP on the listen port. `tcp://` endpoints are dialed either
+    /// way.
+    pub listen_tcp: bool,
+    /// An interface opened by someone else, such as a privileged helper, instead of creating one
+    pub tun: Option<TunStream>,
+    /// A control socket bound by someone else, such as systemd, which is left in place on
+    /// shutdown
+    pub api_listener: Option<std::os::unix::net::UnixListener>,
+}
+
+impl Default for DeviceOptions {
+    fn default() -> Self {
+        DeviceOptions {
+            sock_dir: api::SOCK_DIR.to_owned(),
+            api_path: None,
+            api_access: Default::default(),
+            api_tcp: None,
+            listen_tcp: false,
+            tun: None,
+            api_listener: None,
+        }
+    }
+}
 
 pub struct DeviceConfig {
     pub peers: Vec<PeerConfig>,
-    pub private_key: [u8; 32],
-    pub public_key: [u8; 32],
+    pub private_key: Option<Secret>,
+    pub public_key: Option<[u8; 32]>,
     pub listen_port: Option<u16>,
+    pub filter: Filter,
 }
 pub struct Device {
     pub key_pair: RwLock<Option<(x25519::StaticSecret, x25519::PublicKey)>>,
     pub close_sender: tokio::sync::broadcast::Sender<()>,
+    /// Every task spawned for the device, joined by `shutdown`
+    tasks: TaskTracker,
+    /// Set once the device is closing, nothing is sent to the peers afterwards
+    closing: AtomicBool,
+    events: tokio::sync::broadcast::Sender<DeviceEvent>,
     pub tun_out: Mutex<SplitSink<Framed<TunStream, PacketCodec>, Bytes>>, // TODO remove lock, use channel
     pub name: String,
     pub next_index: Mutex<IndexLfsr>,
     pub peers: DashMap<x25519::PublicKey, Arc<Mutex<Peer>>>,
     pub peers_by_ip: RwLock<IpNetworkTable<Arc<Mutex<Peer>>>>,
     pub peers_by_idx: DashMap<u32, Arc<Mutex<Peer>>>,
-    pub udp4: RwLock<Option<Arc<tokio::net::UdpSocket>>>,
-    pub udp6: RwLock<Option<Arc<tokio::net::UdpSocket>>>,
-    pub udp_close: tokio::sync::broadcast::Sender<()>,
+    /// Obfuscators of the peers that have one by the id of their key, so an incoming message is
+    /// tried once per key however many peers share it
+    pub obfuscators: DashMap<u32, HashMap<x25519::PublicKey, Arc<Obfuscator>>>,
+    pub relay: RwLock<Relay>,
+    pub filter: RwLock<Filter>,
+    pub metrics: DeviceMetrics,
+    capture: RwLock<Option<Arc<Capture>>>,
+    /// Transports added with `add_transport`, consulted before the listen port ones
+    pub transports: RwLock<Vec<Arc<dyn Transport>>>,
+    /// The UDP and TCP transports bound to `listen_port`
+    pub listen_transports: RwLock<Vec<Arc<dyn Transport>>>,
     pub listen_port: AtomicU16,
+    /// Whether the TCP transport accepts connections on `listen_port`
+    listen_tcp: AtomicBool,
     pub rate_limiter: RwLock<Option<Arc<RateLimiter>>>,
+    /// Where `persist_state` saves the state, a last time on shutdown
+    state_file: RwLock<Option<std::path::PathBuf>>,
+    /// Who may use the control socket
+    api_access: ApiAccess,
+    /// Serializes the control requests, see [`Device::lock_config`]
+    config_lock: Mutex<()>,
+    /// Looks up the hostname endpoints
+    resolver: RwLock<Arc<dyn Resolver>>,
+    resolve_options: RwLock<ResolveOptions>,
+    /// Wakes up the resolution of hostname endpoints
+    resolve_now: tokio::sync::Notify,
 }
 impl Device {
+    /// Create the interface `name` and its control socket. The device listens on a random port
+    /// until [`Device::open_listen_port`] or a `listen_port` picks one.
     pub async fn new(name: String) -> WgResult<Arc<Self>> {
-        let tun_stream = TunStream::new(&name)?;
+        Self::with_options(name, DeviceOptions::default()).await
+    }
+
+    /// Like [`Device::new`], with the control socket in `sock_dir`
+    pub async fn with_sock_dir(name: String, sock_dir: &str) -> WgResult<Arc<Self>> {
+        let options = DeviceOptions {
+            sock_dir: sock_dir.to_owned(),
+            ..Default::default()
+        };
+        Self::with_options(name, options).await
+    }
+
+    /// Like [`Device::new`], taking over what `options` already opened
+    pub async fn with_options(name: String, options: DeviceOptions) -> WgResult<Arc<Self>> {
+        let tun_stream = match options.tun {
+            Some(tun_stream) => tun_stream,
+            None => TunStream::new(&name)?,
+        };
         let mtu = tun_stream.mtu()?;
-        let (udp_close, _) = tokio::sync::broadcast::channel(1);
         let (close_sender, mut close_receiver) = tokio::sync::broadcast::channel(1);
         let (tun_out, mut tun_in) = Framed::new(tun_stream, PacketCodec { mtu }).split();
         let this = Arc::new(Self {
             close_sender,
+            tasks: TaskTracker::new(),
+            closing: AtomicBool::new(false),
+            events: event::channel(),
             tun_out: Mutex::new(tun_out),
             name,
             next_index: Default::default(),
             peers: Default::default(),
             peers_by_ip: RwLock::new(IpNetworkTable::new()),
             peers_by_idx: Default::default(),
-            udp4: Default::default(),
-            udp6: Default::default(),
+            obfuscators: Default::default(),
+            relay: Default::default(),
+            filter: Default::default(),
+            metrics: Default::default(),
+            capture: Default::default(),
+            transports: Default::default(),
+            listen_transports: Default::default(),
             key_pair: Default::default(),
-            udp_close,
             listen_port: Default::default(),
+            listen_tcp: AtomicBool::new(options.listen_tcp),
             rate_limiter: Default::default(),
+            state_file: Default::default(),
+            api_access: options.api_access,
+            config_lock: Mutex::new(()),
+            resolver: RwLock::new(Arc::new(SystemResolver)),
+            resolve_options: Default::default(),
+            resolve_now: Default::default(),
         });
-        this.open_listen_port(8001).await?;
-        let (api_listener, api_path) = this.create_api_listener().await?;
+        this.open_listen_port(0).await?;
+        this.resolve_endpoints();
+        let (api_listener, api_path) = match options.api_listener {
+            Some(listener) => {
+                listener.set_nonblocking(true)?;
+                (tokio::net::UnixListener::from_std(listener)?, None)
+            }
+            None => {
+                let path = options
+                    .api_path
+                    .unwrap_or_else(|| api::socket_path_in(&options.sock_dir, &this.name));
+                let listener = this.create_api_listener(&path, &this.api_access).await?;
+                (listener, Some(path))
+            }
+        };
+        if let Some(ApiTcp { addr, token }) = options.api_tcp {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            tracing::info!(message = "Serving the control protocol over TCP", addr = %listener.local_addr()?);
+            this.serve_api_tcp(listener, token);
+        }
 
         {
             // tunnel input handler
@@ -85,7 +231,7 @@ impl Device {
             let mut rate_limiter_interval =
                 tokio::time::interval(std::time::Duration::from_secs(1));
 
-            tokio::spawn(async move {
+            this.tasks.spawn(async move {
                 loop {
                     tokio::select! {
                         _ = update_interval.tick() => {
@@ -97,97 +243,278 @@ impl Device {
                             }
                         }
                         Some(Ok(packet)) = tun_in.next() => {
-                            // TODO handle error
-                            let _ = device.handle_iface_packet(packet).await;
-                        }
-                        Ok((api_conn, _)) = api_listener.accept() => {
-                            let (mut api_writer, mut api_reader) = Framed::new(api_conn, LinesCodec::new()).split::<String>();
-                            if let Some(Ok(line)) = api_reader.next().await {
-                                let status = match line.as_str() {
-                                    "get=1" => device.api_get(&mut api_writer).await,
-                                    "set=1" => device.api_set(&mut api_reader).await,
-                                    _ => libc::EIO,
-                                };
-
-                                api_writer.send(format!("errno={}\n", status)).await.ok();
+                            if let Err(e) = device.handle_iface_packet(packet).await {
+                                tracing::warn!(message = "Failed to handle interface packet", error = ?e);
                             }
                         }
-                        _ = close_receiver.recv() => {
-                            device.udp_close.send(()).ok();
-                            let _ = tokio::fs::remove_file(&api_path).await;
-                            let pub_keys: Vec<_> = device
-                                .peers
-                                .iter()
-                                .map(|entry| entry.key().clone())
-                                .collect();
-                            for pub_key in pub_keys {
-                                device.remove_peer(&pub_key).await;
-                            }
-                            break;
+                        Ok((api_conn, _)) = api_listener.accept() => {
+                            device.accept_api_conn(api_conn);
                         }
-
+                        _ = close_receiver.recv() => break,
                     }
                 }
+                // No more control connections
+                drop(api_listener);
+                device.teardown(api_path.as_deref()).await;
             });
         }
         Ok(this)
     }
 
+    /// Close the device and wait until every task it spawned has finished.
+    ///
+    /// The control socket stops accepting connections and is removed, packets already handed
+    /// to the interface are flushed, nothing is sent to the peers afterwards and the peers and
+    /// keys are dropped, which zeroizes the keys. Fails if tasks are still running after
+    /// [`SHUTDOWN_TIMEOUT`].
+    pub async fn shutdown(&self) -> WgResult<()> {
+        self.close();
+        self.tasks.close();
+        match tokio::time::timeout(SHUTDOWN_TIMEOUT, self.tasks.wait()).await {
+            Ok(()) => {
+                tracing::info!(message = "Device shut down", device = %self.name);
+                Ok(())
+            }
+            Err(_) => Err(WgError::ShutdownTimeout(self.tasks.len())),
+        }
+    }
+
+    /// Release everything once the main loop has stopped
+    async fn teardown(&self, api_path: Option<&str>) {
+        if let Some(api_path) = api_path {
+            // Fails after switching to a user that cannot write to the socket directory, the
+            // next start replaces the socket
+            match tokio::fs::remove_file(api_path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    tracing::warn!(message = "Failed to remove the control socket", path = api_path, error = %e);
+                }
+                _ => {}
+            }
+        }
+        let flush = async { self.tun_out.lock().await.flush().await };
+        if tokio::time::timeout(FLUSH_TIMEOUT, flush).await.is_err() {
+            tracing::warn!(message = "Timed out flushing the interface", device = %self.name);
+        }
+
+        let mut transports: Vec<_> = self.transports.write().await.drain(..).collect();
+        transports.extend(self.listen_transports.write().await.drain(..));
+        for transport in &transports {
+            transport.close();
+        }
+        for transport in &transports {
+            transport.wait_closed().await;
+        }
+
+        self.stop_capture().await;
+        if let Some(path) = self.state_file.read().await.as_deref() {
+            if let Err(e) = self.save_state_file(path).await {
+                tracing::warn!(message = "Failed to save the state", path = %path.display(), error = %e);
+            }
+        }
+        let pub_keys: Vec<_> = self.peers.iter().map(|entry| *entry.key()).collect();
+        for pub_key in pub_keys {
+            self.remove_peer(&pub_key).await;
+        }
+        self.key_pair.write().await.take();
+        self.rate_limiter.write().await.take();
+    }
+
     pub async fn update_timers(&self) {
-        let udp4 = self.udp4.read().await;
-        let udp6 = self.udp6.read().await;
-        let (udp4, udp6) = match (udp4.as_ref(), udp6.as_ref()) {
-            (Some(udp4), Some(udp6)) => (udp4, udp6),
-            _ => return,
-        };
         let mut dst_buf = vec![0u8; 65535];
         for peer in self.peers.iter() {
             let mut p = peer.lock().await;
-            let endpoint_addr = match p.addr {
-                Some(addr) => addr,
-                None => continue,
-            };
+            if p.addr.is_none() {
+                continue;
+            }
+            self.advance_race(&mut p).await;
             match p.update_timers(&mut dst_buf[..]) {
                 TunnResult::Done => {}
                 TunnResult::Err(WireGuardError::ConnectionExpired) => {
                     // p.close(); // close open udp socket
+                    // Reported once, the tunnel keeps returning it until the next handshake
+                    if !p.expired {
+                        p.expired = true;
+                        self.emit(DeviceEvent::SessionExpired {
+                            public_key: p.pub_key,
+                        });
+                    }
                 }
                 TunnResult::Err(e) => tracing::error!(message = "Timer error", error = ?e),
                 TunnResult::WriteToNetwork(packet) => {
-                    match endpoint_addr {
-                        SocketAddr::V4(_) => udp4.send_to(packet, &endpoint_addr).await.ok(),
-                        SocketAddr::V6(_) => udp6.send_to(packet, &endpoint_addr).await.ok(),
-                    };
+                    self.log_ephemeral_key(&mut p).await;
+                    self.send_to_peer(&mut p, packet).await;
                 }
-                _ => panic!("Unexpected result from update_timers"),
+                _ => tracing::error!("Unexpected result from update_timers"),
             };
         }
     }
 
+    /// Send a message to an endpoint through the first transport that handles it
+    pub async fn send_to(&self, packet: &[u8], endpoint: &Endpoint) -> WgResult<()> {
+        if self.is_closing() {
+            return Err(WgError::TransportClosed);
+        }
+        let transport = match self.transport_for(endpoint).await {
+            Some(transport) => transport,
+            None => {
+                self.drop_packet(DropReason::NoTransport, None, Some(endpoint));
+                return Ok(());
+            }
+        };
+        if let Err(e) = transport.send_to(packet, endpoint).await {
+            self.drop_packet(DropReason::SendFailed, None, Some(endpoint));
+            return Err(e);
+        }
+        DeviceMetrics::inc(&self.metrics.network_tx_packets);
+        Ok(())
+    }
+
+    /// Send a message, wrapped by `obfuscator` and preceded by its junk datagrams if given
+    async fn send_obfuscated(
+        &self,
+        packet: &[u8],
+        endpoint: &Endpoint,
+        obfuscator: Option<&Obfuscator>,
+        peer: Option<&x25519::PublicKey>,
+    ) -> WgResult<()> {
+        self.capture_ciphertext(peer, Direction::Out, endpoint, packet)
+            .await;
+        let obfuscator = match obfuscator {
+            Some(obfuscator) => obfuscator,
+            None => return self.send_to(packet, endpoint).await,
+        };
+        for junk in obfuscator.junk(packet) {
+            self.send_to(&junk, endpoint).await?;
+        }
+        self.send_to(&obfuscator.obfuscate(packet), endpoint).await
+    }
+
+    /// Unwrap an incoming message with the key whose tag it carries. That costs one MAC per
+    /// distinct key, not per peer, and only colliding key ids cost more.
+    fn deobfuscate<'a>(&self, packet: &'a [u8]) -> (Option<Arc<Obfuscator>>, Cow<'a, [u8]>) {
+        for peers in self.obfuscators.iter() {
+            let mut tried = Vec::new();
+            for obfuscator in peers.values() {
+                let key = &obfuscator.config().key;
+                if tried.contains(&key) {
+                    continue;
+                }
+                tried.push(key);
+                if let Some(unwrapped) = obfuscator.deobfuscate(packet) {
+                    return (Some(Arc::clone(obfuscator)), Cow::Owned(unwrapped));
+                }
+            }
+        }
+        (None, Cow::Borrowed(packet))
+    }
+
+    fn add_obfuscator(&self, pub_key: x25519::PublicKey, obfuscator: &Arc<Obfuscator>) {
+        self.obfuscators
+            .entry(obfuscator.key_id())
+            .or_default()
+            .insert(pub_key, Arc::clone(obfuscator));
+    }
+
+    fn forget_obfuscator(&self, pub_key: &x25519::PublicKey) {
+        self.obfuscators.retain(|_, peers| {
+            peers.remove(pub_key);
+            !peers.is_empty()
+        });
+    }
+
+    async fn transport_for(&self, endpoint: &Endpoint) -> Option<Arc<dyn Transport>> {
+        let transports = self.transports.read().await;
+        let listen_transports = self.listen_transports.read().await;
+        transports
+            .iter()
+            .chain(listen_transports.iter())
+            .find(|transport| transport.handles(endpoint))
+            .cloned()
+    }
+
+    /// Use an additional transport for the encrypted side. It takes precedence over the
+    /// listen port transports for the endpoints it handles.
+    pub async fn add_transport(self: &Arc<Self>, transport: Arc<dyn Transport>) {
+        self.spawn_receiver(Arc::clone(&transport));
+        self.transports.write().await.push(transport);
+    }
+
+    pub async fn remove_transport(&self, transport: &Arc<dyn Transport>) {
+        self.transports
+            .write()
+            .await
+            .retain(|t| !Arc::ptr_eq(t, transport));
+        transport.close();
+    }
+
+    fn spawn_receiver(self: &Arc<Self>, transport: Arc<dyn Transport>) {
+        let device = Arc::clone(self);
+        let mut buf = vec![0u8; 65535];
+        self.tasks.spawn(async move {
+            loop {
+                match transport.recv_from(&mut buf[..]).await {
+                    Ok((n, endpoint)) => {
+                        DeviceMetrics::inc(&device.metrics.network_rx_packets);
+                        let Some(rate_limiter) = device.rate_limiter().await else {
+                            device.drop_packet(DropReason::KeyNotSet, None, Some(&endpoint));
+                            continue;
+                        };
+                        if let Err(e) = device
+                            .handle_incoming_packet(endpoint, &buf[..n], &rate_limiter)
+                            .await
+                        {
+                            tracing::warn!(message = "Failed to handle incoming packet", error = ?e);
+                        }
+                    }
+                    Err(WgError::TransportClosed) => break,
+                    Err(e) => tracing::debug!(message = "Transport receive error", error = ?e),
+                }
+            }
+        });
+    }
+
     pub async fn handle_incoming_packet(
         &self,
-        udp: &UdpSocket,
-        addr: SocketAddr,
+        addr: Endpoint,
         packet: &[u8],
         rate_limiter: &RateLimiter,
     ) -> WgResult<()> {
         // self.tun_out.lock().await.send(packet).await
 
+        let (unwrapped_by, packet) = self.deobfuscate(packet);
         let mut dst_buf = vec![0u8; 65535];
-        let parsed_packet = match rate_limiter.verify_packet(Some(addr.ip()), packet, &mut dst_buf)
-        {
-            Ok(packet) => packet,
-            Err(TunnResult::WriteToNetwork(cookie)) => {
-                let _: Result<_, _> = udp.send_to(cookie, &addr).await;
-                return Ok(());
-            }
-            Err(_) => return Ok(()),
-        };
+        let parsed_packet =
+            match rate_limiter.verify_packet(Some(addr.addr().ip()), &packet, &mut dst_buf) {
+                Ok(packet) => packet,
+                Err(TunnResult::WriteToNetwork(cookie)) => {
+                    self.capture_ciphertext(None, Direction::In, &addr, &packet)
+                        .await;
+                    self.drop_packet(DropReason::RateLimited, None, Some(&addr));
+                    DeviceMetrics::inc(&self.metrics.cookie_replies_sent);
+                    let _: Result<_, _> = self
+                        .send_obfuscated(cookie, &addr, unwrapped_by.as_deref(), None)
+                        .await;
+                    return Ok(());
+                }
+                Err(e) => {
+                    self.capture_ciphertext(None, Direction::In, &addr, &packet)
+                        .await;
+                    let reason = match e {
+                        TunnResult::Err(e) => (&e).into(),
+                        _ => DropReason::InvalidPacket,
+                    };
+                    self.drop_packet(reason, None, Some(&addr));
+                    return Ok(());
+                }
+            };
 
         let peer = match &parsed_packet {
             Packet::HandshakeInit(p) => {
                 let key_pair = self.key_pair.read().await;
-                let (private_key, public_key) = key_pair.as_ref().expect("Key not set");
+                let Some((private_key, public_key)) = key_pair.as_ref() else {
+                    self.drop_packet(DropReason::KeyNotSet, None, Some(&addr));
+                    return Ok(());
+                };
                 parse_handshake_anon(private_key, public_key, p)
                     .ok()
                     .and_then(|hh| {
@@ -210,46 +537,90 @@ impl Device {
                 .map(|e| e.value().clone()),
         };
         let peer = match peer {
-            None => return Ok(()),
+            None => {
+                self.capture_ciphertext(None, Direction::In, &addr, &packet)
+                    .await;
+                self.drop_packet(DropReason::UnknownPeer, None, Some(&addr));
+                return Ok(());
+            }
             Some(peer) => peer,
         };
         let mut p = peer.lock().await;
+        self.capture_ciphertext(Some(&p.pub_key), Direction::In, &addr, &packet)
+            .await;
+        // Only the peer's own obfuscation is accepted, or plain messages if it has none
+        let expected = p.obfuscator.as_ref().map(|o| &o.config().key);
+        if unwrapped_by.as_ref().map(|o| &o.config().key) != expected {
+            self.drop_packet(
+                DropReason::ObfuscationMismatch,
+                Some(&p.pub_key),
+                Some(&addr),
+            );
+            return Ok(());
+        }
+        let obfuscator = p.obfuscator.clone();
+        let obfuscator = obfuscator.as_deref();
 
         // We found a peer, use it to decapsulate the message+
         let mut flush = false; // Are there packets to send from the queue?
-        match p
+        let mut relayed = None; // Destined to another peer, sent once this one is unlocked
+        let is_handshake = matches!(
+            parsed_packet,
+            Packet::HandshakeInit(_) | Packet::HandshakeResponse(_)
+        );
+        let is_cookie_reply = matches!(parsed_packet, Packet::PacketCookieReply(_));
+        let session = p.tunnel.current_session();
+        let result = p
             .tunnel
-            .handle_verified_packet(parsed_packet, &mut dst_buf[..])
-        {
-            TunnResult::Done => {}
-            TunnResult::Err(_) => return Ok(()),
+            .handle_verified_packet(parsed_packet, &mut dst_buf[..]);
+        if let TunnResult::Err(e) = &result {
+            self.drop_packet(e.into(), Some(&p.pub_key), Some(&addr));
+            return Ok(());
+        }
+        // The message authenticated, so where it came from is where the peer now is
+        if !is_cookie_reply && p.addr != Some(addr) {
+            self.emit(DeviceEvent::EndpointChanged {
+                public_key: p.pub_key,
+                old: p.addr,
+                new: addr,
+            });
+            p.addr = Some(addr);
+        }
+        if is_handshake {
+            p.expired = false;
+            p.race = None;
+        }
+        if p.tunnel.current_session() != session {
+            self.emit(DeviceEvent::HandshakeCompleted {
+                public_key: p.pub_key,
+                endpoint: addr,
+            });
+        }
+        match result {
+            TunnResult::Done | TunnResult::Err(_) => {}
             TunnResult::WriteToNetwork(packet) => {
                 flush = true;
-                let _: Result<_, _> = udp.send_to(packet, &addr).await;
+                self.log_ephemeral_key(&mut p).await;
+                let _: Result<_, _> = self
+                    .send_obfuscated(packet, &addr, obfuscator, Some(&p.pub_key))
+                    .await;
             }
-            TunnResult::WriteToTunnelV4(packet, addr) => {
-                // TODO remove clone to_vec
-                if p.is_allowed_ip(addr) {
-                    let _ = self
-                        .tun_out
-                        .lock()
-                        .await
-                        .send(bytes::Bytes::from(packet.to_vec()))
+            TunnResult::WriteToTunnelV4(packet, src) => {
+                if !p.is_allowed_ip(src) {
+                    self.drop_packet(DropReason::DisallowedSource, Some(&p.pub_key), Some(&src));
+                } else if self.filter_in(&p, packet).await {
+                    self.capture_plaintext(&p.pub_key, Direction::In, packet)
                         .await;
-
-                    // t.iface.write4(packet);
+                    relayed = self.deliver(&peer, packet).await;
                 }
             }
-            TunnResult::WriteToTunnelV6(packet, addr) => {
-                // TODO remove clone to_vec
-                if p.is_allowed_ip(addr) {
-                    let _ = self
-                        .tun_out
-                        .lock()
-                        .await
-                        .send(bytes::Bytes::from(packet.to_vec()))
+            TunnResult::WriteToTunnelV6(packet, src) => {
+                if !p.is_allowed_ip(src) {
+                    self.drop_packet(DropReason::DisallowedSource, Some(&p.pub_key), Some(&src));
+                } else if self.filter_in(&p, packet).await {
+                    self.capture_plaintext(&p.pub_key, Direction::In, packet)
                         .await;
-                    // t.iface.write6(packet);
+                    relayed = self.deliver(&peer, packet).await;
                 }
             }
         };
@@ -259,10 +630,18 @@ impl Device {
             while let TunnResult::WriteToNetwork(packet) =
                 p.tunnel.decapsulate(None, &[], &mut dst_buf[..])
             {
-                let _: Result<_, _> = udp.send_to(packet, &addr).await;
+                let _: Result<_, _> = self
+                    .send_obfuscated(packet, &addr, obfuscator, Some(&p.pub_key))
+                    .await;
             }
         }
 
+        if let Some((target, packet)) = relayed {
+            let from = p.pub_key;
+            drop(p);
+            self.relay(&from, &target, &packet).await;
+        }
+
         // // This packet was OK, that means we want to create a connected socket for this peer
         // let addr = addr.as_socket().unwrap();
         // let ip_addr = addr.ip();
@@ -278,116 +657,215 @@ impl Device {
     }
 
     pub async fn handle_iface_packet(&self, packet: Bytes) -> WgResult<()> {
+        DeviceMetrics::inc(&self.metrics.iface_rx_packets);
         let dst_addr = match IpHeader::from_slice(&packet).map(|h| h.dst_address()) {
             Some(addr) => addr,
-            None => return Ok(()), // keepalive
+            None => {
+                self.drop_packet(DropReason::InvalidPacket, None, None);
+                return Ok(());
+            }
         };
         let peer = match self.peers_by_ip.read().await.longest_match(dst_addr) {
             Some((_, peer)) => peer.clone(),
-            None => return Ok(()), // skip
+            None => {
+                self.drop_packet(DropReason::NoRoute, None, Some(&dst_addr));
+                return Ok(());
+            }
         };
         let mut peer = peer.lock().await;
+        if !self.filter_out(&peer, &packet).await {
+            return Ok(());
+        }
         // peer.lock().await.send_packet(packet).await?;
+        self.encapsulate_to(&mut peer, &packet).await
+    }
+
+    async fn encapsulate_to(&self, peer: &mut Peer, packet: &[u8]) -> WgResult<()> {
+        self.capture_plaintext(&peer.pub_key, Direction::Out, packet)
+            .await;
         let mut dst_buf = vec![0u8; 65535];
-        match peer.tunnel.encapsulate(&packet, &mut dst_buf[..]) {
-            TunnResult::Done => {}
-            TunnResult::Err(e) => {
-                tracing::error!(message = "Encapsulate error", error = ?e)
+        let queue_drops = peer.tunnel.queue_drops();
+        match peer.tunnel.encapsulate(packet, &mut dst_buf[..]) {
+            TunnResult::Done if peer.tunnel.queue_drops() > queue_drops => {
+                self.drop_packet(DropReason::QueueFull, Some(&peer.pub_key), None);
             }
+            TunnResult::Done => {}
+            TunnResult::Err(e) => return Err(e.into()),
             TunnResult::WriteToNetwork(packet) => {
-                if let Some(addr @ SocketAddr::V4(_)) = peer.addr {
-                    let _: Result<_, _> = self
-                        .udp4
-                        .write()
-                        .await
-                        .as_ref()
-                        .expect("Not connected")
-                        .send_to(packet, &addr)
-                        .await;
-                } else if let Some(addr @ SocketAddr::V6(_)) = peer.addr {
-                    let _: Result<_, _> = self
-                        .udp6
-                        .write()
-                        .await
-                        .as_ref()
-                        .expect("Not connected")
-                        .send_to(packet, &addr)
-                        .await;
-                } else {
-                    // tracing::error!("No endpoint");
-                }
+                self.log_ephemeral_key(peer).await;
+                self.send_to_peer(peer, packet).await;
             }
-            _ => panic!("Unexpected result from encapsulate"),
+            _ => return Err(WireGuardError::UnexpectedPacket.into()),
         };
+        Ok(())
+    }
+
+    async fn filter_in(&self, from: &Peer, packet: &[u8]) -> bool {
+        let allowed = self
+            .filter
+            .read()
+            .await
+            .check(&from.pub_key, Direction::In, packet);
+        if !allowed {
+            self.drop_packet(DropReason::Filtered, Some(&from.pub_key), None);
+        }
+        allowed
+    }
+
+    async fn filter_out(&self, to: &Peer, packet: &[u8]) -> bool {
+        let allowed = self
+            .filter
+            .read()
+            .await
+            .check(&to.pub_key, Direction::Out, packet);
+        if !allowed {
+            self.drop_packet(DropReason::Filtered, Some(&to.pub_key), None);
+        }
+        allowed
+    }
+
+    /// Write a packet decrypted from `from` to the TUN, unless relay mode routes it to another
+    /// peer, which is then returned along with a copy of the packet
+    async fn deliver(
+        &self,
+        from: &Arc<Mutex<Peer>>,
+        packet: &[u8],
+    ) -> Option<(Arc<Mutex<Peer>>, Bytes)> {
+        let dst_addr = IpHeader::from_slice(packet).map(|h| h.dst_address());
+        if let (true, Some(dst_addr)) = (self.relay.read().await.is_enabled(), dst_addr) {
+            let target = match self.peers_by_ip.read().await.longest_match(dst_addr) {
+                Some((_, target)) if !Arc::ptr_eq(target, from) => Some(Arc::clone(target)),
+                _ => None,
+            };
+            if let Some(target) = target {
+                return Some((target, Bytes::copy_from_slice(packet)));
+            }
+        }
+        // TODO remove clone to_vec
+        let sent = self
+            .tun_out
+            .lock()
+            .await
+            .send(Bytes::from(packet.to_vec()))
+            .await;
+        match sent {
+            Ok(()) => DeviceMetrics::inc(&self.metrics.iface_tx_packets),
+            Err(_) => self.drop_packet(DropReason::SendFailed, None, None),
+        }
+        None
+    }
 
+    /// Re-encrypt a packet decrypted from `from` to `target` if the relay ACL allows the pair
+    async fn relay(&self, from: &x25519::PublicKey, target: &Mutex<Peer>, packet: &[u8]) {
+        let mut target = target.lock().await;
+        if !self.relay.read().await.allows(from, &target.pub_key) {
+            let to = metrics::encode_key(&target.pub_key);
+            self.drop_packet(DropReason::RelayDenied, Some(from), Some(&to));
+            return;
+        }
+        if !self.filter_out(&target, packet).await {
+            return;
+        }
+        DeviceMetrics::inc(&self.metrics.relayed_packets);
+        if let Err(e) = self.encapsulate_to(&mut target, packet).await {
+            tracing::warn!(message = "Failed to relay packet", error = ?e);
+        }
+    }
+
+    /// Listen on `port` instead of the current listen port. The new port is bound before the
+    /// current one is closed, so the device keeps listening if that fails, and the current port
+    /// is kept as is along with its TCP connections.
+    pub async fn open_listen_port(self: &Arc<Self>, port: u16) -> WgResult<()> {
+        if port != 0 && port == self.listen_port.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let udp = UdpTransport::bind(port)?;
+        let port = udp.port();
+        let tcp = self.tcp_transport(port)?;
+        self.close_listen_transports().await;
+        self.install_listen_transports(port, [udp, tcp]).await;
         Ok(())
     }
 
-    pub async fn open_listen_port(self: &Arc<Self>, mut port: u16) -> WgResult<()> {
-        let _ = self.udp_close.send(());
-        self.udp4.write().await.take();
-        self.udp6.write().await.take();
-
-        let udp4 = Arc::new({
-            let udp = socket2::Socket::new(
-                socket2::Domain::IPV4,
-                socket2::Type::DGRAM,
-                Some(socket2::Protocol::UDP),
-            )?;
-            udp.set_reuse_address(true)?;
-            udp.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
-            udp.set_nonblocking(true)?;
-            tokio::net::UdpSocket::from_std(udp.into())?
-        });
+    async fn close_listen_transports(&self) {
+        for transport in self.listen_transports.write().await.drain(..) {
+            transport.close();
+        }
+    }
 
+    /// Accept WireGuard over TCP on the listen port or stop doing so. Only the TCP transport of
+    /// the listen port is replaced, and only if that changes anything.
+    pub async fn set_listen_tcp(self: &Arc<Self>, enabled: bool) -> WgResult<()> {
+        if self.listen_tcp.swap(enabled, Ordering::Relaxed) == enabled {
+            return Ok(());
+        }
+        let port = self.listen_port.load(Ordering::Relaxed);
         if port == 0 {
-            port = udp4.local_addr()?.port();
-        }
-        let udp6 = Arc::new({
-            let udp = socket2::Socket::new(
-                socket2::Domain::IPV6,
-                socket2::Type::DGRAM,
-                Some(socket2::Protocol::UDP),
-            )?;
-            udp.set_reuse_address(true)?;
-            udp.bind(&SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0).into())?;
-            udp.set_nonblocking(true)?;
-            tokio::net::UdpSocket::from_std(udp.into())?
+            return Ok(());
+        }
+        let tcp: Arc<dyn Transport> = match self.tcp_transport(port) {
+            Ok(tcp) => tcp,
+            Err(e) => {
+                self.listen_tcp.store(!enabled, Ordering::Relaxed);
+                return Err(e);
+            }
+        };
+        let probe = Endpoint::Tcp(([0, 0, 0, 0], 0).into());
+        let mut transports = self.listen_transports.write().await;
+        transports.retain(|transport| match transport.handles(&probe) {
+            true => {
+                transport.close();
+                false
+            }
+            false => true,
         });
+        transports.push(Arc::clone(&tcp));
+        drop(transports);
+        self.spawn_receiver(tcp);
+        Ok(())
+    }
 
-        {
-            let device = self.clone();
-            let mut udp_close = self.udp_close.subscribe();
-            let udp4 = Arc::clone(&udp4);
-            let udp6 = Arc::clone(&udp6);
-            let mut udp4_buf = vec![0u8; 65535];
-            let mut udp6_buf = vec![0u8; 65535];
-            tokio::spawn(async move {
-                loop {
-                    tokio::select! {
-                        Ok((n, addr)) = udp4.recv_from(&mut udp4_buf[..]) => {
-                            let rate_limiter = device.rate_limiter().await.expect("rate limiter not exists");
-                            let _ = device.handle_incoming_packet(&udp4,addr,&udp4_buf[..n],&rate_limiter).await;
-                        }
-                        Ok((n, addr)) = udp6.recv_from(&mut udp6_buf[..]) => {
-                            let rate_limiter = device.rate_limiter().await.expect("rate limiter not exists");
-                            let _ = device.handle_incoming_packet(&udp6,addr,&udp6_buf[..n],&rate_limiter).await;
-                        }
-                        _ = udp_close.recv() => {
-                            break
-                        }
-                    }
-                }
-            });
+    /// A TCP transport listening on `port` if TCP listening is enabled, dialing only otherwise
+    fn tcp_transport(&self, port: u16) -> WgResult<Arc<TcpTransport>> {
+        match self.listen_tcp.load(Ordering::Relaxed) {
+            true => TcpTransport::bind(port),
+            false => Ok(TcpTransport::dial_only(port)),
         }
+    }
 
-        self.udp4.write().await.replace(udp4);
-        self.udp6.write().await.replace(udp6);
-        self.listen_port
-            .store(port, std::sync::atomic::Ordering::Relaxed);
+    /// Receive on sockets bound by someone else, such as systemd, instead of the listen port.
+    /// With TCP listening enabled, TCP is bound to the port of the UDP sockets when no TCP
+    /// listener is given.
+    pub async fn use_listen_sockets(
+        self: &Arc<Self>,
+        udp: Vec<std::net::UdpSocket>,
+        tcp: Vec<std::net::TcpListener>,
+    ) -> WgResult<()> {
+        for transport in self.listen_transports.write().await.drain(..) {
+            transport.close();
+        }
 
+        let udp = UdpTransport::from_sockets(udp)?;
+        let port = udp.port();
+        let tcp = match tcp.is_empty() {
+            true => self.tcp_transport(port)?,
+            false => TcpTransport::from_listeners(tcp)?,
+        };
+        self.install_listen_transports(port, [udp, tcp]).await;
         Ok(())
     }
+
+    async fn install_listen_transports(
+        self: &Arc<Self>,
+        port: u16,
+        transports: [Arc<dyn Transport>; 2],
+    ) {
+        for transport in transports {
+            self.spawn_receiver(Arc::clone(&transport));
+            self.listen_transports.write().await.push(transport);
+        }
+        self.listen_port.store(port, Ordering::Relaxed);
+    }
     // pub async fn insert_tcp_peer(
     //     self: &Arc<Self>,
     //     stream: TcpStream,
@@ -405,52 +883,169 @@ impl Device {
     //     Ok(())
     // }
     pub fn close(&self) {
+        self.closing.store(true, Ordering::SeqCst);
         let _ = self.close_sender.send(());
     }
 
-    pub async fn update_peer(&self, config: PeerConfig) {
+    /// Whether `close` was called, for tasks subscribing to `close_sender` late
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+
+    /// Add a peer or change the settings given in `config` of an existing one
+    pub async fn update_peer(&self, config: PeerConfig) -> WgResult<()> {
         if config.remove {
             self.remove_peer(&config.pub_key).await;
+            return Ok(());
         }
-        // Update an existing peer
-        if self.peers.get(&config.pub_key).is_some() {
-            // We already have a peer, we need to merge the existing config into the newly created one
-            panic!("Modifying existing peers is not yet supported. Remove and add again instead.");
+        let networks = config.validate()?;
+
+        let resolve = config.hostname.is_some();
+        let existing = self.peers.get(&config.pub_key).map(|e| e.value().clone());
+        let peer = match existing {
+            Some(peer) => {
+                self.modify_peer(&peer, &config).await;
+                peer
+            }
+            None => self.add_peer(&config).await?,
+        };
+
+        let mut peers_by_ip = self.peers_by_ip.write().await;
+        for network in networks {
+            peers_by_ip.insert(network, Arc::clone(&peer));
+        }
+        if resolve {
+            self.resolve_now.notify_one();
         }
+        Ok(())
+    }
+
+    async fn add_peer(&self, config: &PeerConfig) -> WgResult<Arc<Mutex<Peer>>> {
+        let device_private = match self.key_pair.read().await.as_ref() {
+            Some((private_key, _)) => private_key.clone(),
+            None => return Err(WgError::KeyNotSet),
+        };
         let next_index = self.next_index.lock().await.next();
-        let device_private = self
-            .key_pair
-            .read()
-            .await
-            .as_ref()
-            .expect("Private key must be set first")
-            .0
-            .clone();
         let tunn = crate::noise::Tunn::new(
             device_private,
             config.pub_key,
-            config.preshared_key,
+            config.preshared_key.clone(),
             config.keepalive,
             next_index,
             None,
         )
-        .unwrap();
-        let peer = Peer::new(&config, tunn, next_index);
+        .map_err(|e| WgError::InvalidValue(format!("peer public key, {e}")))?;
+        let mut peer = Peer::new(config, tunn, next_index);
+        if let Some(capture) = self.capture().await.filter(|c| c.config.key_log) {
+            peer.tunnel.set_key_log(true);
+            capture.key_log(&peer.pub_key, &capture::peer_key_log(self, &peer).await);
+        }
+        if let Some(obfuscator) = &peer.obfuscator {
+            self.add_obfuscator(config.pub_key, obfuscator);
+        }
 
         let peer = Arc::new(Mutex::new(peer));
         self.peers.insert(config.pub_key, Arc::clone(&peer));
         self.peers_by_idx.insert(next_index, Arc::clone(&peer));
+        self.emit(DeviceEvent::PeerAdded {
+            public_key: config.pub_key,
+        });
+        Ok(peer)
+    }
 
-        for AllowedIP { addr, cidr } in config.allowed_ips {
-            self.peers_by_ip.write().await.insert(
-                IpNetwork::new_truncate(addr, cidr).expect("cidr is valid length"),
-                Arc::clone(&peer),
-            );
+    /// Apply the settings present in `config` to a running peer, keeping its sessions
+    async fn modify_peer(&self, peer: &Arc<Mutex<Peer>>, config: &PeerConfig) {
+        let mut p = peer.lock().await;
+        if let Some(endpoint) = config.endpoint {
+            p.addr = Some(endpoint);
+            p.hostname = None;
+            p.candidates = config.candidates.clone();
+            p.race = None;
+        }
+        if config.hostname.is_some() && config.hostname != p.hostname {
+            p.hostname = config.hostname.clone();
+            p.resolution = None;
+            p.candidates.clear();
+            p.race = None;
+        }
+        if let Some(keepalive) = config.keepalive {
+            p.tunnel.set_persistent_keepalive(keepalive);
         }
+        if let Some(preshared_key) = &config.preshared_key {
+            // An all-zero key removes it
+            let preshared_key = Some(preshared_key.clone()).filter(|k| !k.is_zero());
+            p.preshared_key = preshared_key.clone();
+            p.tunnel.set_preshared_key(preshared_key);
+        }
+        if let Some(obfuscation) = &config.obfuscation {
+            let obfuscator = Arc::new(Obfuscator::new(obfuscation.clone()));
+            self.forget_obfuscator(&config.pub_key);
+            self.add_obfuscator(config.pub_key, &obfuscator);
+            p.obfuscator = Some(obfuscator);
+        }
+        if config.replace_ips {
+            p.allowed_ips = IpNetworkTable::new();
+            self.peers_by_ip
+                .write()
+                .await
+                .retain(|_, v| !Arc::ptr_eq(peer, v));
+        }
+        for network in config.allowed_ips.iter().filter_map(AllowedIP::network) {
+            p.allowed_ips.insert(network, ());
+        }
+    }
+
+    pub async fn peer_stats(&self, pub_key: &x25519::PublicKey) -> Option<PeerStats> {
+        let peer = self.peers.get(pub_key).map(|e| e.value().clone())?;
+        let stats = peer.lock().await.tunnel.stats();
+        Some(stats)
+    }
+
+    pub async fn all_stats(&self) -> Vec<(x25519::PublicKey, PeerStats)> {
+        let peers: Vec<_> = self
+            .peers
+            .iter()
+            .map(|e| (*e.key(), e.value().clone()))
+            .collect();
+        let mut stats = Vec::with_capacity(peers.len());
+        for (pub_key, peer) in peers {
+            stats.push((pub_key, peer.lock().await.tunnel.stats()));
+        }
+        stats
+    }
+
+    /// Replace the packet filter, the rule counters start over
+    pub async fn set_filter(&self, filter: Filter) {
+        *self.filter.write().await = filter;
+    }
+
+    /// Reload only the filter rules from a config file
+    pub async fn reload_filter<P: AsRef<std::path::Path>>(&self, path: P) -> WgResult<()> {
+        let config = DeviceConfig::from_file(path).await?;
+        self.set_filter(config.filter).await;
+        Ok(())
+    }
+
+    /// Apply a parsed config file on top of the current state
+    pub async fn apply_config(self: &Arc<Self>, config: DeviceConfig) -> WgResult<()> {
+        if let Some(private_key) = &config.private_key {
+            self.set_key(x25519::StaticSecret::from(*private_key.expose()))
+                .await;
+        }
+        if let Some(port) = config.listen_port {
+            self.open_listen_port(port).await?;
+        }
+        for peer in config.peers {
+            self.update_peer(peer).await?;
+        }
+        self.set_filter(config.filter).await;
+        Ok(())
     }
 
     pub async fn remove_peer(&self, pub_key: &x25519::PublicKey) {
         if let Some((_, peer)) = self.peers.remove(pub_key) {
+            self.forget_obfuscator(pub_key);
+            self.relay.write().await.remove_peer(pub_key);
             // Found a peer to remove, now purge all references to it:
             self.peers_by_ip
                 .write()
@@ -458,10 +1053,13 @@ impl Device {
                 .retain(|_, v| !Arc::ptr_eq(&peer, v));
 
             {
-                let p = peer.lock().await;
+                let mut p = peer.lock().await;
                 self.peers_by_idx.remove(&p.index);
-                p.close(); // close open udp socket and free the closure
+                p.close();
             }
+            self.emit(DeviceEvent::PeerRemoved {
+                public_key: *pub_key,
+            });
 
             // tracing::info!("Peer removed");
         }
@@ -501,20 +1099,28 @@ impl Device {
         self.rate_limiter.write().await.replace(rate_limiter);
 
         // Remove all the bad peers
-        for _ in bad_peers {
-            unimplemented!();
+        for peer in bad_peers {
+            let pub_key = peer.lock().await.pub_key;
+            tracing::warn!(message = "Removing peer incompatible with the new key", peer = %metrics::encode_key(&pub_key));
+            self.remove_peer(&pub_key).await;
         }
     }
 
     async fn clear_peers(&self) {
+        let pub_keys: Vec<_> = self.peers.iter().map(|entry| *entry.key()).collect();
         self.peers.clear();
         self.peers_by_idx.clear();
+        self.obfuscators.clear();
         self.peers_by_ip.write().await.retain(|_, _| false);
+        self.relay.write().await.clear();
+        for public_key in pub_keys {
+            self.emit(DeviceEvent::PeerRemoved { public_key });
+        }
     }
     async fn rate_limiter(&self) -> Option<Arc<RateLimiter>> {
         self.rate_limiter.read().await.clone()
     }
-    fn set_fwmark(&self, fwmark: u32) -> WgResult<()> {
+    fn set_fwmark(&self, _fwmark: u32) -> WgResult<()> {
         // TODO
         Ok(())
     }
@@ -553,6 +1159,7 @@ impl IndexLfsr {
     }
 
     /// Generate the next value in the pseudorandom sequence
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> u32 {
         // 24-bit polynomial for randomness. This is arbitrarily chosen to
         // inject bitflips into the value.
@@ -574,3 +1181,230 @@ impl Default for IndexLfsr {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{
+        device::{
+            capture::CaptureConfig,
+            testing::{device, ipv4_packet, peer, wait_for_stats},
+            transport::loopback::LoopbackNetwork,
+        },
+        tun::stream::TunStream,
+        uapi::Client,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn update_peer_in_place() {
+        let network = LoopbackNetwork::new();
+        let addr_a: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let addr_b: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let (a, _) = device("wgloop17", &network, addr_a).await;
+        let key_b =
+            x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(rand_core::OsRng));
+        a.update_peer(peer(key_b, addr_b, "10.0.0.2/32"))
+            .await
+            .unwrap();
+        let index = a.peers.get(&key_b).unwrap().lock().await.index;
+
+        let mut update = peer(key_b, "192.0.2.3:51820".parse().unwrap(), "10.0.1.0/24");
+        update.replace_ips = true;
+        update.keepalive(25);
+        a.update_peer(update).await.unwrap();
+        {
+            let peer = a.peers.get(&key_b).unwrap().clone();
+            let peer = peer.lock().await;
+            assert_eq!(peer.index, index);
+            assert_eq!(peer.addr, Some("192.0.2.3:51820".parse().unwrap()));
+            assert_eq!(peer.tunnel.persistent_keepalive(), Some(25));
+            assert!(!peer.is_allowed_ip([10, 0, 0, 2]));
+            assert!(peer.is_allowed_ip([10, 0, 1, 7]));
+        }
+        let routes = a.peers_by_ip.read().await;
+        assert!(routes.longest_match(Ipv4Addr::new(10, 0, 0, 2)).is_none());
+        assert!(routes.longest_match(Ipv4Addr::new(10, 0, 1, 7)).is_some());
+        drop(routes);
+
+        let mut invalid = PeerConfig::new(key_b);
+        invalid.allowed_ips.push(AllowedIP {
+            addr: [10, 0, 0, 0].into(),
+            cidr: 33,
+        });
+        assert_eq!(
+            a.update_peer(invalid).await.unwrap_err().errno(),
+            libc::EINVAL
+        );
+
+        let mut remove = PeerConfig::new(key_b);
+        remove.remove(true);
+        a.update_peer(remove).await.unwrap();
+        assert!(a.peers.is_empty());
+        assert!(a
+            .peers_by_ip
+            .read()
+            .await
+            .longest_match(Ipv4Addr::new(10, 0, 1, 7))
+            .is_none());
+
+        a.close();
+    }
+
+    #[tokio::test]
+    async fn shutdown_joins_tasks() {
+        let network = LoopbackNetwork::new();
+        let addr_a: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let (a, _) = device("wgloop18", &network, addr_a).await;
+        // Nobody listens for this peer, the TCP transport keeps re-dialing it
+        let key_b =
+            x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(rand_core::OsRng));
+        let mut peer_b = PeerConfig::new(key_b);
+        peer_b.endpoint("tcp://127.0.0.1:9".parse().unwrap());
+        peer_b.keepalive(1);
+        a.update_peer(peer_b).await.unwrap();
+        // The keepalive starts a handshake, which dials the peer
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while a.metrics.network_tx_packets.load(Ordering::Relaxed) == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        a.start_capture(CaptureConfig::default(), tokio::io::sink())
+            .await;
+        let socket = "/var/run/wireguard/wgloop18.sock";
+        let mut subscriber = tokio::net::UnixStream::connect(socket).await.unwrap();
+        subscriber.write_all(b"subscribe=1\n\n").await.unwrap();
+        while a.events.receiver_count() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let runtime = tokio::runtime::Handle::current().metrics();
+        assert!(runtime.num_alive_tasks() > 0);
+        a.shutdown().await.unwrap();
+        assert_eq!(runtime.num_alive_tasks(), 0);
+        assert_eq!(Arc::strong_count(&a), 1);
+        assert!(a.peers.is_empty());
+        assert!(a.key_pair.read().await.is_none());
+        assert!(!std::path::Path::new(socket).exists());
+        assert!(a.send_to(&[1], &addr_a.into()).await.is_err());
+
+        let mut rest = String::new();
+        subscriber.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "errno=0\n\n");
+    }
+
+    #[tokio::test]
+    async fn inherited_interface_and_sockets() {
+        let dir = std::env::temp_dir().join(format!("wg-rs-inherited-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wgloop24.sock");
+        let options = DeviceOptions {
+            tun: Some(TunStream::new("wgloop24").unwrap()),
+            api_listener: Some(std::os::unix::net::UnixListener::bind(&path).unwrap()),
+            ..Default::default()
+        };
+        let device = Device::with_options("wgloop24".to_owned(), options)
+            .await
+            .unwrap();
+        // A dual-stack socket, as systemd binds `ListenDatagram=51820`
+        let udp = std::net::UdpSocket::bind("[::]:0").unwrap();
+        let port = udp.local_addr().unwrap().port();
+        device.use_listen_sockets(vec![udp], vec![]).await.unwrap();
+
+        let state = Client::with_path("wgloop24", &path).get().await.unwrap();
+        assert_eq!(state.listen_port, port);
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&[1; 32], ("127.0.0.1", port)).await.unwrap();
+        let received = async {
+            while device.metrics.network_rx_packets.load(Ordering::Relaxed) == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), received)
+            .await
+            .unwrap();
+
+        device.shutdown().await.unwrap();
+        // Bound by someone else, who removes it
+        assert!(path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tcp_listening_is_opt_in() {
+        let device = Device::new("wgloop37".to_owned()).await.unwrap();
+        let port = device.listen_port.load(Ordering::Relaxed);
+        let connect = || tokio::net::TcpStream::connect(("127.0.0.1", port));
+        assert!(connect().await.is_err());
+
+        let mut lines = ["listen_tcp=true".to_owned()].into_iter();
+        assert_eq!(device.api_set(&mut lines).await, 0);
+        assert_eq!(device.listen_port.load(Ordering::Relaxed), port);
+        assert!(connect().await.is_ok());
+        let mut lines = ["listen_tcp=false".to_owned()].into_iter();
+        assert_eq!(device.api_set(&mut lines).await, 0);
+        // The listeners close with their accept tasks
+        let closed = tokio::time::timeout(Duration::from_secs(5), async {
+            while connect().await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        closed.await.unwrap();
+
+        device.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn same_listen_port_keeps_tcp_sessions() {
+        let options = DeviceOptions {
+            listen_tcp: true,
+            ..Default::default()
+        };
+        let a = Device::with_options("wgloop42".to_owned(), options)
+            .await
+            .unwrap();
+        let b = Device::new("wgloop43".to_owned()).await.unwrap();
+        let (private_a, private_b) = ([1; 32].into(), [2; 32].into());
+        let key_a = x25519::PublicKey::from(&private_a);
+        let key_b = x25519::PublicKey::from(&private_b);
+        a.set_key(private_a).await;
+        b.set_key(private_b).await;
+        let port = a.listen_port.load(Ordering::Relaxed);
+
+        let mut peer_a = PeerConfig::new(key_a);
+        peer_a.endpoint(Endpoint::Tcp(([127, 0, 0, 1], port).into()));
+        peer_a.allowed_ips.push("10.0.0.1/32".parse().unwrap());
+        b.update_peer(peer_a).await.unwrap();
+        let mut peer_b = PeerConfig::new(key_b);
+        peer_b.allowed_ips.push("10.0.0.2/32".parse().unwrap());
+        a.update_peer(peer_b).await.unwrap();
+
+        // From outside B's allowed IPs, so A counts it without writing it to its interface
+        let send = || b.handle_iface_packet(ipv4_packet([10, 0, 0, 9], [10, 0, 0, 1]));
+        send().await.unwrap();
+        assert!(wait_for_stats(&a, &key_b, |stats| stats.rx_packets > 0).await);
+        let endpoint = |a: &Arc<Device>| {
+            let peer = a.peers.get(&key_b).unwrap().value().clone();
+            async move { peer.lock().await.addr }
+        };
+        let connected = endpoint(&a).await;
+        assert!(connected.is_some_and(|e| e.is_tcp()));
+
+        let mut lines = [format!("listen_port={port}")].into_iter();
+        assert_eq!(a.api_set(&mut lines).await, 0);
+        let received = a.peer_stats(&key_b).await.unwrap().rx_packets;
+        send().await.unwrap();
+        assert!(wait_for_stats(&a, &key_b, |stats| stats.rx_packets > received).await);
+        // A reconnect would come from another port
+        assert_eq!(endpoint(&a).await, connected);
+
+        a.shutdown().await.unwrap();
+        b.shutdown().await.unwrap();
+    }
+}
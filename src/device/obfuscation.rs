@@ -0,0 +1,300 @@
+//! Optional per-peer wrapper that hides the WireGuard wire format from DPI.
+//!
+//! Every message becomes
+//! `nonce[8] || tag[4] || masked(pad_len[2] || header[16]) || rest || padding`, where the mask
+//! and the tag are derived from a key shared by both sides and the nonce. The tag tells the
+//! receiver which key a message belongs to with one MAC per key, and changes with every nonce so
+//! it is not something to match on. Handshake and cookie messages get random padding so their
+//! sizes are no longer fixed, and a few random junk datagrams are sent ahead of every handshake
+//! initiation.
+
+use rand_core::{OsRng, RngCore};
+
+use crate::{
+    error::{WgError, WgResult},
+    noise::handshake::b2s_hmac,
+    secret::Secret,
+};
+
+const NONCE_LEN: usize = 8;
+const TAG_LEN: usize = 4;
+const PAD_LEN_LEN: usize = 2;
+/// How much of the WireGuard message is masked, covers the type and the sender/receiver indices
+const MASKED_HEADER_LEN: usize = 16;
+const MASKED_START: usize = NONCE_LEN + TAG_LEN;
+const OVERHEAD: usize = MASKED_START + PAD_LEN_LEN;
+
+const HANDSHAKE_INIT: u8 = 1;
+const DATA: u8 = 4;
+/// The largest message that gets padded
+const HANDSHAKE_INIT_SZ: usize = 148;
+/// The largest UDP payload over IPv4
+const MAX_DATAGRAM: usize = 65507;
+
+/// Parameters that must be identical on both ends of the tunnel
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ObfuscationConfig {
+    pub key: Secret,
+    /// Upper bound of the random padding appended to handshake and cookie messages
+    pub max_padding: u16,
+    /// Number of junk datagrams sent before every handshake initiation
+    pub junk_packets: u8,
+    /// Upper bound of the junk datagram size
+    pub junk_max_size: u16,
+}
+
+impl ObfuscationConfig {
+    pub fn new(key: [u8; 32]) -> Self {
+        ObfuscationConfig {
+            key: Secret::new(key),
+            max_padding: 0,
+            junk_packets: 0,
+            junk_max_size: 0,
+        }
+    }
+
+    /// Reject padding and junk sizes whose datagrams could not be sent
+    pub fn validate(&self) -> WgResult<()> {
+        if HANDSHAKE_INIT_SZ + OVERHEAD + self.max_padding as usize > MAX_DATAGRAM {
+            return Err(WgError::InvalidValue(format!(
+                "obfuscation max padding {}",
+                self.max_padding
+            )));
+        }
+        if self.junk_max_size as usize > MAX_DATAGRAM {
+            return Err(WgError::InvalidValue(format!(
+                "obfuscation junk max size {}",
+                self.junk_max_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+pub struct Obfuscator {
+    config: ObfuscationConfig,
+    key_id: u32,
+}
+
+impl Obfuscator {
+    pub fn new(config: ObfuscationConfig) -> Self {
+        let id = b2s_hmac(config.key.expose(), b"key id");
+        let key_id = u32::from_be_bytes([id[0], id[1], id[2], id[3]]);
+        Self { config, key_id }
+    }
+
+    pub fn config(&self) -> &ObfuscationConfig {
+        &self.config
+    }
+
+    /// Tells keys apart without keeping copies of them, never sent
+    pub fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    /// The mask for the message with `nonce`, its last bytes are the tag
+    fn mask(&self, nonce: &[u8]) -> [u8; 32] {
+        b2s_hmac(self.config.key.expose(), nonce)
+    }
+
+    /// Wrap a WireGuard message produced by `Tunn`
+    pub fn obfuscate(&self, packet: &[u8]) -> Vec<u8> {
+        let pad_len = match packet.first() {
+            Some(&t) if t != DATA && self.config.max_padding > 0 => {
+                (OsRng.next_u32() % (self.config.max_padding as u32 + 1)) as u16
+            }
+            _ => 0,
+        };
+        let mut out = vec![0u8; OVERHEAD + packet.len() + pad_len as usize];
+        OsRng.fill_bytes(&mut out[..NONCE_LEN]);
+        let mask = self.mask(&out[..NONCE_LEN]);
+        out[NONCE_LEN..MASKED_START].copy_from_slice(&mask[mask.len() - TAG_LEN..]);
+        out[MASKED_START..OVERHEAD].copy_from_slice(&pad_len.to_be_bytes());
+        out[OVERHEAD..OVERHEAD + packet.len()].copy_from_slice(packet);
+        OsRng.fill_bytes(&mut out[OVERHEAD + packet.len()..]);
+
+        let masked_len = PAD_LEN_LEN + packet.len().min(MASKED_HEADER_LEN);
+        for (b, m) in out[MASKED_START..MASKED_START + masked_len]
+            .iter_mut()
+            .zip(mask)
+        {
+            *b ^= m;
+        }
+        out
+    }
+
+    /// Recover the WireGuard message, `None` if `packet` was not produced with this key
+    pub fn deobfuscate(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        if packet.len() < OVERHEAD + MASKED_HEADER_LEN {
+            return None;
+        }
+        // Most messages of other keys stop at the tag, before anything is copied
+        let mask = self.mask(&packet[..NONCE_LEN]);
+        if packet[NONCE_LEN..MASKED_START] != mask[mask.len() - TAG_LEN..] {
+            return None;
+        }
+        let mut out = packet[MASKED_START..].to_vec();
+        for (b, m) in out[..PAD_LEN_LEN + MASKED_HEADER_LEN].iter_mut().zip(mask) {
+            *b ^= m;
+        }
+        let pad_len = u16::from_be_bytes([out[0], out[1]]) as usize;
+        let message = &out[PAD_LEN_LEN..];
+        // A wrong key leaves garbage where the message type and its reserved zeros should be
+        if !(HANDSHAKE_INIT..=DATA).contains(&message[0]) || message[1..4] != [0, 0, 0] {
+            return None;
+        }
+        let len = message.len().checked_sub(pad_len)?;
+        if len < MASKED_HEADER_LEN {
+            return None;
+        }
+        out.truncate(PAD_LEN_LEN + len);
+        out.drain(..PAD_LEN_LEN);
+        Some(out)
+    }
+
+    /// Random datagrams to send ahead of `packet`, only handshake initiations get any
+    pub fn junk(&self, packet: &[u8]) -> Vec<Vec<u8>> {
+        if packet.first() != Some(&HANDSHAKE_INIT) {
+            return Vec::new();
+        }
+        (0..self.config.junk_packets)
+            .map(|_| {
+                let len = 1 + OsRng.next_u32() as usize % self.config.junk_max_size.max(1) as usize;
+                let mut junk = vec![0u8; len];
+                OsRng.fill_bytes(&mut junk);
+                junk
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::device::{
+        metrics::DropReason,
+        testing::{device, handshake, ipv4_packet, peer, wait_for_stats},
+        transport::loopback::LoopbackNetwork,
+    };
+
+    use super::*;
+
+    fn message(message_type: u8, len: usize) -> Vec<u8> {
+        let mut packet = vec![0u8; len];
+        OsRng.fill_bytes(&mut packet);
+        packet[..4].copy_from_slice(&[message_type, 0, 0, 0]);
+        packet
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut config = ObfuscationConfig::new([7; 32]);
+        config.max_padding = 64;
+        let obfuscator = Obfuscator::new(config);
+        for (message_type, len) in [(1, 148), (2, 92), (3, 64), (4, 32), (4, 1420)] {
+            let packet = message(message_type, len);
+            let wrapped = obfuscator.obfuscate(&packet);
+            assert_ne!(&wrapped[OVERHEAD..OVERHEAD + 4], &packet[..4]);
+            assert_eq!(obfuscator.deobfuscate(&wrapped), Some(packet));
+        }
+    }
+
+    #[test]
+    fn handshake_size_varies() {
+        let mut config = ObfuscationConfig::new([7; 32]);
+        config.max_padding = 255;
+        let obfuscator = Obfuscator::new(config);
+        let packet = message(1, 148);
+        let sizes: std::collections::HashSet<_> = (0..32)
+            .map(|_| obfuscator.obfuscate(&packet).len())
+            .collect();
+        assert!(sizes.len() > 1);
+    }
+
+    #[test]
+    fn wrong_key() {
+        let obfuscator = Obfuscator::new(ObfuscationConfig::new([7; 32]));
+        let other = Obfuscator::new(ObfuscationConfig::new([8; 32]));
+        let wrapped = obfuscator.obfuscate(&message(1, 148));
+        assert_eq!(other.deobfuscate(&wrapped), None);
+        assert_eq!(obfuscator.deobfuscate(&message(1, 148)[..10]), None);
+    }
+
+    #[test]
+    fn tag_is_not_a_fingerprint() {
+        let obfuscator = Obfuscator::new(ObfuscationConfig::new([7; 32]));
+        let other = Obfuscator::new(ObfuscationConfig::new([8; 32]));
+        assert_ne!(obfuscator.key_id(), other.key_id());
+        let word = |w: &[u8], at: usize| u32::from_be_bytes(w[at..at + 4].try_into().unwrap());
+        let wrapped: Vec<_> = (0..8)
+            .map(|_| obfuscator.obfuscate(&message(4, 32)))
+            .collect();
+        // Neither the tag nor anything combining it with the nonce stays the same
+        let combinations: [fn(u32, u32) -> u32; 3] = [|_, t| t, |n, t| n ^ t, u32::wrapping_sub];
+        for combine in combinations {
+            let values: std::collections::HashSet<u32> = wrapped
+                .iter()
+                .map(|w| combine(word(w, 0), word(w, NONCE_LEN)))
+                .collect();
+            assert!(values.len() > 1);
+        }
+        for wrapped in &wrapped {
+            assert!(other.deobfuscate(wrapped).is_none());
+            assert!(obfuscator.deobfuscate(wrapped).is_some());
+        }
+    }
+
+    #[test]
+    fn junk_before_handshake_only() {
+        let mut config = ObfuscationConfig::new([7; 32]);
+        config.junk_packets = 3;
+        config.junk_max_size = 100;
+        let obfuscator = Obfuscator::new(config);
+        let junk = obfuscator.junk(&message(1, 148));
+        assert_eq!(junk.len(), 3);
+        assert!(junk.iter().all(|j| (1..=100).contains(&j.len())));
+        assert!(obfuscator.junk(&message(4, 32)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn obfuscated_handshake() {
+        let mut obfuscation = ObfuscationConfig::new([42; 32]);
+        obfuscation.max_padding = 200;
+        obfuscation.junk_packets = 4;
+        obfuscation.junk_max_size = 300;
+        handshake(["wgloop2", "wgloop3"], Some(obfuscation)).await;
+    }
+
+    #[tokio::test]
+    async fn unobfuscated_messages_rejected() {
+        let network = LoopbackNetwork::new();
+        let addr_a: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let addr_b: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let (a, key_a) = device("wgloop40", &network, addr_a).await;
+        let (b, key_b) = device("wgloop41", &network, addr_b).await;
+
+        // B expects A to obfuscate, A does not
+        a.update_peer(peer(key_b, addr_b, "10.0.0.2/32"))
+            .await
+            .unwrap();
+        let mut peer_a = peer(key_a, addr_a, "10.0.0.1/32");
+        peer_a.obfuscation = Some(ObfuscationConfig::new([42; 32]));
+        b.update_peer(peer_a).await.unwrap();
+
+        a.handle_iface_packet(ipv4_packet([10, 0, 0, 1], [10, 0, 0, 2]))
+            .await
+            .unwrap();
+        let dropped = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while b.metrics.dropped(DropReason::ObfuscationMismatch) == 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        assert!(dropped.is_ok(), "plain handshake was not rejected");
+        assert!(!wait_for_stats(&b, &key_a, |stats| stats.last_handshake.is_some()).await);
+
+        a.close();
+        b.close();
+    }
+}
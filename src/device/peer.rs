@@ -1,11 +1,24 @@
 use crate::noise::TunnResult;
 use ip_network::IpNetwork;
 use ip_network_table::IpNetworkTable;
-use std::net::{IpAddr, SocketAddr};
+use std::net::IpAddr;
 
-use crate::x25519;
+use crate::{
+    error::{WgError, WgResult},
+    key_bytes::KeyBytes,
+    secret::Secret,
+    x25519,
+};
 
-use super::allowed_ip::AllowedIP;
+use std::sync::Arc;
+
+use super::{
+    allowed_ip::AllowedIP,
+    endpoint::Endpoint,
+    eyeballs::Race,
+    obfuscation::{ObfuscationConfig, Obfuscator},
+    resolve::{HostEndpoint, Resolution},
+};
 
 #[derive(Clone, Debug)]
 pub struct PeerConfig {
@@ -20,9 +33,14 @@ pub struct PeerConfig {
     pub pub_key: x25519::PublicKey,
     pub remove: bool,
     pub replace_ips: bool,
-    pub endpoint: Option<SocketAddr>,
+    pub endpoint: Option<Endpoint>,
+    /// Every endpoint to race handshakes over when there are several, `endpoint` is the first
+    pub candidates: Vec<Endpoint>,
+    /// An endpoint the device resolves, instead of `endpoint`
+    pub hostname: Option<HostEndpoint>,
     pub keepalive: Option<u16>,
-    pub preshared_key: Option<[u8; 32]>,
+    pub preshared_key: Option<Secret>,
+    pub obfuscation: Option<ObfuscationConfig>,
 }
 
 impl PeerConfig {
@@ -33,8 +51,11 @@ impl PeerConfig {
             remove: false,
             replace_ips: false,
             endpoint: None,
+            candidates: Vec::new(),
+            hostname: None,
             keepalive: None,
             preshared_key: None,
+            obfuscation: None,
         }
     }
     pub fn remove(&mut self, remove: bool) {
@@ -43,41 +64,178 @@ impl PeerConfig {
     pub fn replace_ips(&mut self, replace_ips: bool) {
         self.replace_ips = replace_ips
     }
-    pub fn endpoint(&mut self, endpoint: SocketAddr) {
-        self.endpoint = Some(endpoint)
+    pub fn endpoint(&mut self, endpoint: Endpoint) {
+        self.endpoint = Some(endpoint);
+        self.candidates.clear();
+        self.hostname = None;
+    }
+    /// Endpoints raced on each handshake, the first one is used until another answers
+    pub fn candidates(&mut self, candidates: Vec<Endpoint>) {
+        let mut unique = Vec::with_capacity(candidates.len());
+        for endpoint in candidates {
+            if !unique.contains(&endpoint) {
+                unique.push(endpoint);
+            }
+        }
+        self.endpoint = unique.first().copied();
+        self.candidates = if unique.len() > 1 { unique } else { Vec::new() };
+        self.hostname = None;
+    }
+    pub fn hostname(&mut self, hostname: HostEndpoint) {
+        self.hostname = Some(hostname);
+        self.endpoint = None;
+        self.candidates.clear();
+    }
+    /// An `ip:port` endpoint, a `host:port` one the device resolves, or a comma separated list
+    /// of `ip:port` candidates
+    pub fn parse_endpoint(&mut self, s: &str) -> Result<(), String> {
+        if s.contains(',') {
+            let candidates = s
+                .split(',')
+                .map(|endpoint| endpoint.trim().parse())
+                .collect::<Result<_, _>>()?;
+            self.candidates(candidates);
+            return Ok(());
+        }
+        match s.parse::<Endpoint>() {
+            Ok(endpoint) => self.endpoint(endpoint),
+            Err(_) => self.hostname(s.parse()?),
+        }
+        Ok(())
     }
     pub fn keepalive(&mut self, keepalive: u16) {
         self.keepalive = Some(keepalive)
     }
     pub fn preshared_key(&mut self, preshared_key: [u8; 32]) {
-        self.preshared_key = Some(preshared_key)
+        self.preshared_key = Some(Secret::new(preshared_key))
+    }
+    pub fn obfuscation(&mut self, obfuscation: ObfuscationConfig) {
+        self.obfuscation = Some(obfuscation)
+    }
+
+    /// Reject what [`Device::update_peer`](super::Device::update_peer) cannot apply: invalid
+    /// allowed IPs, an endpoint nothing can be sent to or obfuscation that does not fit in a
+    /// datagram
+    pub fn validate(&self) -> WgResult<Vec<IpNetwork>> {
+        for endpoint in self.endpoint.iter().chain(&self.candidates) {
+            let addr = endpoint.addr();
+            if addr.port() == 0 || addr.ip().is_unspecified() {
+                return Err(WgError::InvalidValue(format!("endpoint {endpoint}")));
+            }
+        }
+        if let Some(hostname) = self.hostname.as_ref().filter(|h| h.port == 0) {
+            return Err(WgError::InvalidValue(format!("endpoint {hostname}")));
+        }
+        if let Some(obfuscation) = &self.obfuscation {
+            obfuscation.validate()?;
+        }
+        self.allowed_ips
+            .iter()
+            .map(|ip| {
+                ip.network().ok_or_else(|| {
+                    WgError::InvalidValue(format!("allowed ip {}/{}", ip.addr, ip.cidr))
+                })
+            })
+            .collect()
+    }
+
+    /// The peer section of a `set=1` request applying this config
+    pub fn to_uapi(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "public_key={}",
+            KeyBytes(self.pub_key.to_bytes()).to_hex()
+        )];
+        if self.remove {
+            lines.push("remove=true".to_owned());
+            return lines;
+        }
+        if let Some(preshared_key) = &self.preshared_key {
+            lines.push(format!(
+                "preshared_key={}",
+                KeyBytes(*preshared_key.expose()).to_hex()
+            ));
+        }
+        if !self.candidates.is_empty() {
+            let candidates: Vec<_> = self.candidates.iter().map(Endpoint::to_string).collect();
+            lines.push(format!("endpoint={}", candidates.join(",")));
+        } else if let Some(endpoint) = &self.endpoint {
+            lines.push(format!("endpoint={endpoint}"));
+        }
+        if let Some(hostname) = &self.hostname {
+            lines.push(format!("endpoint={hostname}"));
+        }
+        if let Some(keepalive) = self.keepalive {
+            lines.push(format!("persistent_keepalive_interval={keepalive}"));
+        }
+        if self.replace_ips {
+            lines.push("replace_allowed_ips=true".to_owned());
+        }
+        for ip in &self.allowed_ips {
+            lines.push(format!("allowed_ip={ip}"));
+        }
+        if let Some(obfuscation) = &self.obfuscation {
+            lines.push(format!(
+                "obfuscation_key={}",
+                KeyBytes(*obfuscation.key.expose()).to_hex()
+            ));
+            lines.push(format!(
+                "obfuscation_max_padding={}",
+                obfuscation.max_padding
+            ));
+            lines.push(format!(
+                "obfuscation_junk_packets={}",
+                obfuscation.junk_packets
+            ));
+            lines.push(format!(
+                "obfuscation_junk_max_size={}",
+                obfuscation.junk_max_size
+            ));
+        }
+        lines
     }
 }
 
 pub struct Peer {
+    pub pub_key: x25519::PublicKey,
     /// The associated tunnel struct
     pub(crate) tunnel: crate::noise::Tunn,
     /// The index the tunnel uses
     pub index: u32,
-    pub addr: Option<SocketAddr>,
+    pub addr: Option<Endpoint>,
+    /// The endpoints handshakes are raced over, `addr` is the one that answered last
+    pub candidates: Vec<Endpoint>,
+    pub(crate) race: Option<Race>,
+    /// Resolved into `addr` by the device
+    pub hostname: Option<HostEndpoint>,
+    pub(crate) resolution: Option<Resolution>,
     pub allowed_ips: IpNetworkTable<()>,
-    pub preshared_key: Option<[u8; 32]>,
+    pub preshared_key: Option<Secret>,
+    pub obfuscator: Option<Arc<Obfuscator>>,
+    /// Whether the session expiry was reported since the last handshake
+    pub(crate) expired: bool,
 }
 impl Peer {
     pub fn new(config: &PeerConfig, tunnel: crate::noise::Tunn, index: u32) -> Self {
         let mut allowed_ips = IpNetworkTable::new();
-        for AllowedIP { addr, cidr } in config.allowed_ips.iter() {
-            allowed_ips.insert(
-                IpNetwork::new_truncate(*addr, *cidr).expect("cidr is valid length"),
-                (),
-            );
+        for network in config.allowed_ips.iter().filter_map(AllowedIP::network) {
+            allowed_ips.insert(network, ());
         }
         Self {
+            pub_key: config.pub_key,
             tunnel,
             index,
             addr: config.endpoint,
+            candidates: config.candidates.clone(),
+            race: None,
+            hostname: config.hostname.clone(),
+            resolution: None,
             allowed_ips,
-            preshared_key: config.preshared_key,
+            preshared_key: config.preshared_key.clone().filter(|k| !k.is_zero()),
+            obfuscator: config
+                .obfuscation
+                .clone()
+                .map(|config| Arc::new(Obfuscator::new(config))),
+            expired: false,
         }
     }
 
@@ -94,7 +252,10 @@ impl Peer {
     //     self.out_stream.send(packet).await
     // }
 
-    pub fn close(&self) {
-        // TODO
+    /// Drop the sessions and queued packets, nothing is sent to the peer afterwards
+    pub fn close(&mut self) {
+        self.tunnel.clear_all();
+        self.addr = None;
+        self.race = None;
     }
 }
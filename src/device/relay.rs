@@ -0,0 +1,182 @@
+use std::{collections::HashSet, str::FromStr};
+
+use crate::x25519;
+
+/// Whether packets decrypted from one peer may be re-encrypted straight to another
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum RelayMode {
+    /// Everything goes to the TUN, forwarding is left to the kernel
+    #[default]
+    Off,
+    /// Relay between any two peers
+    All,
+    /// Relay only between the pairs allowed in the [`Relay`] ACL
+    Acl,
+}
+
+impl FromStr for RelayMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(RelayMode::Off),
+            "all" => Ok(RelayMode::All),
+            "acl" => Ok(RelayMode::Acl),
+            _ => Err(format!("Unsupported relay mode {s}")),
+        }
+    }
+}
+
+/// In-process forwarding between peers, packets relayed this way never touch the TUN
+#[derive(Clone, Debug, Default)]
+pub struct Relay {
+    pub mode: RelayMode,
+    /// Allowed `(from, to)` pairs, one direction each
+    allowed: HashSet<(x25519::PublicKey, x25519::PublicKey)>,
+}
+
+impl Relay {
+    pub fn new(mode: RelayMode) -> Self {
+        Relay {
+            mode,
+            allowed: HashSet::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.mode != RelayMode::Off
+    }
+
+    pub fn allow(&mut self, from: &x25519::PublicKey, to: &x25519::PublicKey) {
+        self.allowed.insert((*from, *to));
+    }
+
+    pub fn deny(&mut self, from: &x25519::PublicKey, to: &x25519::PublicKey) {
+        self.allowed.remove(&(*from, *to));
+    }
+
+    pub fn allows(&self, from: &x25519::PublicKey, to: &x25519::PublicKey) -> bool {
+        match self.mode {
+            RelayMode::Off => false,
+            RelayMode::All => true,
+            RelayMode::Acl => self.allowed.contains(&(*from, *to)),
+        }
+    }
+
+    /// Forget every pair `peer` is part of
+    pub fn remove_peer(&mut self, peer: &x25519::PublicKey) {
+        self.allowed.retain(|(from, to)| from != peer && to != peer);
+    }
+
+    /// Forget every pair `from` may relay to
+    pub fn remove_from(&mut self, from: &x25519::PublicKey) {
+        self.allowed
+            .retain(|(allowed_from, _)| allowed_from != from);
+    }
+
+    /// Forget every pair, the mode is kept
+    pub fn clear(&mut self) {
+        self.allowed.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{atomic::Ordering, Arc},
+    };
+
+    use crate::device::{
+        metrics::DropReason,
+        testing::{device, ipv4_packet, peer, wait_for_stats},
+        transport::loopback::LoopbackNetwork,
+        Device,
+    };
+
+    use super::*;
+
+    #[test]
+    fn acl() {
+        let a = x25519::PublicKey::from([1; 32]);
+        let b = x25519::PublicKey::from([2; 32]);
+        let mut relay = Relay::new(RelayMode::Acl);
+        relay.allow(&a, &b);
+        assert!(relay.allows(&a, &b));
+        assert!(!relay.allows(&b, &a));
+
+        relay.mode = RelayMode::Off;
+        assert!(!relay.allows(&a, &b));
+        relay.mode = RelayMode::All;
+        assert!(relay.allows(&b, &a));
+
+        relay.mode = RelayMode::Acl;
+        relay.remove_peer(&b);
+        assert!(!relay.allows(&a, &b));
+
+        relay.allow(&a, &b);
+        relay.clear();
+        assert!(!relay.allows(&a, &b));
+        assert_eq!(relay.mode, RelayMode::Acl);
+    }
+
+    /// Spoke A sends to spoke B through the hub, returns whether the hub relayed it, and the hub
+    async fn relay(names: [&str; 3], mode: RelayMode, allow: bool) -> (bool, Arc<Device>) {
+        let network = LoopbackNetwork::new();
+        let addr_a: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let addr_b: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let addr_hub: SocketAddr = "192.0.2.254:51820".parse().unwrap();
+        let (a, key_a) = device(names[0], &network, addr_a).await;
+        let (b, key_b) = device(names[1], &network, addr_b).await;
+        let (hub, key_hub) = device(names[2], &network, addr_hub).await;
+
+        a.update_peer(peer(key_hub, addr_hub, "10.0.0.0/24"))
+            .await
+            .unwrap();
+        b.update_peer(peer(key_hub, addr_hub, "10.0.0.0/24"))
+            .await
+            .unwrap();
+        hub.update_peer(peer(key_a, addr_a, "10.0.0.1/32"))
+            .await
+            .unwrap();
+        hub.update_peer(peer(key_b, addr_b, "10.0.0.2/32"))
+            .await
+            .unwrap();
+        hub.relay.write().await.mode = mode;
+        if allow {
+            hub.relay.write().await.allow(&key_a, &key_b);
+        }
+
+        a.handle_iface_packet(ipv4_packet([10, 0, 0, 1], [10, 0, 0, 2]))
+            .await
+            .unwrap();
+        // B's TUN is down, so look at what the hub sent rather than what B delivered
+        let relayed = wait_for_stats(&hub, &key_b, |stats| stats.tx_bytes > 0).await;
+
+        a.close();
+        b.close();
+        hub.close();
+        (relayed, hub)
+    }
+
+    #[tokio::test]
+    async fn relay_between_peers() {
+        let (relayed, hub) = relay(["wgloop4", "wgloop5", "wgloop6"], RelayMode::All, false).await;
+        assert!(relayed);
+        assert_eq!(hub.metrics.relayed_packets.load(Ordering::Relaxed), 1);
+        let (relayed, _) = relay(["wgloop7", "wgloop8", "wgloop9"], RelayMode::Acl, true).await;
+        assert!(relayed);
+    }
+
+    #[tokio::test]
+    async fn relay_denied_by_acl() {
+        let (relayed, hub) =
+            relay(["wgloop10", "wgloop11", "wgloop12"], RelayMode::Acl, false).await;
+        assert!(!relayed);
+        assert_eq!(hub.metrics.dropped(DropReason::RelayDenied), 1);
+        let metrics = hub.render_metrics().await;
+        assert!(metrics
+            .contains("wg_dropped_packets_total{device=\"wgloop12\",reason=\"relay_denied\"} 1\n"));
+        assert!(metrics.ends_with("# EOF\n"));
+    }
+}
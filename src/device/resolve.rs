@@ -0,0 +1,499 @@
+//! Endpoints given as a hostname, such as `vpn.example.com:51820`, which the device resolves
+//! itself and resolves again to follow dynamic DNS, see [`Device::set_resolver`].
+
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    error::{WgError, WgResult},
+    noise::stats::PeerStats,
+    x25519,
+};
+
+use super::{endpoint::Endpoint, Device};
+
+/// How long a lookup may take
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the device looks for endpoints due for resolution
+const RESOLVE_TICK: Duration = Duration::from_secs(1);
+/// How soon a failed lookup is retried
+const RETRY_INTERVAL: Duration = Duration::from_secs(15);
+/// A session this recent is never moved to another address, like `reresolve-dns.sh` does
+const STALE_HANDSHAKE: Duration = Duration::from_secs(135);
+
+/// A `host:port` endpoint, prefixed with `tcp://` for WireGuard over TCP
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HostEndpoint {
+    pub host: String,
+    pub port: u16,
+    pub tcp: bool,
+}
+
+impl HostEndpoint {
+    /// The endpoint at `addr`, one of the addresses of the host
+    pub fn endpoint(&self, addr: SocketAddr) -> Endpoint {
+        match self.tcp {
+            true => Endpoint::Tcp(addr),
+            false => Endpoint::Udp(addr),
+        }
+    }
+}
+
+impl FromStr for HostEndpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (tcp, host_port) = match s.split_once("://") {
+            Some(("udp", host_port)) => (false, host_port),
+            Some(("tcp", host_port)) => (true, host_port),
+            Some((scheme, _)) => return Err(format!("Unsupported endpoint scheme {scheme}")),
+            None => (false, s),
+        };
+        let invalid = || format!("Invalid endpoint {s}");
+        let (host, port) = host_port.rsplit_once(':').ok_or_else(invalid)?;
+        let valid_host = !host.is_empty()
+            && host.len() <= 253
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+        if !valid_host {
+            return Err(invalid());
+        }
+        Ok(HostEndpoint {
+            host: host.to_owned(),
+            port: port.parse().map_err(|_| invalid())?,
+            tcp,
+        })
+    }
+}
+
+impl fmt::Display for HostEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.tcp {
+            f.write_str("tcp://")?;
+        }
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+/// Looks up the addresses of a host, replaced with [`Device::set_resolver`]
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// The system resolver, `getaddrinfo`
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok(tokio::net::lookup_host((host, port)).await?.collect())
+    }
+}
+
+/// Which address of a host with both IPv4 and IPv6 ones is used
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddressPreference {
+    /// The first one the resolver returns
+    #[default]
+    System,
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressPreference {
+    pub fn pick(self, addrs: &[SocketAddr]) -> Option<SocketAddr> {
+        self.filter(addrs).first().copied()
+    }
+
+    /// The addresses of the preferred family, every one when the host has none of them
+    pub fn filter(self, addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+        let preferred: Vec<_> = addrs
+            .iter()
+            .filter(|addr| match self {
+                AddressPreference::System => true,
+                AddressPreference::Ipv4 => addr.is_ipv4(),
+                AddressPreference::Ipv6 => addr.is_ipv6(),
+            })
+            .copied()
+            .collect();
+        match preferred.is_empty() {
+            true => addrs.to_vec(),
+            false => preferred,
+        }
+    }
+}
+
+impl FromStr for AddressPreference {
+    type Err = WgError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "system" => Ok(AddressPreference::System),
+            "ipv4" => Ok(AddressPreference::Ipv4),
+            "ipv6" => Ok(AddressPreference::Ipv6),
+            _ => Err(WgError::InvalidValue(format!("address preference {s}"))),
+        }
+    }
+}
+
+/// When hostname endpoints are resolved again
+#[derive(Clone, Debug)]
+pub struct ResolveOptions {
+    /// Resolve again this often, never when none
+    pub interval: Option<Duration>,
+    /// Resolve again once this many handshake initiations went unanswered
+    pub failed_handshakes: u64,
+    pub preference: AddressPreference,
+}
+
+impl Default for ResolveOptions {
+    fn default() -> Self {
+        ResolveOptions {
+            interval: Some(Duration::from_secs(300)),
+            failed_handshakes: 3,
+            preference: AddressPreference::System,
+        }
+    }
+}
+
+/// The last lookup of a peer's hostname
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Resolution {
+    at: Instant,
+    /// Handshake initiations sent by then
+    attempts: u64,
+    failed: bool,
+}
+
+impl Resolution {
+    /// Whether the hostname should be looked up again
+    fn due(&self, options: &ResolveOptions, stats: &PeerStats, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.at);
+        if self.failed {
+            return elapsed >= RETRY_INTERVAL;
+        }
+        if options.interval.is_some_and(|interval| elapsed >= interval) {
+            return true;
+        }
+        let handshake_since = stats
+            .time_since_last_handshake
+            .is_some_and(|since| since < elapsed);
+        let unanswered = stats.handshake_attempts.saturating_sub(self.attempts);
+        !handshake_since && unanswered >= options.failed_handshakes
+    }
+}
+
+/// The address to move to out of `addrs`, none to stay. The current address is kept while
+/// the host still has it, and a live session is never moved.
+fn choose(
+    current: Option<SocketAddr>,
+    addrs: &[SocketAddr],
+    preference: AddressPreference,
+    stats: &PeerStats,
+) -> Option<SocketAddr> {
+    if current.is_some_and(|current| addrs.contains(&current)) {
+        return None;
+    }
+    let live = stats
+        .time_since_last_handshake
+        .is_some_and(|since| since < STALE_HANDSHAKE);
+    if current.is_some() && live {
+        return None;
+    }
+    preference.pick(addrs)
+}
+
+impl Device {
+    /// Look up hostname endpoints with `resolver` from now on
+    pub async fn set_resolver(&self, resolver: Arc<dyn Resolver>) {
+        *self.resolver.write().await = resolver;
+    }
+
+    pub async fn set_resolve_options(&self, options: ResolveOptions) {
+        *self.resolve_options.write().await = options;
+        self.resolve_now.notify_one();
+    }
+
+    /// Resolve the hostname endpoints when they are set and when they are due again, until
+    /// the device is closed
+    pub(super) fn resolve_endpoints(self: &Arc<Self>) {
+        let device = Arc::clone(self);
+        let mut close = self.close_sender.subscribe();
+        self.tasks.spawn(async move {
+            let mut tick = tokio::time::interval(RESOLVE_TICK);
+            loop {
+                tokio::select! {
+                    _ = tick.tick() => {}
+                    _ = device.resolve_now.notified() => {}
+                    _ = close.recv() => break,
+                }
+                // A slow lookup does not hold up the shutdown
+                tokio::select! {
+                    _ = device.resolve_due() => {}
+                    _ = close.recv() => break,
+                }
+            }
+        });
+    }
+
+    async fn resolve_due(&self) {
+        let options = self.resolve_options.read().await.clone();
+        let now = Instant::now();
+        let mut due = Vec::new();
+        let peers: Vec<_> = self.peers.iter().map(|e| e.value().clone()).collect();
+        for peer in peers {
+            let p = peer.lock().await;
+            let Some(hostname) = &p.hostname else {
+                continue;
+            };
+            let stats = p.tunnel.stats();
+            if p.resolution
+                .is_none_or(|resolution| resolution.due(&options, &stats, now))
+            {
+                due.push((p.pub_key, hostname.clone()));
+            }
+        }
+        if due.is_empty() {
+            return;
+        }
+
+        let resolver = Arc::clone(&*self.resolver.read().await);
+        let lookups = due.into_iter().map(|(pub_key, hostname)| {
+            let resolver = Arc::clone(&resolver);
+            async move {
+                let lookup = resolver.resolve(&hostname.host, hostname.port);
+                let addrs = match tokio::time::timeout(RESOLVE_TIMEOUT, lookup).await {
+                    Ok(Ok(addrs)) if !addrs.is_empty() => Ok(addrs),
+                    Ok(Ok(_)) => Err(io::Error::from(io::ErrorKind::NotFound).into()),
+                    Ok(Err(e)) => Err(WgError::from(e)),
+                    Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+                };
+                (pub_key, hostname, addrs)
+            }
+        });
+        for (pub_key, hostname, addrs) in futures_util::future::join_all(lookups).await {
+            self.apply_resolution(&pub_key, &hostname, addrs, options.preference)
+                .await;
+        }
+    }
+
+    async fn apply_resolution(
+        &self,
+        pub_key: &x25519::PublicKey,
+        hostname: &HostEndpoint,
+        addrs: WgResult<Vec<SocketAddr>>,
+        preference: AddressPreference,
+    ) {
+        let Some(peer) = self.peers.get(pub_key).map(|e| e.value().clone()) else {
+            return;
+        };
+        let mut p = peer.lock().await;
+        // Changed while resolving
+        if p.hostname.as_ref() != Some(hostname) {
+            return;
+        }
+        let stats = p.tunnel.stats();
+        let mut resolution = Resolution {
+            at: Instant::now(),
+            attempts: stats.handshake_attempts,
+            failed: false,
+        };
+        match addrs {
+            Ok(addrs) => {
+                let current = p.addr.map(|endpoint| endpoint.addr());
+                if let Some(addr) = choose(current, &addrs, preference, &stats) {
+                    tracing::info!(message = "Resolved endpoint", device = %self.name, %hostname, %addr);
+                    p.addr = Some(hostname.endpoint(addr));
+                }
+                // Hosts with several addresses, usually one of each family, are raced over
+                let mut candidates = Vec::new();
+                for addr in preference.filter(&addrs) {
+                    let endpoint = hostname.endpoint(addr);
+                    if !candidates.contains(&endpoint) {
+                        candidates.push(endpoint);
+                    }
+                }
+                if candidates.len() < 2 {
+                    candidates.clear();
+                }
+                p.candidates = candidates;
+            }
+            Err(e) => {
+                tracing::warn!(message = "Failed to resolve endpoint", device = %self.name, %hostname, error = %e);
+                resolution.failed = true;
+            }
+        }
+        p.resolution = Some(resolution);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::{
+        peer::PeerConfig,
+        testing::{device, ipv4_packet, peer, wait_for_addr, wait_for_stats, StaticResolver},
+        transport::loopback::LoopbackNetwork,
+    };
+
+    use super::*;
+
+    #[test]
+    fn parse_host_endpoint() {
+        let endpoint: HostEndpoint = "vpn.example.com:51820".parse().unwrap();
+        assert_eq!(
+            endpoint,
+            HostEndpoint {
+                host: "vpn.example.com".to_owned(),
+                port: 51820,
+                tcp: false
+            }
+        );
+        assert_eq!(endpoint.to_string(), "vpn.example.com:51820");
+        let tcp: HostEndpoint = "tcp://vpn.example.com:443".parse().unwrap();
+        assert!(tcp.tcp);
+        assert_eq!(tcp.to_string(), "tcp://vpn.example.com:443");
+        let addr = "192.0.2.1:443".parse().unwrap();
+        assert_eq!(tcp.endpoint(addr), Endpoint::Tcp(addr));
+        for invalid in [
+            "vpn.example.com",
+            ":51820",
+            "quic://vpn:1",
+            "[::1]:51820",
+            "a b:1",
+        ] {
+            assert!(invalid.parse::<HostEndpoint>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn address_preference() {
+        let v6: SocketAddr = "[2001:db8::1]:51820".parse().unwrap();
+        let v4: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        assert_eq!(AddressPreference::System.pick(&[v6, v4]), Some(v6));
+        assert_eq!(AddressPreference::Ipv4.pick(&[v6, v4]), Some(v4));
+        assert_eq!(AddressPreference::Ipv6.pick(&[v4, v6]), Some(v6));
+        // Falls back to the other family
+        assert_eq!(AddressPreference::Ipv6.pick(&[v4]), Some(v4));
+        assert_eq!(AddressPreference::Ipv4.pick(&[]), None);
+        assert_eq!(AddressPreference::Ipv4.filter(&[v6, v4]), [v4]);
+        assert_eq!(AddressPreference::System.filter(&[v6, v4]), [v6, v4]);
+        assert_eq!(
+            "ipv6".parse::<AddressPreference>().unwrap(),
+            AddressPreference::Ipv6
+        );
+        assert!("both".parse::<AddressPreference>().is_err());
+    }
+
+    #[test]
+    fn choose_address() {
+        let (old, new): (SocketAddr, SocketAddr) = (
+            "192.0.2.1:51820".parse().unwrap(),
+            "192.0.2.2:51820".parse().unwrap(),
+        );
+        let prefer = AddressPreference::System;
+        let mut stats = PeerStats::default();
+        assert_eq!(choose(None, &[new], prefer, &stats), Some(new));
+        assert_eq!(choose(Some(old), &[new], prefer, &stats), Some(new));
+        // Still one of the host's addresses
+        assert_eq!(choose(Some(old), &[new, old], prefer, &stats), None);
+        stats.time_since_last_handshake = Some(Duration::from_secs(10));
+        assert_eq!(choose(Some(old), &[new], prefer, &stats), None);
+        stats.time_since_last_handshake = Some(STALE_HANDSHAKE);
+        assert_eq!(choose(Some(old), &[new], prefer, &stats), Some(new));
+    }
+
+    #[test]
+    fn resolution_due() {
+        let options = ResolveOptions {
+            interval: Some(Duration::from_secs(60)),
+            failed_handshakes: 3,
+            preference: AddressPreference::System,
+        };
+        let at = Instant::now();
+        let now = at + Duration::from_secs(20);
+        let resolution = Resolution {
+            at,
+            attempts: 5,
+            failed: false,
+        };
+        let mut stats = PeerStats {
+            handshake_attempts: 7,
+            ..Default::default()
+        };
+        assert!(!resolution.due(&options, &stats, now));
+        assert!(resolution.due(&options, &stats, at + Duration::from_secs(60)));
+        // Handshakes keep failing
+        stats.handshake_attempts = 8;
+        assert!(resolution.due(&options, &stats, now));
+        // Unless one completed since
+        stats.time_since_last_handshake = Some(Duration::from_secs(5));
+        assert!(!resolution.due(&options, &stats, now));
+
+        let failed = Resolution {
+            failed: true,
+            ..resolution
+        };
+        assert!(!failed.due(&options, &stats, at + Duration::from_secs(5)));
+        assert!(failed.due(&options, &stats, at + RETRY_INTERVAL));
+    }
+
+    #[tokio::test]
+    async fn hostname_endpoints() {
+        let network = LoopbackNetwork::new();
+        let addr_a: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let addr_b: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let (a, key_a) = device("wgloop28", &network, addr_a).await;
+        let (b, key_b) = device("wgloop29", &network, addr_b).await;
+        let resolver = Arc::new(StaticResolver::default());
+        resolver.set("b.test", &["2001:db8::2", "192.0.2.2"]);
+        resolver.set("c.test", &["192.0.2.4"]);
+        a.set_resolver(resolver.clone()).await;
+        a.set_resolve_options(ResolveOptions {
+            interval: Some(Duration::from_millis(100)),
+            failed_handshakes: 3,
+            preference: AddressPreference::Ipv4,
+        })
+        .await;
+
+        let mut peer_b = PeerConfig::new(key_b);
+        peer_b.parse_endpoint("b.test:51820").unwrap();
+        peer_b.allowed_ips.push("10.0.0.2/32".parse().unwrap());
+        assert_eq!(peer_b.to_uapi()[1], "endpoint=b.test:51820");
+        a.update_peer(peer_b).await.unwrap();
+        b.update_peer(peer(key_a, addr_a, "10.0.0.1/32"))
+            .await
+            .unwrap();
+        assert!(wait_for_addr(&a, &key_b, "192.0.2.2:51820").await);
+        a.handle_iface_packet(ipv4_packet([10, 0, 0, 1], [10, 0, 0, 2]))
+            .await
+            .unwrap();
+        let handshake = wait_for_stats(&a, &key_b, |stats| stats.last_handshake.is_some()).await;
+        assert!(handshake, "handshake did not complete");
+
+        // The host moved, but the session is live
+        resolver.set("b.test", &["192.0.2.3"]);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(wait_for_addr(&a, &key_b, "192.0.2.2:51820").await);
+
+        // Without a session the endpoint follows the host
+        let key_c = x25519::PublicKey::from([8; 32]);
+        let mut peer_c = PeerConfig::new(key_c);
+        peer_c.parse_endpoint("c.test:51820").unwrap();
+        a.update_peer(peer_c).await.unwrap();
+        assert!(wait_for_addr(&a, &key_c, "192.0.2.4:51820").await);
+        resolver.set("c.test", &["192.0.2.5"]);
+        assert!(wait_for_addr(&a, &key_c, "192.0.2.5:51820").await);
+
+        a.shutdown().await.unwrap();
+        b.close();
+    }
+}
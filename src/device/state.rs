@@ -0,0 +1,270 @@
+//! Runtime state kept across restarts: the endpoints the peers were last seen at, the
+//! timestamp of their last handshake initiation, which rejects replays of older ones, and
+//! their traffic counters.
+//!
+//! The state file holds the `key=value` lines of the control protocol, a `public_key` line
+//! starting each peer:
+//!
+//! ```text
+//! public_key=<HEX_PUBLIC_KEY>
+//! endpoint=192.0.2.2:51820
+//! last_handshake_timestamp=<HEX_TAI64N>
+//! rx_bytes=1024
+//! tx_bytes=2048
+//! ```
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    error::{WgError, WgResult},
+    key_bytes::KeyBytes,
+    noise::stats::PeerStats,
+    x25519,
+};
+
+use super::{endpoint::Endpoint, Device};
+
+/// What [`Device::saved_state`] records about the peers
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SavedState {
+    pub peers: Vec<SavedPeer>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SavedPeer {
+    pub public_key: x25519::PublicKey,
+    pub endpoint: Option<Endpoint>,
+    /// TAI64N timestamp of the last handshake initiation accepted from the peer
+    pub last_handshake_timestamp: [u8; 12],
+    /// Only the traffic and handshake counters are saved
+    pub counters: PeerStats,
+}
+
+impl SavedPeer {
+    fn new(public_key: x25519::PublicKey) -> Self {
+        SavedPeer {
+            public_key,
+            endpoint: None,
+            last_handshake_timestamp: [0; 12],
+            counters: PeerStats::default(),
+        }
+    }
+}
+
+impl fmt::Display for SavedState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for peer in &self.peers {
+            let counters = &peer.counters;
+            writeln!(
+                f,
+                "public_key={}",
+                KeyBytes(peer.public_key.to_bytes()).to_hex()
+            )?;
+            if let Some(endpoint) = peer.endpoint {
+                writeln!(f, "endpoint={endpoint}")?;
+            }
+            let timestamp: String = peer
+                .last_handshake_timestamp
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect();
+            writeln!(f, "last_handshake_timestamp={timestamp}")?;
+            for (key, value) in [
+                ("rx_bytes", counters.rx_bytes),
+                ("tx_bytes", counters.tx_bytes),
+                ("rx_wire_bytes", counters.rx_wire_bytes),
+                ("tx_wire_bytes", counters.tx_wire_bytes),
+                ("rx_packets", counters.rx_packets),
+                ("tx_packets", counters.tx_packets),
+                ("handshake_attempts", counters.handshake_attempts),
+                ("handshake_failures", counters.handshake_failures),
+            ] {
+                writeln!(f, "{key}={value}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SavedState {
+    type Err = WgError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut state = SavedState::default();
+        for (number, line) in s.lines().enumerate() {
+            let invalid = || WgError::InvalidConfig(number + 1, format!("Invalid line {line}"));
+            if line.is_empty() {
+                continue;
+            }
+            let (key, val) = line.split_once('=').ok_or_else(invalid)?;
+            if key == "public_key" {
+                let key_bytes: KeyBytes = val.parse().map_err(|_| invalid())?;
+                state.peers.push(SavedPeer::new(key_bytes.0.into()));
+                continue;
+            }
+            let peer = state.peers.last_mut().ok_or_else(invalid)?;
+            let counters = &mut peer.counters;
+            let counter = match key {
+                "endpoint" => {
+                    peer.endpoint = Some(val.parse().map_err(|_| invalid())?);
+                    continue;
+                }
+                "last_handshake_timestamp" => {
+                    peer.last_handshake_timestamp = parse_timestamp(val).ok_or_else(invalid)?;
+                    continue;
+                }
+                "rx_bytes" => &mut counters.rx_bytes,
+                "tx_bytes" => &mut counters.tx_bytes,
+                "rx_wire_bytes" => &mut counters.rx_wire_bytes,
+                "tx_wire_bytes" => &mut counters.tx_wire_bytes,
+                "rx_packets" => &mut counters.rx_packets,
+                "tx_packets" => &mut counters.tx_packets,
+                "handshake_attempts" => &mut counters.handshake_attempts,
+                "handshake_failures" => &mut counters.handshake_failures,
+                // Written by a newer version
+                _ => continue,
+            };
+            *counter = val.parse().map_err(|_| invalid())?;
+        }
+        Ok(state)
+    }
+}
+
+fn parse_timestamp(val: &str) -> Option<[u8; 12]> {
+    let mut timestamp = [0u8; 12];
+    if val.len() != 24 {
+        return None;
+    }
+    for (i, byte) in timestamp.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(val.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(timestamp)
+}
+
+impl Device {
+    /// The state of every peer, to hand to [`Device::restore_state`] after a restart
+    pub async fn saved_state(&self) -> SavedState {
+        let peers: Vec<_> = self.peers.iter().map(|e| e.value().clone()).collect();
+        let mut state = SavedState::default();
+        for peer in peers {
+            let p = peer.lock().await;
+            state.peers.push(SavedPeer {
+                public_key: p.pub_key,
+                endpoint: p.addr,
+                last_handshake_timestamp: p.tunnel.last_handshake_timestamp(),
+                counters: p.tunnel.stats(),
+            });
+        }
+        state
+    }
+
+    /// Restore what `state` saved about the peers that are configured, returns how many.
+    /// The saved endpoint is only used by peers without a configured one.
+    pub async fn restore_state(&self, state: &SavedState) -> usize {
+        let mut restored = 0;
+        for saved in &state.peers {
+            let Some(peer) = self.peers.get(&saved.public_key).map(|e| e.value().clone()) else {
+                continue;
+            };
+            let mut p = peer.lock().await;
+            if p.addr.is_none() {
+                p.addr = saved.endpoint;
+            }
+            p.tunnel
+                .restore_last_handshake_timestamp(&saved.last_handshake_timestamp);
+            p.tunnel.restore_counters(&saved.counters);
+            restored += 1;
+        }
+        restored
+    }
+
+    /// Write the state to `path`, replaced at once and only readable by its owner
+    pub async fn save_state_file(&self, path: &Path) -> WgResult<()> {
+        let state = self.saved_state().await.to_string();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)
+            .await?;
+        file.write_all(state.as_bytes()).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+
+    /// Restore the state saved in `path`, returns how many peers were restored. Nothing is
+    /// restored when the file does not exist yet.
+    pub async fn load_state_file(&self, path: &Path) -> WgResult<usize> {
+        let state = match tokio::fs::read_to_string(path).await {
+            Ok(state) => state,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(self.restore_state(&state.parse()?).await)
+    }
+
+    /// Save the state to `path` every `interval`, and a last time when the device shuts down
+    pub async fn persist_state(self: &Arc<Self>, path: PathBuf, interval: Duration) {
+        *self.state_file.write().await = Some(path.clone());
+        let device = Arc::clone(self);
+        let mut close = self.close_sender.subscribe();
+        if self.is_closing() {
+            return;
+        }
+        self.tasks.spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            // The first tick is immediate, there is nothing new to save yet
+            interval.tick().await;
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        if let Err(e) = device.save_state_file(&path).await {
+                            tracing::warn!(message = "Failed to save the state", path = %path.display(), error = %e);
+                        }
+                    }
+                    // The final save is part of the teardown, before the peers are gone
+                    _ = close.recv() => break,
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_round_trip() {
+        let mut peer = SavedPeer::new([9; 32].into());
+        peer.endpoint = Some("192.0.2.2:51820".parse().unwrap());
+        peer.last_handshake_timestamp = [0x40, 0, 0, 0, 0, 0, 0, 0x25, 0, 0, 0, 7];
+        peer.counters.rx_bytes = 1024;
+        peer.counters.handshake_attempts = 3;
+        let state = SavedState {
+            peers: vec![peer, SavedPeer::new([10; 32].into())],
+        };
+        let text = state.to_string();
+        assert!(text.contains("last_handshake_timestamp=400000000000002500000007\n"));
+        assert_eq!(text.parse::<SavedState>().unwrap(), state);
+
+        assert!("rx_bytes=1".parse::<SavedState>().is_err());
+        let err = format!("public_key={}\nendpoint=nowhere", "09".repeat(32))
+            .parse::<SavedState>()
+            .err()
+            .unwrap();
+        assert!(matches!(err, WgError::InvalidConfig(2, _)));
+    }
+}
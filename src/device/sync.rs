@@ -0,0 +1,332 @@
+//! Applying a whole configuration as its difference to the running one, like `wg syncconf`.
+
+use std::{collections::HashSet, fmt, sync::atomic::Ordering, sync::Arc};
+
+use ip_network::IpNetwork;
+
+use super::{
+    peer::{Peer, PeerConfig},
+    Device, DeviceConfig,
+};
+use crate::{
+    error::{WgError, WgResult},
+    secret::Secret,
+    x25519,
+};
+
+/// What [`Device::sync_config`] did to the peers
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SyncSummary {
+    pub added: usize,
+    pub removed: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+}
+
+impl fmt::Display for SyncSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} removed, {} updated, {} unchanged",
+            self.added, self.removed, self.updated, self.unchanged
+        )
+    }
+}
+
+/// How a running peer differs from its configuration
+enum PeerDiff {
+    Unchanged,
+    /// The settings to change in place
+    Update(Box<PeerConfig>),
+    /// Obfuscation was turned off, which only a new peer can do
+    Replace,
+}
+
+impl Device {
+    /// Make the device match `config`, touching only what differs.
+    ///
+    /// Peers missing from `config` are removed and new ones added. The endpoint, allowed IPs,
+    /// persistent keepalive, preshared key and obfuscation of the others are changed in place,
+    /// so the sessions of the peers survive. Like `wg syncconf`, a preshared key or keepalive
+    /// missing from `config` is cleared while a missing endpoint keeps the current one. The
+    /// filter rules are left alone, see [`Device::set_filter`].
+    ///
+    /// The whole of `config` is validated first, and nothing changes if it is rejected.
+    pub async fn sync_config(self: &Arc<Self>, config: DeviceConfig) -> WgResult<SyncSummary> {
+        // Everything that can fail is checked or done before anything changes, so an invalid
+        // config or a listen port that cannot be bound leaves the device as it was
+        for peer in &config.peers {
+            peer.validate()?;
+        }
+        let has_key = config.private_key.is_some() || self.key_pair.read().await.is_some();
+        if !has_key && !config.peers.is_empty() {
+            return Err(WgError::KeyNotSet);
+        }
+        if let Some(port) = config.listen_port {
+            if port != 0 && port != self.listen_port.load(Ordering::Relaxed) {
+                self.open_listen_port(port).await?;
+            }
+        }
+
+        if let Some(private_key) = &config.private_key {
+            // Does nothing when the key is the same
+            self.set_key(x25519::StaticSecret::from(*private_key.expose()))
+                .await;
+        }
+
+        let mut summary = SyncSummary::default();
+        let wanted: HashSet<_> = config.peers.iter().map(|p| p.pub_key).collect();
+        let stale: Vec<_> = self
+            .peers
+            .iter()
+            .map(|e| *e.key())
+            .filter(|key| !wanted.contains(key))
+            .collect();
+        for key in stale {
+            self.remove_peer(&key).await;
+            summary.removed += 1;
+        }
+
+        for peer in config.peers {
+            let existing = self.peers.get(&peer.pub_key).map(|e| e.value().clone());
+            let Some(existing) = existing else {
+                self.update_peer(peer).await?;
+                summary.added += 1;
+                continue;
+            };
+            let diff = diff(&*existing.lock().await, &peer)?;
+            match diff {
+                PeerDiff::Unchanged => summary.unchanged += 1,
+                PeerDiff::Update(changes) => {
+                    self.update_peer(*changes).await?;
+                    summary.updated += 1;
+                }
+                PeerDiff::Replace => {
+                    self.remove_peer(&peer.pub_key).await;
+                    self.update_peer(peer).await?;
+                    summary.updated += 1;
+                }
+            }
+        }
+        Ok(summary)
+    }
+}
+
+fn networks(config: &PeerConfig) -> WgResult<HashSet<IpNetwork>> {
+    config
+        .allowed_ips
+        .iter()
+        .map(|ip| {
+            ip.network()
+                .ok_or_else(|| WgError::InvalidValue(format!("allowed ip {ip}")))
+        })
+        .collect()
+}
+
+fn diff(peer: &Peer, config: &PeerConfig) -> WgResult<PeerDiff> {
+    let obfuscation = peer.obfuscator.as_ref().map(|o| o.config());
+    if obfuscation.is_some() && config.obfuscation.is_none() {
+        return Ok(PeerDiff::Replace);
+    }
+
+    let mut changes = PeerConfig::new(config.pub_key);
+    let mut changed = false;
+    let moved = match config.candidates.is_empty() {
+        true => config.endpoint != peer.addr || !peer.candidates.is_empty(),
+        false => config.candidates != peer.candidates,
+    };
+    if config.endpoint.is_some() && (moved || peer.hostname.is_some()) {
+        changes.endpoint = config.endpoint;
+        changes.candidates = config.candidates.clone();
+        changed = true;
+    }
+    if config.hostname.is_some() && config.hostname != peer.hostname {
+        changes.hostname = config.hostname.clone();
+        changed = true;
+    }
+    let keepalive = config.keepalive.unwrap_or(0);
+    if keepalive != peer.tunnel.persistent_keepalive().unwrap_or(0) {
+        changes.keepalive(keepalive);
+        changed = true;
+    }
+    let preshared_key = config.preshared_key.clone().filter(|k| !k.is_zero());
+    if preshared_key != peer.preshared_key {
+        // An all-zero key removes it
+        changes.preshared_key = Some(preshared_key.unwrap_or_else(|| Secret::new([0; 32])));
+        changed = true;
+    }
+    let current: HashSet<_> = peer
+        .allowed_ips
+        .iter()
+        .map(|(network, _)| network)
+        .collect();
+    if networks(config)? != current {
+        changes.replace_ips(true);
+        changes.allowed_ips = config.allowed_ips.clone();
+        changed = true;
+    }
+    if config.obfuscation.as_ref() != obfuscation {
+        changes.obfuscation = config.obfuscation.clone();
+        changed = true;
+    }
+    Ok(match changed {
+        true => PeerDiff::Update(Box::new(changes)),
+        false => PeerDiff::Unchanged,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        time::Duration,
+    };
+
+    use crate::device::{
+        obfuscation::ObfuscationConfig,
+        testing::{device, peer, wait_for_stats},
+        transport::loopback::LoopbackNetwork,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn sync_config_keeps_sessions() {
+        let network = LoopbackNetwork::new();
+        let addr_a: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let addr_b: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let (a, key_a) = device("wgloop21", &network, addr_a).await;
+        let (b, key_b) = device("wgloop22", &network, addr_b).await;
+        let (key_c, key_d) = ([8; 32].into(), [7; 32].into());
+
+        let mut peer_b = peer(key_b, addr_b, "10.0.0.2/32");
+        peer_b.keepalive(1);
+        a.update_peer(peer_b.clone()).await.unwrap();
+        a.update_peer(peer(key_d, addr_b, "10.0.0.4/32"))
+            .await
+            .unwrap();
+        b.update_peer(peer(key_a, addr_a, "10.0.0.1/32"))
+            .await
+            .unwrap();
+        assert!(wait_for_stats(&a, &key_b, |stats| stats.last_handshake.is_some()).await);
+        let handshake = a.peer_stats(&key_b).await.unwrap().last_handshake.unwrap();
+
+        // B gains a network, C is new and D is gone
+        peer_b.allowed_ips.push("10.2.0.0/16".parse().unwrap());
+        let config = |peers| DeviceConfig {
+            peers,
+            private_key: None,
+            public_key: None,
+            listen_port: None,
+            filter: Default::default(),
+        };
+        let summary = a
+            .sync_config(config(vec![
+                peer_b.clone(),
+                peer(key_c, addr_b, "10.0.0.3/32"),
+            ]))
+            .await
+            .unwrap();
+        assert_eq!((summary.added, summary.removed, summary.updated), (1, 1, 1));
+        assert!(a.peers.contains_key(&key_c) && !a.peers.contains_key(&key_d));
+        let routed = a.peers_by_ip.read().await;
+        let (_, route) = routed.longest_match(Ipv4Addr::new(10, 2, 3, 4)).unwrap();
+        assert_eq!(route.lock().await.pub_key, key_b);
+        drop(routed);
+        // Updated in place, so the session is still there. The time is derived from an elapsed
+        // duration, so it moves a little between reads.
+        let after = a.peer_stats(&key_b).await.unwrap().last_handshake.unwrap();
+        let drift = after
+            .duration_since(handshake)
+            .unwrap_or_else(|e| e.duration());
+        assert!(drift < Duration::from_millis(100), "{drift:?}");
+
+        let summary = a
+            .sync_config(config(vec![peer_b, peer(key_c, addr_b, "10.0.0.3/32")]))
+            .await
+            .unwrap();
+        assert_eq!(summary.unchanged, 2);
+        assert_eq!(summary.added + summary.removed + summary.updated, 0);
+
+        a.close();
+        b.close();
+    }
+
+    #[tokio::test]
+    async fn sync_config_rejects_invalid() {
+        let network = LoopbackNetwork::new();
+        let addr: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let (a, _) = device("wgloop38", &network, addr).await;
+        let (key_b, key_c, key_d) = ([9; 32].into(), [8; 32].into(), [7; 32].into());
+        let peer_b = peer(key_b, addr, "10.0.0.2/32");
+        a.update_peer(peer_b.clone()).await.unwrap();
+        a.open_listen_port(0).await.unwrap();
+        let port = a.listen_port.load(Ordering::Relaxed);
+
+        let config = |peers, listen_port| DeviceConfig {
+            peers,
+            private_key: None,
+            public_key: None,
+            listen_port,
+            filter: Default::default(),
+        };
+        let unchanged = |a: &Device| {
+            a.peers.len() == 1
+                && a.peers.contains_key(&key_b)
+                && a.listen_port.load(Ordering::Relaxed) == port
+        };
+
+        // D cannot be sent to, so C is not added and B keeps its network
+        let mut moved_b = peer(key_b, addr, "10.9.0.0/16");
+        moved_b.keepalive(5);
+        let unreachable = peer(key_d, "192.0.2.4:0".parse().unwrap(), "10.0.0.4/32");
+        let result = a
+            .sync_config(config(
+                vec![
+                    moved_b.clone(),
+                    peer(key_c, addr, "10.0.0.3/32"),
+                    unreachable,
+                ],
+                Some(0),
+            ))
+            .await;
+        assert!(
+            matches!(result, Err(WgError::InvalidValue(_))),
+            "{result:?}"
+        );
+        assert!(unchanged(&a));
+        let routed = a.peers_by_ip.read().await;
+        assert!(routed.longest_match(Ipv4Addr::new(10, 0, 0, 2)).is_some());
+        assert!(routed.longest_match(Ipv4Addr::new(10, 9, 0, 1)).is_none());
+        drop(routed);
+
+        // Padding that does not fit in a datagram
+        let mut padded = peer(key_c, addr, "10.0.0.3/32");
+        let mut obfuscation = ObfuscationConfig::new([1; 32]);
+        obfuscation.max_padding = u16::MAX;
+        padded.obfuscation = Some(obfuscation);
+        let result = a.sync_config(config(vec![padded], None)).await;
+        assert!(
+            matches!(result, Err(WgError::InvalidValue(_))),
+            "{result:?}"
+        );
+        assert!(unchanged(&a));
+
+        // A listen port in use fails before the peers are touched
+        let taken = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        let taken_port = taken.local_addr().unwrap().port();
+        let result = a.sync_config(config(vec![moved_b], Some(taken_port))).await;
+        assert!(result.is_err());
+        assert!(unchanged(&a));
+
+        // Peers need a key, from the config or the device
+        let (bare, _) = device("wgloop39", &network, "192.0.2.2:51820".parse().unwrap()).await;
+        bare.key_pair.write().await.take();
+        let result = bare.sync_config(config(vec![peer_b], None)).await;
+        assert!(matches!(result, Err(WgError::KeyNotSet)), "{result:?}");
+        assert!(bare.peers.is_empty());
+
+        a.close();
+        bare.close();
+    }
+}
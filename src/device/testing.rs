@@ -0,0 +1,134 @@
+//! Fixtures shared by the device tests: devices wired together over a [`LoopbackNetwork`] and
+//! the waits for what they do.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use crate::{noise::stats::PeerStats, x25519};
+
+use super::{
+    endpoint::Endpoint, obfuscation::ObfuscationConfig, peer::PeerConfig, resolve::Resolver,
+    transport::loopback::LoopbackNetwork, Device,
+};
+
+pub(crate) fn ipv4_packet(src: [u8; 4], dst: [u8; 4]) -> Bytes {
+    let mut packet = vec![0u8; 20];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&20u16.to_be_bytes());
+    packet[8] = 64;
+    packet[12..16].copy_from_slice(&src);
+    packet[16..20].copy_from_slice(&dst);
+    Bytes::from(packet)
+}
+
+/// A device on `network` at `addr`, with a fresh private key
+pub(crate) async fn device(
+    name: &str,
+    network: &LoopbackNetwork,
+    addr: SocketAddr,
+) -> (Arc<Device>, x25519::PublicKey) {
+    let device = Device::new(name.to_owned()).await.unwrap();
+    let private_key = x25519::StaticSecret::random_from_rng(rand_core::OsRng);
+    let public_key = x25519::PublicKey::from(&private_key);
+    device.set_key(private_key).await;
+    device.add_transport(network.bind(addr)).await;
+    (device, public_key)
+}
+
+pub(crate) fn peer(key: x25519::PublicKey, addr: SocketAddr, allowed_ip: &str) -> PeerConfig {
+    let mut config = PeerConfig::new(key);
+    config.endpoint(Endpoint::Udp(addr));
+    config.allowed_ips.push(allowed_ip.parse().unwrap());
+    config
+}
+
+/// Wait until the tunnel stats of `device`'s `peer` satisfy `f`
+pub(crate) async fn wait_for_stats(
+    device: &Device,
+    peer: &x25519::PublicKey,
+    f: impl Fn(PeerStats) -> bool,
+) -> bool {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !f(device.peer_stats(peer).await.unwrap()) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .is_ok()
+}
+
+/// Wait until `device` sends to `peer` at `addr`
+pub(crate) async fn wait_for_addr(device: &Device, peer: &x25519::PublicKey, addr: &str) -> bool {
+    let addr = Some(Endpoint::Udp(addr.parse().unwrap()));
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while device
+            .peers
+            .get(peer)
+            .unwrap()
+            .value()
+            .clone()
+            .lock()
+            .await
+            .addr
+            != addr
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .is_ok()
+}
+
+/// Two devices named `names` complete a handshake, both with `obfuscation` for the other
+pub(crate) async fn handshake(names: [&str; 2], obfuscation: Option<ObfuscationConfig>) {
+    let network = LoopbackNetwork::new();
+    let addr_a: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+    let addr_b: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+    let (a, key_a) = device(names[0], &network, addr_a).await;
+    let (b, key_b) = device(names[1], &network, addr_b).await;
+
+    let mut peer_b = peer(key_b, addr_b, "10.0.0.2/32");
+    peer_b.obfuscation = obfuscation.clone();
+    a.update_peer(peer_b).await.unwrap();
+    let mut peer_a = peer(key_a, addr_a, "10.0.0.1/32");
+    peer_a.obfuscation = obfuscation;
+    b.update_peer(peer_a).await.unwrap();
+
+    a.handle_iface_packet(ipv4_packet([10, 0, 0, 1], [10, 0, 0, 2]))
+        .await
+        .unwrap();
+    let handshake = wait_for_stats(&a, &key_b, |stats| stats.last_handshake.is_some()).await;
+    assert!(handshake, "handshake did not complete");
+
+    a.close();
+    b.close();
+}
+
+/// Hosts and their addresses, instead of the system resolver
+#[derive(Default)]
+pub(crate) struct StaticResolver(Mutex<HashMap<String, Vec<IpAddr>>>);
+
+impl StaticResolver {
+    pub(crate) fn set(&self, host: &str, ips: &[&str]) {
+        let ips = ips.iter().map(|ip| ip.parse().unwrap()).collect();
+        self.0.lock().unwrap().insert(host.to_owned(), ips);
+    }
+}
+
+#[async_trait]
+impl Resolver for StaticResolver {
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        match self.0.lock().unwrap().get(host) {
+            Some(ips) => Ok(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use dashmap::DashMap;
+use tokio::sync::{mpsc, watch, Mutex};
+
+use crate::error::{WgError, WgResult};
+
+use super::{closed, Endpoint, Transport};
+
+const MAX_QUEUE_DEPTH: usize = 1024;
+
+type Inbox = mpsc::Sender<(Bytes, SocketAddr)>;
+
+/// An in-memory network for tests. Every transport bound to it pretends to own a UDP
+/// address, and messages sent to an address nobody is bound to are silently lost.
+#[derive(Clone, Default)]
+pub struct LoopbackNetwork {
+    nodes: Arc<DashMap<SocketAddr, Inbox>>,
+}
+
+impl LoopbackNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bind(&self, addr: SocketAddr) -> Arc<LoopbackTransport> {
+        let (tx, rx) = mpsc::channel(MAX_QUEUE_DEPTH);
+        let (close, _) = watch::channel(false);
+        self.nodes.insert(addr, tx);
+        Arc::new(LoopbackTransport {
+            network: self.clone(),
+            addr,
+            rx: Mutex::new(rx),
+            close,
+        })
+    }
+}
+
+pub struct LoopbackTransport {
+    network: LoopbackNetwork,
+    addr: SocketAddr,
+    rx: Mutex<mpsc::Receiver<(Bytes, SocketAddr)>>,
+    close: watch::Sender<bool>,
+}
+
+impl LoopbackTransport {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+#[async_trait]
+impl Transport for LoopbackTransport {
+    fn handles(&self, endpoint: &Endpoint) -> bool {
+        matches!(endpoint, Endpoint::Udp(_))
+    }
+
+    async fn send_to(&self, packet: &[u8], endpoint: &Endpoint) -> WgResult<()> {
+        let inbox = self
+            .network
+            .nodes
+            .get(&endpoint.addr())
+            .map(|e| e.value().clone());
+        if let Some(inbox) = inbox {
+            inbox
+                .try_send((Bytes::copy_from_slice(packet), self.addr))
+                .ok();
+        }
+        Ok(())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> WgResult<(usize, Endpoint)> {
+        let mut close = self.close.subscribe();
+        let mut rx = self.rx.lock().await;
+        tokio::select! {
+            Some((packet, addr)) = rx.recv() => {
+                let n = packet.len().min(buf.len());
+                buf[..n].copy_from_slice(&packet[..n]);
+                Ok((n, Endpoint::Udp(addr)))
+            }
+            _ = closed(&mut close) => Err(WgError::TransportClosed),
+        }
+    }
+
+    fn close(&self) {
+        self.close.send_replace(true);
+        self.network.nodes.remove(&self.addr);
+    }
+}
+
+impl Drop for LoopbackTransport {
+    fn drop(&mut self) {
+        self.network.nodes.remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::testing::handshake;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn delivery() {
+        let network = LoopbackNetwork::new();
+        let addr_a: SocketAddr = "192.0.2.1:51820".parse().unwrap();
+        let addr_b: SocketAddr = "192.0.2.2:51820".parse().unwrap();
+        let a = network.bind(addr_a);
+        let b = network.bind(addr_b);
+        let mut buf = [0u8; 16];
+
+        a.send_to(b"hello", &Endpoint::Udp(addr_b)).await.unwrap();
+        let (n, from) = b.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..n], from), (&b"hello"[..], Endpoint::Udp(addr_a)));
+        // Nobody is bound there, the message is lost
+        let nowhere = Endpoint::Udp("192.0.2.3:51820".parse().unwrap());
+        a.send_to(b"lost", &nowhere).await.unwrap();
+
+        b.close();
+        assert!(matches!(
+            b.recv_from(&mut buf).await,
+            Err(WgError::TransportClosed)
+        ));
+        assert!(!network.nodes.contains_key(&addr_b));
+        drop(a);
+        assert!(network.nodes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn handshake_over_loopback() {
+        handshake(["wgloop0", "wgloop1"], None).await;
+    }
+}
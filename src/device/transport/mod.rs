@@ -0,0 +1,58 @@
+//! The encrypted side of a [`Device`](super::Device).
+//!
+//! A [`Transport`] moves WireGuard messages to and from [`Endpoint`]s. The device binds a
+//! [`UdpTransport`] and a [`TcpTransport`] to its listen port and consults any transports
+//! added with [`Device::add_transport`](super::Device::add_transport) before those, which is
+//! the hook for relays, obfuscation or tests.
+
+use std::net::SocketAddr;
+
+use async_trait::async_trait;
+use tokio::sync::watch;
+
+use crate::error::WgResult;
+
+use super::endpoint::Endpoint;
+
+pub mod loopback;
+pub mod tcp;
+pub mod udp;
+
+pub use self::{loopback::LoopbackTransport, tcp::TcpTransport, udp::UdpTransport};
+
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Whether messages for `endpoint` should be sent through this transport
+    fn handles(&self, endpoint: &Endpoint) -> bool;
+
+    /// Send a single WireGuard message to `endpoint`
+    async fn send_to(&self, packet: &[u8], endpoint: &Endpoint) -> WgResult<()>;
+
+    /// Receive a single WireGuard message into `buf`, returning its length and origin.
+    /// Must return [`WgError::TransportClosed`](crate::error::WgError::TransportClosed) once
+    /// the transport has been closed.
+    async fn recv_from(&self, buf: &mut [u8]) -> WgResult<(usize, Endpoint)>;
+
+    /// Stop receiving and release the underlying resources
+    fn close(&self);
+
+    /// Resolves once the tasks of a closed transport have finished
+    async fn wait_closed(&self) {}
+}
+
+/// Resolves once the close flag has been set, including when it was set before the call
+async fn closed(close: &mut watch::Receiver<bool>) {
+    // The guard returned by `wait_for` is not `Send`, drop it right away
+    let _ = close.wait_for(|closed| *closed).await;
+}
+
+/// The IPv4 address of a peer seen through a dual-stack IPv6 socket
+fn unmapped(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        addr => addr,
+    }
+}
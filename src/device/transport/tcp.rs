@@ -0,0 +1,477 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use dashmap::DashMap;
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch, Mutex, Semaphore},
+    time::Instant,
+};
+use tokio_util::{
+    codec::{Decoder, Encoder, Framed},
+    task::TaskTracker,
+};
+
+use crate::error::{WgError, WgResult};
+
+use super::{closed, unmapped, Endpoint, Transport};
+
+/// Every WireGuard message is prefixed with its length as a big-endian u16, the same framing
+/// used by udp-over-tcp relays, so either side can be replaced by one of those tools
+const LEN_PREFIX_SZ: usize = 2;
+/// Messages buffered per connection before new ones are dropped
+const MAX_QUEUE_DEPTH: usize = 1024;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Accept errors such as EMFILE are retried sooner than failed dials
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+/// Accepted connections served at once, further ones wait in the listen backlog
+const MAX_ACCEPTED: usize = 256;
+/// Connections nothing arrived on for this long are closed. It is REJECT_AFTER_TIME, past
+/// which the peer's session is gone anyway.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+
+pub struct FrameCodec;
+
+impl Encoder<Bytes> for FrameCodec {
+    type Error = WgError;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> WgResult<()> {
+        let len = u16::try_from(item.len()).map_err(|_| WgError::InvalidPacket)?;
+        dst.reserve(LEN_PREFIX_SZ + item.len());
+        dst.put_u16(len);
+        dst.put(item);
+        Ok(())
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Bytes;
+    type Error = WgError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> WgResult<Option<Self::Item>> {
+        if src.len() < LEN_PREFIX_SZ {
+            return Ok(None);
+        }
+        let len = u16::from_be_bytes([src[0], src[1]]) as usize;
+        if src.len() < LEN_PREFIX_SZ + len {
+            src.reserve(LEN_PREFIX_SZ + len - src.len());
+            return Ok(None);
+        }
+        src.advance(LEN_PREFIX_SZ);
+        Ok(Some(src.split_to(len).freeze()))
+    }
+}
+
+/// Carries WireGuard messages over TCP.
+///
+/// Connections accepted on the listen port are used to answer the peer that opened them,
+/// connections to `tcp://` endpoints are dialed on demand and re-dialed with exponential
+/// backoff whenever there is something to send. Up to `MAX_ACCEPTED` accepted connections
+/// are served at once, and any connection nothing arrived on for `IDLE_TIMEOUT` is closed.
+pub struct TcpTransport {
+    /// Outgoing queues of the open (or pending) connections, keyed by remote address
+    conns: DashMap<SocketAddr, mpsc::Sender<Bytes>>,
+    incoming_tx: mpsc::Sender<(Bytes, SocketAddr)>,
+    incoming_rx: Mutex<mpsc::Receiver<(Bytes, SocketAddr)>>,
+    close: watch::Sender<bool>,
+    /// The accept, dial and connection tasks
+    tasks: TaskTracker,
+    /// One permit per accepted connection being served
+    accepted: Arc<Semaphore>,
+    idle_timeout: Duration,
+    port: u16,
+    /// Handed to the connection tasks
+    this: Weak<TcpTransport>,
+}
+
+impl TcpTransport {
+    pub fn bind(mut port: u16) -> WgResult<Arc<Self>> {
+        let listener4 = {
+            let tcp = socket2::Socket::new(
+                socket2::Domain::IPV4,
+                socket2::Type::STREAM,
+                Some(socket2::Protocol::TCP),
+            )?;
+            tcp.set_reuse_address(true)?;
+            tcp.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
+            tcp.listen(1024)?;
+            tcp.set_nonblocking(true)?;
+            TcpListener::from_std(tcp.into())?
+        };
+        if port == 0 {
+            port = listener4.local_addr()?.port();
+        }
+        let listener6 = {
+            let tcp = socket2::Socket::new(
+                socket2::Domain::IPV6,
+                socket2::Type::STREAM,
+                Some(socket2::Protocol::TCP),
+            )?;
+            tcp.set_reuse_address(true)?;
+            tcp.set_only_v6(true)?;
+            tcp.bind(&SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0).into())?;
+            tcp.listen(1024)?;
+            tcp.set_nonblocking(true)?;
+            TcpListener::from_std(tcp.into())?
+        };
+        Ok(Self::with_listeners(vec![listener4, listener6], port))
+    }
+
+    /// Only dial `tcp://` endpoints, nothing is accepted on `port`
+    pub fn dial_only(port: u16) -> Arc<Self> {
+        Self::with_listeners(Vec::new(), port)
+    }
+
+    /// Accept connections on listeners bound by someone else, such as systemd, all on the
+    /// same port
+    pub fn from_listeners(listeners: Vec<std::net::TcpListener>) -> WgResult<Arc<Self>> {
+        let mut port = None;
+        let mut accepted = Vec::with_capacity(listeners.len());
+        for listener in listeners {
+            let addr = listener.local_addr()?;
+            if port.get_or_insert(addr.port()) != &addr.port() {
+                return Err(WgError::InvalidValue(format!(
+                    "listen socket {addr}, not on the same port"
+                )));
+            }
+            listener.set_nonblocking(true)?;
+            accepted.push(TcpListener::from_std(listener)?);
+        }
+        let port =
+            port.ok_or_else(|| WgError::InvalidValue("listen sockets, none given".to_owned()))?;
+        Ok(Self::with_listeners(accepted, port))
+    }
+
+    fn with_listeners(listeners: Vec<TcpListener>, port: u16) -> Arc<Self> {
+        Self::with_limits(listeners, port, MAX_ACCEPTED, IDLE_TIMEOUT)
+    }
+
+    fn with_limits(
+        listeners: Vec<TcpListener>,
+        port: u16,
+        max_accepted: usize,
+        idle_timeout: Duration,
+    ) -> Arc<Self> {
+        let (incoming_tx, incoming_rx) = mpsc::channel(MAX_QUEUE_DEPTH);
+        let (close, _) = watch::channel(false);
+        let this = Arc::new_cyclic(|this| Self {
+            conns: Default::default(),
+            incoming_tx,
+            incoming_rx: Mutex::new(incoming_rx),
+            close,
+            tasks: TaskTracker::new(),
+            accepted: Arc::new(Semaphore::new(max_accepted)),
+            idle_timeout,
+            port,
+            this: this.clone(),
+        });
+        for listener in listeners {
+            this.tasks
+                .spawn(Arc::clone(&this).accept(listener, this.close.subscribe()));
+        }
+        this
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Queue a message for the connection to `addr`, dialing it if there is none
+    fn queue(&self, packet: &[u8], addr: SocketAddr) -> WgResult<()> {
+        let packet = Bytes::copy_from_slice(packet);
+        let sender = self
+            .conns
+            .entry(addr)
+            .or_insert_with(|| self.dial(addr))
+            .clone();
+        match sender.try_send(packet) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                tracing::debug!(message = "TCP queue full, dropping packet", %addr);
+                Ok(())
+            }
+            Err(mpsc::error::TrySendError::Closed(packet)) => {
+                // The connection went away since we looked it up, start over
+                self.conns
+                    .remove_if(&addr, |_, tx| tx.same_channel(&sender));
+                let sender = self.dial(addr);
+                sender.try_send(packet).ok();
+                self.conns.insert(addr, sender);
+                Ok(())
+            }
+        }
+    }
+
+    fn dial(&self, addr: SocketAddr) -> mpsc::Sender<Bytes> {
+        let (tx, rx) = mpsc::channel(MAX_QUEUE_DEPTH);
+        if let Some(this) = self.this.upgrade() {
+            self.tasks
+                .spawn(this.dial_loop(addr, rx, self.close.subscribe()));
+        }
+        tx
+    }
+
+    async fn dial_loop(
+        self: Arc<Self>,
+        addr: SocketAddr,
+        mut rx: mpsc::Receiver<Bytes>,
+        mut close: watch::Receiver<bool>,
+    ) {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            // Only (re)connect once there is something to send
+            let pending = tokio::select! {
+                packet = rx.recv() => match packet {
+                    Some(packet) => packet,
+                    None => break,
+                },
+                _ = closed(&mut close) => break,
+            };
+            let stream = tokio::select! {
+                stream = TcpStream::connect(addr) => stream,
+                _ = closed(&mut close) => break,
+            };
+            match stream {
+                Ok(stream) => {
+                    tracing::debug!(message = "TCP connected", %addr);
+                    backoff = INITIAL_BACKOFF;
+                    stream.set_nodelay(true).ok();
+                    let mut framed = Framed::new(stream, FrameCodec);
+                    if framed.send(pending).await.is_ok()
+                        && !self.serve(framed, addr, &mut rx, &mut close).await
+                    {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!(message = "TCP connect failed", %addr, error = ?e, retry_in = ?backoff);
+                    tokio::select! {
+                        _ = tokio::time::sleep(backoff) => {}
+                        _ = closed(&mut close) => break,
+                    }
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+        drop(rx);
+        self.conns.remove_if(&addr, |_, tx| tx.is_closed());
+    }
+
+    async fn accept(self: Arc<Self>, listener: TcpListener, mut close: watch::Receiver<bool>) {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            // Leave further connections in the backlog until one of ours is done
+            let permit = tokio::select! {
+                permit = Arc::clone(&self.accepted).acquire_owned() => match permit {
+                    Ok(permit) => permit,
+                    Err(_) => break,
+                },
+                _ = closed(&mut close) => break,
+            };
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = closed(&mut close) => break,
+            };
+            let (stream, addr) = match accepted {
+                Ok(accepted) => {
+                    backoff = INITIAL_BACKOFF;
+                    accepted
+                }
+                Err(e) => {
+                    tracing::warn!(message = "TCP accept failed", error = ?e, retry_in = ?backoff);
+                    tokio::select! {
+                        _ = tokio::time::sleep(backoff) => {}
+                        _ = closed(&mut close) => break,
+                    }
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                    continue;
+                }
+            };
+            let addr = unmapped(addr);
+            tracing::debug!(message = "TCP accepted", %addr);
+            let (tx, mut rx) = mpsc::channel(MAX_QUEUE_DEPTH);
+            self.conns.insert(addr, tx);
+            let transport = Arc::clone(&self);
+            let mut close = close.clone();
+            self.tasks.spawn(async move {
+                stream.set_nodelay(true).ok();
+                let framed = Framed::new(stream, FrameCodec);
+                transport.serve(framed, addr, &mut rx, &mut close).await;
+                drop(rx);
+                transport.conns.remove_if(&addr, |_, tx| tx.is_closed());
+                drop(permit);
+            });
+        }
+    }
+
+    /// Pump messages in both directions until the connection breaks or nothing arrives on it for
+    /// the idle timeout. Returns false if the transport is shutting down and the connection must not be re-dialed.
+    async fn serve(
+        &self,
+        framed: Framed<TcpStream, FrameCodec>,
+        addr: SocketAddr,
+        rx: &mut mpsc::Receiver<Bytes>,
+        close: &mut watch::Receiver<bool>,
+    ) -> bool {
+        let (mut sink, mut stream) = framed.split();
+        let idle = tokio::time::sleep(self.idle_timeout);
+        tokio::pin!(idle);
+        loop {
+            tokio::select! {
+                packet = rx.recv() => match packet {
+                    Some(packet) => {
+                        if let Err(e) = sink.send(packet).await {
+                            tracing::debug!(message = "TCP send failed", %addr, error = ?e);
+                            return true;
+                        }
+                    }
+                    None => return false,
+                },
+                frame = stream.next() => match frame {
+                    Some(Ok(frame)) => {
+                        idle.as_mut().reset(Instant::now() + self.idle_timeout);
+                        if self.incoming_tx.send((frame, addr)).await.is_err() {
+                            return false;
+                        }
+                    }
+                    Some(Err(e)) => {
+                        tracing::debug!(message = "TCP receive failed", %addr, error = ?e);
+                        return true;
+                    }
+                    None => {
+                        tracing::debug!(message = "TCP closed by remote", %addr);
+                        return true;
+                    }
+                },
+                _ = &mut idle => {
+                    tracing::debug!(message = "TCP connection idle, closing it", %addr);
+                    return true;
+                }
+                _ = closed(close) => return false,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for TcpTransport {
+    fn handles(&self, endpoint: &Endpoint) -> bool {
+        endpoint.is_tcp()
+    }
+
+    async fn send_to(&self, packet: &[u8], endpoint: &Endpoint) -> WgResult<()> {
+        self.queue(packet, endpoint.addr())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> WgResult<(usize, Endpoint)> {
+        let mut close = self.close.subscribe();
+        let mut incoming = self.incoming_rx.lock().await;
+        tokio::select! {
+            Some((packet, addr)) = incoming.recv() => {
+                let n = packet.len().min(buf.len());
+                buf[..n].copy_from_slice(&packet[..n]);
+                Ok((n, Endpoint::Tcp(addr)))
+            }
+            _ = closed(&mut close) => Err(WgError::TransportClosed),
+        }
+    }
+
+    fn close(&self) {
+        self.close.send_replace(true);
+        self.tasks.close();
+    }
+
+    async fn wait_closed(&self) {
+        self.tasks.wait().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_codec_round_trip() {
+        let mut buf = BytesMut::new();
+        FrameCodec
+            .encode(Bytes::from_static(&[1, 2, 3]), &mut buf)
+            .unwrap();
+        FrameCodec.encode(Bytes::new(), &mut buf).unwrap();
+        assert_eq!(&buf[..], &[0, 3, 1, 2, 3, 0, 0]);
+
+        let mut partial = BytesMut::from(&buf[..4]);
+        assert!(FrameCodec.decode(&mut partial).unwrap().is_none());
+
+        assert_eq!(
+            FrameCodec.decode(&mut buf).unwrap().unwrap(),
+            Bytes::from_static(&[1, 2, 3])
+        );
+        assert_eq!(FrameCodec.decode(&mut buf).unwrap().unwrap(), Bytes::new());
+        assert!(FrameCodec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn transport_exchange() {
+        let server = TcpTransport::bind(0).unwrap();
+        let client = TcpTransport::bind(0).unwrap();
+        let server_addr = Endpoint::Tcp(SocketAddr::from(([127, 0, 0, 1], server.port())));
+        let mut buf = [0u8; 16];
+
+        client.send_to(b"hello", &server_addr).await.unwrap();
+        let (n, client_addr) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hello");
+
+        server.send_to(b"world", &client_addr).await.unwrap();
+        let (n, from) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"world");
+        assert_eq!(from, server_addr);
+
+        client.close();
+        server.close();
+        tokio::time::timeout(Duration::from_secs(5), server.wait_closed())
+            .await
+            .unwrap();
+        assert!(matches!(
+            server.recv_from(&mut buf).await,
+            Err(WgError::TransportClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn accept_limits() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server =
+            TcpTransport::with_limits(vec![listener], addr.port(), 1, Duration::from_millis(200));
+        let mut buf = [0u8; 16];
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        first.write_all(&[0, 1, 1]).await.unwrap();
+        let (n, _) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], [1]);
+        // Waits in the backlog while the first one is served
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        second.write_all(&[0, 1, 2]).await.unwrap();
+        let waiting = tokio::time::timeout(Duration::from_millis(100), server.recv_from(&mut buf));
+        assert!(waiting.await.is_err());
+
+        // The first one goes quiet and is closed, which lets the second one in
+        let closed = tokio::time::timeout(Duration::from_secs(5), first.read(&mut buf));
+        assert_eq!(closed.await.unwrap().unwrap(), 0);
+        let received = tokio::time::timeout(Duration::from_secs(5), server.recv_from(&mut buf));
+        let (n, _) = received.await.unwrap().unwrap();
+        assert_eq!(&buf[..n], [2]);
+
+        server.close();
+        drop(second);
+    }
+}
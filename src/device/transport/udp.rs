@@ -0,0 +1,162 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use tokio::{net::UdpSocket, sync::watch};
+
+use crate::error::{WgError, WgResult};
+
+use super::{closed, unmapped, Endpoint, Transport};
+
+/// Plain WireGuard over a pair of IPv4 and IPv6 UDP sockets bound to the same port, or a
+/// single dual-stack IPv6 socket
+pub struct UdpTransport {
+    udp4: Option<UdpSocket>,
+    udp6: Option<UdpSocket>,
+    close: watch::Sender<bool>,
+    port: u16,
+}
+
+impl UdpTransport {
+    pub fn bind(mut port: u16) -> WgResult<Arc<Self>> {
+        let udp4 = {
+            let udp = socket2::Socket::new(
+                socket2::Domain::IPV4,
+                socket2::Type::DGRAM,
+                Some(socket2::Protocol::UDP),
+            )?;
+            udp.set_reuse_address(true)?;
+            udp.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
+            udp.set_nonblocking(true)?;
+            UdpSocket::from_std(udp.into())?
+        };
+
+        if port == 0 {
+            port = udp4.local_addr()?.port();
+        }
+        let udp6 = {
+            let udp = socket2::Socket::new(
+                socket2::Domain::IPV6,
+                socket2::Type::DGRAM,
+                Some(socket2::Protocol::UDP),
+            )?;
+            udp.set_reuse_address(true)?;
+            udp.bind(&SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0).into())?;
+            udp.set_nonblocking(true)?;
+            UdpSocket::from_std(udp.into())?
+        };
+
+        let (close, _) = watch::channel(false);
+        Ok(Arc::new(Self {
+            udp4: Some(udp4),
+            udp6: Some(udp6),
+            close,
+            port,
+        }))
+    }
+
+    /// Use sockets bound by someone else, such as systemd: at most one IPv4 and one IPv6
+    /// socket, bound to the same port. IPv4 peers go through the IPv6 one when it is alone.
+    pub fn from_sockets(sockets: Vec<std::net::UdpSocket>) -> WgResult<Arc<Self>> {
+        let (mut udp4, mut udp6) = (None, None);
+        let mut port = None;
+        for socket in sockets {
+            let addr = socket.local_addr()?;
+            if port.get_or_insert(addr.port()) != &addr.port() {
+                return Err(WgError::InvalidValue(format!(
+                    "listen socket {addr}, not on the same port"
+                )));
+            }
+            socket.set_nonblocking(true)?;
+            let slot = match addr {
+                SocketAddr::V4(_) => &mut udp4,
+                SocketAddr::V6(_) => &mut udp6,
+            };
+            if slot.replace(UdpSocket::from_std(socket)?).is_some() {
+                return Err(WgError::InvalidValue(format!(
+                    "listen socket {addr}, more than one of its family"
+                )));
+            }
+        }
+        let port =
+            port.ok_or_else(|| WgError::InvalidValue("listen sockets, none given".to_owned()))?;
+        let (close, _) = watch::channel(false);
+        Ok(Arc::new(Self {
+            udp4,
+            udp6,
+            close,
+            port,
+        }))
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    fn try_recv(udp: &Option<UdpSocket>, buf: &mut [u8]) -> io::Result<Option<(usize, Endpoint)>> {
+        let Some(udp) = udp else {
+            return Ok(None);
+        };
+        match udp.try_recv_from(buf) {
+            Ok((n, addr)) => Ok(Some((n, Endpoint::Udp(unmapped(addr))))),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Never ready without a socket
+async fn readable(udp: &Option<UdpSocket>) -> io::Result<()> {
+    match udp {
+        Some(udp) => udp.readable().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[async_trait]
+impl Transport for UdpTransport {
+    fn handles(&self, endpoint: &Endpoint) -> bool {
+        matches!(endpoint, Endpoint::Udp(_))
+    }
+
+    async fn send_to(&self, packet: &[u8], endpoint: &Endpoint) -> WgResult<()> {
+        let (udp, addr) = match (endpoint.addr(), &self.udp4, &self.udp6) {
+            (addr @ SocketAddr::V4(_), Some(udp4), _) => (udp4, addr),
+            (SocketAddr::V4(addr), None, Some(udp6)) => {
+                let ip = addr.ip().to_ipv6_mapped();
+                (udp6, SocketAddr::new(ip.into(), addr.port()))
+            }
+            (addr @ SocketAddr::V6(_), _, Some(udp6)) => (udp6, addr),
+            _ => return Err(io::Error::from(io::ErrorKind::AddrNotAvailable).into()),
+        };
+        udp.send_to(packet, addr).await?;
+        Ok(())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> WgResult<(usize, Endpoint)> {
+        let mut close = self.close.subscribe();
+        loop {
+            let received = tokio::select! {
+                ready = readable(&self.udp4) => {
+                    ready?;
+                    Self::try_recv(&self.udp4, buf)?
+                }
+                ready = readable(&self.udp6) => {
+                    ready?;
+                    Self::try_recv(&self.udp6, buf)?
+                }
+                _ = closed(&mut close) => return Err(WgError::TransportClosed),
+            };
+            if let Some(received) = received {
+                return Ok(received);
+            }
+        }
+    }
+
+    fn close(&self) {
+        self.close.send_replace(true);
+    }
+}
@@ -2,12 +2,74 @@ use std::io;
 
 use thiserror::Error;
 
+use crate::noise::errors::WireGuardError;
+
 pub type WgResult<T> = Result<T, WgError>;
 
 #[derive(Error, Debug)]
 pub enum WgError {
     #[error("invalid packet")]
     InvalidPacket,
+
+    // Configuration
+    #[error("invalid config, line {0}: {1}")]
+    InvalidConfig(usize, String),
+    /// A malformed control request
+    #[error("protocol error, {0}")]
+    Protocol(String),
+    #[error("invalid {0}")]
+    InvalidValue(String),
+
+    // State
+    #[error("private key not set")]
+    KeyNotSet,
+    #[error("tunnel error, {0:?}")]
+    Tunnel(WireGuardError),
+    #[error("shutdown timed out with {0} tasks running")]
+    ShutdownTimeout(usize),
+
+    // Transport
+    #[error("transport closed")]
+    TransportClosed,
     #[error("io error, {0}")]
     IO(#[from] io::Error),
 }
+
+impl WgError {
+    /// The errno reported on the control socket
+    pub fn errno(&self) -> i32 {
+        match self {
+            WgError::Protocol(_) => libc::EPROTO,
+            WgError::InvalidPacket
+            | WgError::InvalidConfig(..)
+            | WgError::InvalidValue(_)
+            | WgError::KeyNotSet
+            | WgError::Tunnel(_) => libc::EINVAL,
+            WgError::ShutdownTimeout(_) => libc::ETIMEDOUT,
+            WgError::TransportClosed => libc::EIO,
+            WgError::IO(e) if e.kind() == io::ErrorKind::AddrInUse => libc::EADDRINUSE,
+            WgError::IO(e) => e.raw_os_error().unwrap_or(libc::EIO),
+        }
+    }
+}
+
+impl From<WireGuardError> for WgError {
+    fn from(e: WireGuardError) -> Self {
+        WgError::Tunnel(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errno() {
+        assert_eq!(WgError::Protocol("x".to_owned()).errno(), libc::EPROTO);
+        assert_eq!(WgError::KeyNotSet.errno(), libc::EINVAL);
+        let in_use = io::Error::from(io::ErrorKind::AddrInUse);
+        assert_eq!(WgError::from(in_use).errno(), libc::EADDRINUSE);
+        let denied = io::Error::from_raw_os_error(libc::EACCES);
+        assert_eq!(WgError::from(denied).errno(), libc::EACCES);
+    }
+}
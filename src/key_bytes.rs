@@ -1,4 +1,11 @@
 use base64::Engine;
+use rand_core::{OsRng, RngCore};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::x25519;
+
+/// A key parsed from hex or base64, zeroized on drop
+#[derive(Zeroize, ZeroizeOnDrop)]
 pub struct KeyBytes(pub [u8; 32]);
 
 impl std::str::FromStr for KeyBytes {
@@ -18,13 +25,11 @@ impl std::str::FromStr for KeyBytes {
             }
             43 | 44 => {
                 // Try to parse as base64
-                // ()
-                if let Ok(decoded_key) = base64::engine::general_purpose::STANDARD.decode(s) {
-                    if decoded_key.len() == internal.len() {
+                match base64::engine::general_purpose::STANDARD.decode(s) {
+                    Ok(decoded_key) if decoded_key.len() == internal.len() => {
                         internal[..].copy_from_slice(&decoded_key);
-                    } else {
-                        return Err("Illegal character in key");
                     }
+                    _ => return Err("Illegal character in key"),
                 }
             }
             _ => return Err("Illegal key size"),
@@ -33,3 +38,71 @@ impl std::str::FromStr for KeyBytes {
         Ok(KeyBytes(internal))
     }
 }
+
+/// Base64, as used by wireguard-tools and config files
+impl std::fmt::Display for KeyBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&base64::engine::general_purpose::STANDARD.encode(self.0))
+    }
+}
+
+impl KeyBytes {
+    /// A new random private key, clamped like `wg genkey` does
+    pub fn generate_private() -> Self {
+        let mut key = Self::random();
+        key.0[0] &= 248;
+        key.0[31] &= 127;
+        key.0[31] |= 64;
+        key
+    }
+
+    /// A new random preshared key
+    pub fn generate_preshared() -> Self {
+        Self::random()
+    }
+
+    fn random() -> Self {
+        let mut key = KeyBytes([0u8; 32]);
+        OsRng.fill_bytes(&mut key.0);
+        key
+    }
+
+    /// The public key of this private key
+    pub fn public_key(&self) -> KeyBytes {
+        let private_key = x25519::StaticSecret::from(self.0);
+        KeyBytes(x25519::PublicKey::from(&private_key).to_bytes())
+    }
+
+    /// Lowercase hex, as used by the UAPI
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_pair() {
+        // From the wg(8) examples
+        let private_key: KeyBytes = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk="
+            .parse()
+            .unwrap();
+        let public_key = private_key.public_key();
+        assert_eq!(
+            public_key.to_string(),
+            "HIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw="
+        );
+        let hex: KeyBytes = public_key.to_hex().parse().unwrap();
+        assert_eq!(hex.0, public_key.0);
+
+        let generated = KeyBytes::generate_private();
+        assert_eq!(generated.0[0] & 7, 0);
+        assert_eq!(generated.0[31] & 0xc0, 0x40);
+        assert_eq!(generated.to_string().len(), 44);
+        assert!("!AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+            .parse::<KeyBytes>()
+            .is_err());
+    }
+}
@@ -1,8 +1,13 @@
+pub mod config;
 pub mod device;
 pub mod error;
 pub mod key_bytes;
 pub mod noise;
+pub mod privileges;
+pub mod secret;
+pub mod systemd;
 pub mod tun;
+pub mod uapi;
 
 // Re-export of the x25519 types
 pub mod x25519 {
@@ -4,6 +4,7 @@
 use super::{HandshakeInit, HandshakeResponse, PacketCookieReply};
 use crate::noise::errors::WireGuardError;
 use crate::noise::session::Session;
+use crate::secret::Secret;
 use crate::x25519;
 use aead::{Aead, Payload};
 use blake2::digest::{FixedOutput, KeyInit};
@@ -223,6 +224,14 @@ impl Tai64N {
     pub fn after(&self, other: &Tai64N) -> bool {
         (self.secs > other.secs) || ((self.secs == other.secs) && (self.nano > other.nano))
     }
+
+    /// The 12 bytes `parse` reads
+    fn to_bytes(&self) -> [u8; 12] {
+        let mut buf = [0u8; 12];
+        buf[..8].copy_from_slice(&self.secs.to_be_bytes());
+        buf[8..].copy_from_slice(&self.nano.to_be_bytes());
+        buf
+    }
 }
 
 /// Parameters used by the noise protocol
@@ -238,7 +247,7 @@ struct NoiseParams {
     /// A pre-computation of HASH("mac1----", peer_static_public) for this peer
     sending_mac1_key: [u8; KEY_LEN],
     /// An optional preshared key
-    preshared_key: Option<[u8; KEY_LEN]>,
+    preshared_key: Option<Secret>,
 }
 
 impl std::fmt::Debug for NoiseParams {
@@ -257,8 +266,8 @@ impl std::fmt::Debug for NoiseParams {
 struct HandshakeInitSentState {
     local_index: u32,
     hash: [u8; KEY_LEN],
-    chaining_key: [u8; KEY_LEN],
-    ephemeral_private: x25519::ReusableSecret,
+    chaining_key: Secret,
+    ephemeral_private: x25519::StaticSecret,
     time_sent: Instant,
 }
 
@@ -283,7 +292,7 @@ enum HandshakeState {
     /// Handshake initiated by peer
     InitReceived {
         hash: [u8; KEY_LEN],
-        chaining_key: [u8; KEY_LEN],
+        chaining_key: Secret,
         peer_ephemeral_public: x25519::PublicKey,
         peer_index: u32,
     },
@@ -305,6 +314,10 @@ pub struct Handshake {
     // TODO: make TimeStamper a singleton
     stamper: TimeStamper,
     pub(super) last_rtt: Option<u32>,
+    /// Keep the ephemeral private keys we generate, for a key log
+    pub(super) log_keys: bool,
+    /// Ephemeral private key of the last handshake message we formatted, if logging keys
+    pub(super) logged_ephemeral: Option<Secret>,
 }
 
 #[derive(Default)]
@@ -371,7 +384,7 @@ impl NoiseParams {
         static_private: x25519::StaticSecret,
         static_public: x25519::PublicKey,
         peer_static_public: x25519::PublicKey,
-        preshared_key: Option<[u8; 32]>,
+        preshared_key: Option<Secret>,
     ) -> Result<NoiseParams, WireGuardError> {
         let static_shared = static_private.diffie_hellman(&peer_static_public);
 
@@ -411,7 +424,7 @@ impl Handshake {
         static_public: x25519::PublicKey,
         peer_static_public: x25519::PublicKey,
         global_idx: u32,
-        preshared_key: Option<[u8; 32]>,
+        preshared_key: Option<Secret>,
     ) -> Result<Handshake, WireGuardError> {
         let params = NoiseParams::new(
             static_private,
@@ -429,6 +442,8 @@ impl Handshake {
             stamper: TimeStamper::new(),
             cookies: Default::default(),
             last_rtt: None,
+            log_keys: false,
+            logged_ephemeral: None,
         })
     }
 
@@ -468,6 +483,25 @@ impl Handshake {
         self.next_index
     }
 
+    /// The timestamp of the last handshake initiation accepted from the peer. Initiations that
+    /// are not newer are rejected as replays.
+    pub(crate) fn last_handshake_timestamp(&self) -> [u8; 12] {
+        self.last_handshake_timestamp.to_bytes()
+    }
+
+    /// Reject initiations not newer than `timestamp` too, e.g. one saved before a restart
+    pub(crate) fn restore_last_handshake_timestamp(&mut self, timestamp: &[u8; 12]) {
+        if let Ok(timestamp) = Tai64N::parse(timestamp) {
+            if timestamp.after(&self.last_handshake_timestamp) {
+                self.last_handshake_timestamp = timestamp;
+            }
+        }
+    }
+
+    pub(crate) fn set_preshared_key(&mut self, preshared_key: Option<Secret>) {
+        self.params.preshared_key = preshared_key;
+    }
+
     pub(crate) fn set_static_private(
         &mut self,
         private_key: x25519::StaticSecret,
@@ -550,7 +584,7 @@ impl Handshake {
         self.previous = std::mem::replace(
             &mut self.state,
             HandshakeState::InitReceived {
-                chaining_key,
+                chaining_key: Secret::new(chaining_key),
                 hash,
                 peer_ephemeral_public,
                 peer_index,
@@ -579,7 +613,10 @@ impl Handshake {
         // responder.hash = HASH(responder.hash || msg.unencrypted_ephemeral)
         let mut hash = b2s_hash(&state.hash, unencrypted_ephemeral.as_bytes());
         // temp = HMAC(responder.chaining_key, msg.unencrypted_ephemeral)
-        let temp = b2s_hmac(&state.chaining_key, unencrypted_ephemeral.as_bytes());
+        let temp = b2s_hmac(
+            state.chaining_key.expose(),
+            unencrypted_ephemeral.as_bytes(),
+        );
         // responder.chaining_key = HMAC(temp, 0x1)
         let mut chaining_key = b2s_hmac(&temp, &[0x01]);
         // temp = HMAC(responder.chaining_key, DH(responder.ephemeral_private, initiator.ephemeral_public))
@@ -603,7 +640,10 @@ impl Handshake {
         // temp = HMAC(responder.chaining_key, preshared_key)
         let temp = b2s_hmac(
             &chaining_key,
-            &self.params.preshared_key.unwrap_or([0u8; 32])[..],
+            self.params
+                .preshared_key
+                .as_ref()
+                .map_or(&[0u8; KEY_LEN][..], |psk| &psk.expose()[..]),
         );
         // responder.chaining_key = HMAC(temp, 0x1)
         chaining_key = b2s_hmac(&temp, &[0x01]);
@@ -726,7 +766,10 @@ impl Handshake {
         let mut hash = INITIAL_CHAIN_HASH;
         hash = b2s_hash(&hash, self.params.peer_static_public.as_bytes());
         // initiator.ephemeral_private = DH_GENERATE()
-        let ephemeral_private = x25519::ReusableSecret::random_from_rng(OsRng);
+        let ephemeral_private = x25519::StaticSecret::random_from_rng(OsRng);
+        if self.log_keys {
+            self.logged_ephemeral = Some(Secret::new(ephemeral_private.to_bytes()));
+        }
         // msg.message_type = 1
         // msg.reserved_zero = { 0, 0, 0 }
         message_type.copy_from_slice(&super::HANDSHAKE_INIT.to_le_bytes());
@@ -774,7 +817,7 @@ impl Handshake {
             &mut self.state,
             HandshakeState::InitSent(HandshakeInitSentState {
                 local_index,
-                chaining_key,
+                chaining_key: Secret::new(chaining_key),
                 hash,
                 ephemeral_private,
                 time_sent: time_now,
@@ -799,7 +842,12 @@ impl Handshake {
                 hash,
                 peer_ephemeral_public,
                 peer_index,
-            } => (chaining_key, hash, peer_ephemeral_public, peer_index),
+            } => (
+                *chaining_key.expose(),
+                hash,
+                peer_ephemeral_public,
+                peer_index,
+            ),
             _ => {
                 panic!("Unexpected attempt to call send_handshake_response");
             }
@@ -812,7 +860,10 @@ impl Handshake {
         let (encrypted_nothing, _) = rest.split_at_mut(16);
 
         // responder.ephemeral_private = DH_GENERATE()
-        let ephemeral_private = x25519::ReusableSecret::random_from_rng(OsRng);
+        let ephemeral_private = x25519::StaticSecret::random_from_rng(OsRng);
+        if self.log_keys {
+            self.logged_ephemeral = Some(Secret::new(ephemeral_private.to_bytes()));
+        }
         let local_index = self.inc_index();
         // msg.message_type = 2
         // msg.reserved_zero = { 0, 0, 0 }
@@ -847,7 +898,10 @@ impl Handshake {
         // temp = HMAC(responder.chaining_key, preshared_key)
         let temp = b2s_hmac(
             &chaining_key,
-            &self.params.preshared_key.unwrap_or([0u8; 32])[..],
+            self.params
+                .preshared_key
+                .as_ref()
+                .map_or(&[0u8; KEY_LEN][..], |psk| &psk.expose()[..]),
         );
         // responder.chaining_key = HMAC(temp, 0x1)
         chaining_key = b2s_hmac(&temp, &[0x01]);
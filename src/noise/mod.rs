@@ -4,6 +4,7 @@
 pub mod errors;
 pub mod handshake;
 pub mod rate_limiter;
+pub mod stats;
 
 mod session;
 mod timers;
@@ -11,14 +12,16 @@ mod timers;
 use crate::noise::errors::WireGuardError;
 use crate::noise::handshake::Handshake;
 use crate::noise::rate_limiter::RateLimiter;
+use crate::noise::stats::PeerStats;
 use crate::noise::timers::{TimerName, Timers};
+use crate::secret::Secret;
 use crate::x25519;
 
 use std::collections::VecDeque;
 use std::convert::{TryFrom, TryInto};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::SystemTime;
 
 /// The default value to use for rate limiting, when no other rate limiter is defined
 const PEER_HANDSHAKE_RATE_LIMIT: u64 = 10;
@@ -68,8 +71,7 @@ pub struct Tunn {
     packet_queue: VecDeque<Vec<u8>>,
     /// Keeps tabs on the expiring timers
     timers: timers::Timers,
-    tx_bytes: usize,
-    rx_bytes: usize,
+    stats: PeerStats,
     rate_limiter: Arc<RateLimiter>,
 }
 
@@ -123,9 +125,21 @@ pub enum Packet<'a> {
     PacketData(PacketData<'a>),
 }
 
+impl Packet<'_> {
+    /// Size of the message on the wire
+    fn wire_len(&self) -> usize {
+        match self {
+            Packet::HandshakeInit(_) => HANDSHAKE_INIT_SZ,
+            Packet::HandshakeResponse(_) => HANDSHAKE_RESP_SZ,
+            Packet::PacketCookieReply(_) => COOKIE_REPLY_SZ,
+            Packet::PacketData(p) => session::DATA_OFFSET + p.encrypted_encapsulated_packet.len(),
+        }
+    }
+}
+
 impl Tunn {
     #[inline(always)]
-    pub fn parse_incoming_packet(src: &[u8]) -> Result<Packet, WireGuardError> {
+    pub fn parse_incoming_packet(src: &[u8]) -> Result<Packet<'_>, WireGuardError> {
         if src.len() < 4 {
             return Err(WireGuardError::InvalidPacket);
         }
@@ -194,7 +208,7 @@ impl Tunn {
     pub fn new(
         static_private: x25519::StaticSecret,
         peer_static_public: x25519::PublicKey,
-        preshared_key: Option<[u8; 32]>,
+        preshared_key: Option<Secret>,
         persistent_keepalive: Option<u16>,
         index: u32,
         rate_limiter: Option<Arc<RateLimiter>>,
@@ -212,8 +226,7 @@ impl Tunn {
             .map_err(|_| "Invalid parameters")?,
             sessions: Default::default(),
             current: Default::default(),
-            tx_bytes: Default::default(),
-            rx_bytes: Default::default(),
+            stats: Default::default(),
 
             packet_queue: VecDeque::new(),
             timers: Timers::new(persistent_keepalive, rate_limiter.is_none()),
@@ -245,6 +258,35 @@ impl Tunn {
         Ok(())
     }
 
+    /// Use a new preshared key from the next handshake on
+    pub fn set_preshared_key(&mut self, preshared_key: Option<Secret>) {
+        self.handshake.set_preshared_key(preshared_key);
+    }
+
+    /// The TAI64N timestamp of the last handshake initiation accepted from the peer, older
+    /// ones are replays
+    pub fn last_handshake_timestamp(&self) -> [u8; 12] {
+        self.handshake.last_handshake_timestamp()
+    }
+
+    /// Keep rejecting the initiations not newer than `timestamp`, saved before a restart
+    pub fn restore_last_handshake_timestamp(&mut self, timestamp: &[u8; 12]) {
+        self.handshake.restore_last_handshake_timestamp(timestamp);
+    }
+
+    /// Carry on counting from the traffic and handshake counters of `saved`
+    pub fn restore_counters(&mut self, saved: &PeerStats) {
+        let stats = &mut self.stats;
+        stats.tx_bytes += saved.tx_bytes;
+        stats.rx_bytes += saved.rx_bytes;
+        stats.tx_wire_bytes += saved.tx_wire_bytes;
+        stats.rx_wire_bytes += saved.rx_wire_bytes;
+        stats.tx_packets += saved.tx_packets;
+        stats.rx_packets += saved.rx_packets;
+        stats.handshake_attempts += saved.handshake_attempts;
+        stats.handshake_failures += saved.handshake_failures;
+    }
+
     /// Encapsulate a single packet from the tunnel interface.
     /// Returns TunnResult.
     ///
@@ -261,7 +303,9 @@ impl Tunn {
             if !src.is_empty() {
                 self.timer_tick(TimerName::TimeLastDataPacketSent);
             }
-            self.tx_bytes += src.len();
+            self.stats.tx_bytes += src.len() as u64;
+            self.stats.tx_packets += 1;
+            self.stats.tx_wire_bytes += packet.len() as u64;
             return TunnResult::WriteToNetwork(packet);
         }
 
@@ -295,8 +339,9 @@ impl Tunn {
         {
             Ok(packet) => packet,
             Err(TunnResult::WriteToNetwork(cookie)) => {
+                self.mark_cookie_reply_sent();
                 dst[..cookie.len()].copy_from_slice(cookie);
-                return TunnResult::WriteToNetwork(&mut dst[..cookie.len()]);
+                return TunnResult::WriteToNetwork(&dst[..cookie.len()]);
             }
             Err(TunnResult::Err(e)) => return TunnResult::Err(e),
             _ => unreachable!(),
@@ -310,13 +355,40 @@ impl Tunn {
         packet: Packet,
         dst: &'a mut [u8],
     ) -> TunnResult<'a> {
-        match packet {
+        self.stats.rx_wire_bytes += packet.wire_len() as u64;
+        let is_handshake = matches!(
+            packet,
+            Packet::HandshakeInit(_) | Packet::HandshakeResponse(_)
+        );
+        let result = match packet {
             Packet::HandshakeInit(p) => self.handle_handshake_init(p, dst),
             Packet::HandshakeResponse(p) => self.handle_handshake_response(p, dst),
             Packet::PacketCookieReply(p) => self.handle_cookie_reply(p),
             Packet::PacketData(p) => self.handle_data(p, dst),
+        };
+        match result {
+            Ok(TunnResult::WriteToNetwork(packet)) => {
+                self.stats.tx_wire_bytes += packet.len() as u64;
+                TunnResult::WriteToNetwork(packet)
+            }
+            Ok(result) => result,
+            Err(e) => {
+                match e {
+                    _ if is_handshake => self.stats.handshake_failures += 1,
+                    WireGuardError::DuplicateCounter | WireGuardError::InvalidCounter => {
+                        self.stats.replay_failures += 1
+                    }
+                    WireGuardError::InvalidAeadTag => self.stats.aead_failures += 1,
+                    _ => {}
+                }
+                TunnResult::from(e)
+            }
         }
-        .unwrap_or_else(TunnResult::from)
+    }
+
+    fn mark_cookie_reply_sent(&mut self) {
+        self.stats.cookie_replies_sent += 1;
+        self.stats.tx_wire_bytes += COOKIE_REPLY_SZ as u64;
     }
 
     fn handle_handshake_init<'a>(
@@ -358,6 +430,7 @@ impl Tunn {
         let session = self.handshake.receive_handshake_response(p)?;
 
         let keepalive_packet = session.format_packet_data(&[], dst);
+        self.stats.tx_packets += 1;
         // Store new session in ring buffer
         let l_idx = session.local_index();
         let index = l_idx % N_SESSIONS;
@@ -384,6 +457,7 @@ impl Tunn {
         self.handshake.receive_cookie_reply(p)?;
         self.timer_tick(TimerName::TimeLastPacketReceived);
         self.timer_tick(TimerName::TimeCookieReceived);
+        self.stats.cookie_replies_received += 1;
 
         tracing::debug!("Did set cookie");
 
@@ -428,6 +502,7 @@ impl Tunn {
         self.set_current_session(r_idx);
 
         self.timer_tick(TimerName::TimeLastPacketReceived);
+        self.stats.rx_packets += 1;
 
         Ok(self.validate_decapsulated_packet(decapsulated_packet))
     }
@@ -457,6 +532,8 @@ impl Tunn {
                     self.timer_tick(TimerName::TimeLastHandshakeStarted);
                 }
                 self.timer_tick(TimerName::TimeLastPacketSent);
+                self.stats.handshake_attempts += 1;
+                self.stats.tx_wire_bytes += packet.len() as u64;
                 TunnResult::WriteToNetwork(packet)
             }
             Err(e) => TunnResult::Err(e),
@@ -502,11 +579,11 @@ impl Tunn {
         }
 
         self.timer_tick(TimerName::TimeLastDataPacketReceived);
-        self.rx_bytes += computed_len;
+        self.stats.rx_bytes += computed_len as u64;
 
         match src_ip_address {
-            IpAddr::V4(addr) => TunnResult::WriteToTunnelV4(&mut packet[..computed_len], addr),
-            IpAddr::V6(addr) => TunnResult::WriteToTunnelV6(&mut packet[..computed_len], addr),
+            IpAddr::V4(addr) => TunnResult::WriteToTunnelV4(&packet[..computed_len], addr),
+            IpAddr::V6(addr) => TunnResult::WriteToTunnelV6(&packet[..computed_len], addr),
         }
     }
 
@@ -529,6 +606,8 @@ impl Tunn {
         if self.packet_queue.len() < MAX_QUEUE_DEPTH {
             // Drop if too many are already in queue
             self.packet_queue.push_back(packet.to_vec());
+        } else {
+            self.stats.queue_drops += 1;
         }
     }
 
@@ -537,6 +616,8 @@ impl Tunn {
         if self.packet_queue.len() < MAX_QUEUE_DEPTH {
             // Drop if too many are already in queue
             self.packet_queue.push_front(packet);
+        } else {
+            self.stats.queue_drops += 1;
         }
     }
 
@@ -574,18 +655,50 @@ impl Tunn {
         }
     }
 
-    /// Return stats from the tunnel:
-    /// * Time since last handshake in seconds
-    /// * Data bytes sent
-    /// * Data bytes received
-    pub fn stats(&self) -> (Option<Duration>, usize, usize, f32, Option<u32>) {
-        let time = self.time_since_last_handshake();
-        let tx_bytes = self.tx_bytes;
-        let rx_bytes = self.rx_bytes;
-        let loss = self.estimate_loss();
-        let rtt = self.handshake.last_rtt;
+    /// The index of the session in use. It changes once a handshake is confirmed, on receipt of
+    /// the response by the initiator and of the first data packet by the responder.
+    pub fn current_session(&self) -> Option<usize> {
+        self.sessions[self.current % N_SESSIONS]
+            .as_ref()
+            .filter(|session| session.local_index() == self.current)
+            .map(|_| self.current)
+    }
+
+    /// Keep the ephemeral private keys of the following handshakes for
+    /// [`take_logged_ephemeral`](Self::take_logged_ephemeral)
+    pub fn set_key_log(&mut self, enabled: bool) {
+        self.handshake.log_keys = enabled;
+        if !enabled {
+            self.handshake.logged_ephemeral = None;
+        }
+    }
+
+    /// The ephemeral private key of the last handshake message formatted while key logging
+    pub fn take_logged_ephemeral(&mut self) -> Option<Secret> {
+        self.handshake.logged_ephemeral.take()
+    }
+
+    /// Packets dropped so far because the queue waiting for a session was full
+    pub fn queue_drops(&self) -> u64 {
+        self.stats.queue_drops
+    }
+
+    /// Return stats from the tunnel
+    pub fn stats(&self) -> PeerStats {
+        let time_since_last_handshake = self.time_since_last_handshake();
+        PeerStats {
+            last_handshake: time_since_last_handshake
+                .and_then(|elapsed| SystemTime::now().checked_sub(elapsed)),
+            time_since_last_handshake,
+            estimated_loss: self.estimate_loss(),
+            rtt: self.handshake.last_rtt,
+            ..self.stats.clone()
+        }
+    }
 
-        (time, tx_bytes, rx_bytes, loss, rtt)
+    /// Count a handshake that was given up on
+    fn mark_handshake_failed(&mut self) {
+        self.stats.handshake_failures += 1;
     }
 }
 
@@ -726,6 +839,47 @@ mod tests {
         assert!(matches!(packet, Packet::PacketData(_)));
     }
 
+    #[test]
+    fn session_confirmation() {
+        let (mut my_tun, mut their_tun) = create_two_tuns();
+        let init = create_handshake_init(&mut my_tun);
+        let resp = create_handshake_response(&mut their_tun, &init);
+        // The responder waits for the initiator to use the session
+        assert_eq!(their_tun.current_session(), None);
+        let keepalive = parse_handshake_resp(&mut my_tun, &resp);
+        assert!(my_tun.current_session().is_some());
+        parse_keepalive(&mut their_tun, &keepalive);
+        assert!(their_tun.current_session().is_some());
+    }
+
+    #[test]
+    fn restored_timestamp_rejects_replay() {
+        let my_secret_key = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+        let my_public_key = x25519_dalek::PublicKey::from(&my_secret_key);
+        let their_secret_key = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+        let their_public_key = x25519_dalek::PublicKey::from(&their_secret_key);
+        let new_their_tun =
+            || Tunn::new(their_secret_key.clone(), my_public_key, None, None, 1, None).unwrap();
+
+        let mut my_tun = Tunn::new(my_secret_key, their_public_key, None, None, 2, None).unwrap();
+        let mut their_tun = new_their_tun();
+        let init = create_handshake_init(&mut my_tun);
+        create_handshake_response(&mut their_tun, &init);
+        let timestamp = their_tun.last_handshake_timestamp();
+        assert_ne!(timestamp, [0; 12]);
+
+        // A restarted peer that forgot the timestamp accepts the replayed initiation
+        create_handshake_response(&mut new_their_tun(), &init);
+
+        let mut restarted = new_their_tun();
+        restarted.restore_last_handshake_timestamp(&timestamp);
+        let mut dst = vec![0u8; 2048];
+        assert!(matches!(
+            restarted.decapsulate(None, &init, &mut dst),
+            TunnResult::Err(WireGuardError::WrongTai64nTimestamp)
+        ));
+    }
+
     #[test]
     fn full_handshake_plus_timers() {
         let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
@@ -796,4 +950,42 @@ mod tests {
         };
         assert_eq!(sent_packet_buf, recv_packet_buf);
     }
+
+    #[test]
+    fn stats_count_messages() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let mut my_dst = [0u8; 1024];
+        let mut their_dst = [0u8; 1024];
+
+        let sent_packet_buf = create_ipv4_udp_packet();
+        let data = match my_tun.encapsulate(&sent_packet_buf, &mut my_dst) {
+            TunnResult::WriteToNetwork(sent) => sent.to_vec(),
+            _ => unreachable!(),
+        };
+        their_tun.decapsulate(None, &data, &mut their_dst);
+        // The same message again is a replay
+        their_tun.decapsulate(None, &data, &mut their_dst);
+        let mut tampered = data.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        let counter = u64::from_le_bytes(tampered[8..16].try_into().unwrap()) + 1;
+        tampered[8..16].copy_from_slice(&counter.to_le_bytes());
+        their_tun.decapsulate(None, &tampered, &mut their_dst);
+
+        let mine = my_tun.stats();
+        assert_eq!(mine.handshake_attempts, 1);
+        assert_eq!(mine.tx_bytes, sent_packet_buf.len() as u64);
+        // The keepalive that completed the handshake, then the packet
+        assert_eq!(mine.tx_packets, 2);
+        assert!(mine.last_handshake.is_some());
+
+        let theirs = their_tun.stats();
+        assert_eq!(theirs.rx_bytes, sent_packet_buf.len() as u64);
+        assert_eq!(theirs.rx_packets, 2);
+        assert_eq!(theirs.replay_failures, 1);
+        assert_eq!(theirs.aead_failures, 1);
+        assert_eq!(
+            theirs.rx_wire_bytes,
+            (HANDSHAKE_INIT_SZ + DATA_OVERHEAD_SZ + 3 * data.len()) as u64
+        );
+    }
 }
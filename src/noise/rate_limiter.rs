@@ -1,6 +1,7 @@
 use super::handshake::{b2s_hash, b2s_keyed_mac_16, b2s_keyed_mac_16_2, b2s_mac_24};
 use crate::noise::handshake::{LABEL_COOKIE, LABEL_MAC1};
 use crate::noise::{HandshakeInit, HandshakeResponse, Packet, Tunn, TunnResult, WireGuardError};
+use crate::secret::Secret;
 
 use std::net::IpAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
@@ -25,6 +26,7 @@ type Cookie = [u8; COOKIE_SIZE];
 /// There are two places where WireGuard requires "randomness" for cookies
 /// * The 24 byte nonce in the cookie massage - here the only goal is to avoid nonce reuse
 /// * A secret value that changes every two minutes
+///
 /// Because the main goal of the cookie is simply for a party to prove ownership of an IP address
 /// we can relax the randomness definition a bit, in order to avoid locking, because using less
 /// resources is the main goal of any DoS prevention mechanism.
@@ -32,9 +34,9 @@ type Cookie = [u8; COOKIE_SIZE];
 /// some counters.
 pub struct RateLimiter {
     /// The key we use to derive the nonce
-    nonce_key: [u8; 32],
+    nonce_key: Secret,
     /// The key we use to derive the cookie
-    secret_key: [u8; 16],
+    secret_key: Secret<16>,
     start_time: Instant,
     /// A single 64 bit counter (should suffice for many years)
     nonce_ctr: AtomicU64,
@@ -43,6 +45,8 @@ pub struct RateLimiter {
     limit: u64,
     /// The counter since last reset
     count: AtomicU64,
+    /// Handshake messages that arrived while under load, never reset
+    under_load: AtomicU64,
     /// The time last reset was performed on this rate limiter
     last_reset: Mutex<Instant>,
 }
@@ -52,14 +56,15 @@ impl RateLimiter {
         let mut secret_key = [0u8; 16];
         OsRng.fill_bytes(&mut secret_key);
         RateLimiter {
-            nonce_key: Self::rand_bytes(),
-            secret_key,
+            nonce_key: Secret::new(Self::rand_bytes()),
+            secret_key: Secret::new(secret_key),
             start_time: Instant::now(),
             nonce_ctr: AtomicU64::new(0),
             mac1_key: b2s_hash(LABEL_MAC1, public_key.as_bytes()),
             cookie_key: b2s_hash(LABEL_COOKIE, public_key.as_bytes()).into(),
             limit,
             count: AtomicU64::new(0),
+            under_load: AtomicU64::new(0),
             last_reset: Mutex::new(Instant::now()),
         }
     }
@@ -95,17 +100,30 @@ impl RateLimiter {
         let cur_counter = Instant::now().duration_since(self.start_time).as_secs() / COOKIE_REFRESH;
 
         // Next we derive the cookie
-        b2s_keyed_mac_16_2(&self.secret_key, &cur_counter.to_le_bytes(), &addr_bytes)
+        b2s_keyed_mac_16_2(
+            self.secret_key.expose(),
+            &cur_counter.to_le_bytes(),
+            &addr_bytes,
+        )
     }
 
     fn nonce(&self) -> [u8; COOKIE_NONCE_SIZE] {
         let ctr = self.nonce_ctr.fetch_add(1, Ordering::Relaxed);
 
-        b2s_mac_24(&self.nonce_key, &ctr.to_le_bytes())
+        b2s_mac_24(self.nonce_key.expose(), &ctr.to_le_bytes())
     }
 
     fn is_under_load(&self) -> bool {
-        self.count.fetch_add(1, Ordering::SeqCst) >= self.limit
+        let under_load = self.count.fetch_add(1, Ordering::SeqCst) >= self.limit;
+        if under_load {
+            self.under_load.fetch_add(1, Ordering::Relaxed);
+        }
+        under_load
+    }
+
+    /// Number of handshake messages that arrived while under load
+    pub fn under_load_events(&self) -> u64 {
+        self.under_load.load(Ordering::Relaxed)
     }
 
     pub(crate) fn format_cookie_reply<'a>(
@@ -27,7 +27,7 @@ impl std::fmt::Debug for Session {
 }
 
 /// Where encrypted data resides in a data packet
-const DATA_OFFSET: usize = 16;
+pub(super) const DATA_OFFSET: usize = 16;
 /// The overhead of the AEAD
 const AEAD_SIZE: usize = 16;
 
@@ -129,7 +129,7 @@ impl ReceivingKeyCounterValidator {
             }
         } else {
             let mut i = self.next;
-            while i % WORD_SIZE != 0 && i < counter {
+            while !i.is_multiple_of(WORD_SIZE) && i < counter {
                 // Clear until i aligned to word size
                 self.clear_bit(i);
                 i += 1;
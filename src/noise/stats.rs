@@ -0,0 +1,35 @@
+use std::time::{Duration, SystemTime};
+
+/// Statistics of a single tunnel, see [`Tunn::stats`](super::Tunn::stats)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PeerStats {
+    /// Wall-clock time the current session was established
+    pub last_handshake: Option<SystemTime>,
+    pub time_since_last_handshake: Option<Duration>,
+    /// Decrypted payload bytes, excluding WireGuard overhead
+    pub tx_bytes: u64,
+    pub rx_bytes: u64,
+    /// Bytes of all WireGuard messages exchanged, handshakes included
+    pub tx_wire_bytes: u64,
+    pub rx_wire_bytes: u64,
+    /// Transport data messages, keepalives included
+    pub tx_packets: u64,
+    pub rx_packets: u64,
+    /// Handshake initiations sent, retransmissions included
+    pub handshake_attempts: u64,
+    /// Handshake messages that failed validation and handshakes given up on
+    pub handshake_failures: u64,
+    /// Cookie replies sent by the tunnel's own rate limiter, those a device sends before it
+    /// knows which peer a handshake is from are not counted here
+    pub cookie_replies_sent: u64,
+    pub cookie_replies_received: u64,
+    /// Data messages dropped as replayed or too old
+    pub replay_failures: u64,
+    /// Data messages that failed to decrypt
+    pub aead_failures: u64,
+    /// Packets dropped because the queue waiting for a session was full
+    pub queue_drops: u64,
+    pub estimated_loss: f32,
+    /// Round trip time of the last handshake, in milliseconds
+    pub rtt: Option<u32>,
+}
@@ -133,7 +133,7 @@ impl Tunn {
 
     // We don't really clear the timers, but we set them to the current time to
     // so the reference time frame is the same
-    fn clear_all(&mut self) {
+    pub(crate) fn clear_all(&mut self) {
         for session in &mut self.sessions {
             *session = None;
         }
@@ -214,6 +214,7 @@ impl Tunn {
                     // up to be sent. If a packet is explicitly queued up to be sent, then
                     // this timer is reset.
                     tracing::error!("CONNECTION_EXPIRED(REKEY_ATTEMPT_TIME)");
+                    self.mark_handshake_failed();
                     self.handshake.set_expired();
                     self.clear_all();
                     return TunnResult::Err(WireGuardError::ConnectionExpired);
@@ -317,6 +318,18 @@ impl Tunn {
         }
     }
 
+    /// Time since a packet from the peer was last authenticated, or since the tunnel started
+    pub fn time_since_last_received(&self) -> Duration {
+        Instant::now()
+            .duration_since(self.timers.time_started)
+            .saturating_sub(self.timers[TimeLastPacketReceived])
+    }
+
+    /// Change the persistent keepalive interval in seconds, 0 disables it
+    pub fn set_persistent_keepalive(&mut self, keepalive: u16) {
+        self.timers.persistent_keepalive = usize::from(keepalive);
+    }
+
     pub fn persistent_keepalive(&self) -> Option<u16> {
         let keepalive = self.timers.persistent_keepalive;
 
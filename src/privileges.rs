@@ -0,0 +1,316 @@
+//! Giving up root once the interface and sockets are open: switching to another user and
+//! group, narrowing the capabilities and restricting the system calls.
+//!
+//! Capabilities are per thread, so [`drop_privileges`] only keeps them on the calling thread.
+//! Other threads running at that point lose all of them when the user changes.
+
+use std::{ffi::CString, fmt, io, str::FromStr};
+
+use crate::error::{WgError, WgResult};
+
+/// A capability that can be kept after switching user
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    /// Reopening the listen port below 1024
+    NetBindService,
+    /// Changing the fwmark
+    NetAdmin,
+}
+
+impl Capability {
+    fn number(self) -> u32 {
+        match self {
+            Capability::NetBindService => 10,
+            Capability::NetAdmin => 12,
+        }
+    }
+}
+
+impl FromStr for Capability {
+    type Err = WgError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().trim_start_matches("cap_") {
+            "net_bind_service" => Ok(Capability::NetBindService),
+            "net_admin" => Ok(Capability::NetAdmin),
+            _ => Err(WgError::InvalidValue(format!("capability {s}"))),
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Capability::NetBindService => write!(f, "net_bind_service"),
+            Capability::NetAdmin => write!(f, "net_admin"),
+        }
+    }
+}
+
+/// Where [`drop_privileges`] switches to
+#[derive(Clone, Debug, Default)]
+pub struct Privileges {
+    /// User name or id
+    pub user: Option<String>,
+    /// Group name or id, the primary group of `user` when not given
+    pub group: Option<String>,
+    /// Capabilities kept after switching user, none by default
+    pub keep: Vec<Capability>,
+}
+
+/// Switch to the user and group of `privileges`, keeping only its capabilities. The bounding
+/// set is reduced to the same capabilities, and the supplementary groups are cleared.
+pub fn drop_privileges(privileges: &Privileges) -> WgResult<()> {
+    let user = privileges.user.as_deref().map(lookup_user).transpose()?;
+    let gid = match privileges.group.as_deref() {
+        Some(group) => Some(lookup_group(group)?),
+        None => user.map(|(_, gid)| gid),
+    };
+    if user.is_some() {
+        for cap in 0..=last_capability() {
+            if privileges.keep.iter().any(|keep| keep.number() == cap) {
+                continue;
+            }
+            if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) } != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+        let keep_caps = !privileges.keep.is_empty() as libc::c_ulong;
+        if unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, keep_caps, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    if let Some(gid) = gid {
+        if unsafe { libc::setgroups(1, &gid) } != 0 || unsafe { libc::setgid(gid) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    if let Some((uid, _)) = user {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        set_capabilities(&privileges.keep)?;
+        tracing::info!(message = "Dropped privileges", uid, gid, keep = ?privileges.keep);
+    }
+    Ok(())
+}
+
+/// The uid and primary gid of `user`, a name or an id
+pub fn lookup_user(user: &str) -> WgResult<(libc::uid_t, libc::gid_t)> {
+    let name = CString::new(user).map_err(|_| WgError::InvalidValue(format!("user {user}")))?;
+    let passwd = match user.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe { libc::getpwuid(uid) },
+        Err(_) => unsafe { libc::getpwnam(name.as_ptr()) },
+    };
+    match unsafe { passwd.as_ref() } {
+        Some(passwd) => Ok((passwd.pw_uid, passwd.pw_gid)),
+        None => Err(WgError::InvalidValue(format!("user {user}, unknown"))),
+    }
+}
+
+/// The gid of `group`, a name or an id
+pub fn lookup_group(group: &str) -> WgResult<libc::gid_t> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).map_err(|_| WgError::InvalidValue(format!("group {group}")))?;
+    match unsafe { libc::getgrnam(name.as_ptr()).as_ref() } {
+        Some(entry) => Ok(entry.gr_gid),
+        None => Err(WgError::InvalidValue(format!("group {group}, unknown"))),
+    }
+}
+
+/// The highest capability the kernel knows about
+fn last_capability() -> u32 {
+    std::fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+        .ok()
+        .and_then(|last| last.trim().parse().ok())
+        .unwrap_or(40)
+}
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Make `keep` the permitted and effective capabilities of the calling thread
+fn set_capabilities(keep: &[Capability]) -> WgResult<()> {
+    let header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    for cap in keep {
+        let set = &mut data[cap.number() as usize / 32];
+        set.permitted |= 1 << (cap.number() % 32);
+        set.effective |= 1 << (cap.number() % 32);
+    }
+    if unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// System calls the device never makes, refused with `EPERM` by [`apply_seccomp`]
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_reboot,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+];
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// Syscalls of the x32 ABI, which has its own numbers
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// The BPF program refusing [`DENIED_SYSCALLS`], killing the process on another architecture
+fn seccomp_filter(arch: u32) -> Vec<libc::sock_filter> {
+    let stmt = |code: u32, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump = |code: u32, k: u32, jt: usize| libc::sock_filter {
+        code: code as u16,
+        jt: jt as u8,
+        jf: 0,
+        k,
+    };
+    // Offsets in `struct seccomp_data`
+    let (nr, arch_offset) = (0, 4);
+
+    let mut filter = vec![
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, arch_offset),
+        jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, arch, 1),
+        stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, nr),
+    ];
+    // Every jump lands on the last instruction, which refuses the call
+    let remaining = DENIED_SYSCALLS.len() + 1;
+    filter.push(jump(
+        libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+        X32_SYSCALL_BIT,
+        remaining,
+    ));
+    for (i, syscall) in DENIED_SYSCALLS.iter().enumerate() {
+        filter.push(jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            *syscall as u32,
+            remaining - i - 1,
+        ));
+    }
+    filter.push(stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+    filter.push(stmt(
+        libc::BPF_RET | libc::BPF_K,
+        libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+    ));
+    filter
+}
+
+/// Refuse the system calls a compromised device could use to take over the host, such as
+/// running programs, tracing processes or loading kernel modules, on every thread. Only
+/// supported on x86_64 and aarch64.
+pub fn apply_seccomp() -> WgResult<()> {
+    let arch = AUDIT_ARCH.ok_or_else(|| {
+        WgError::IO(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "seccomp filter not supported on this architecture",
+        ))
+    })?;
+    let mut filter = seccomp_filter(arch);
+    let program = libc::sock_fprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_mut_ptr(),
+    };
+    // Also lets an unprivileged process install the filter
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &program,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    tracing::info!(
+        message = "Applied the seccomp filter",
+        denied = DENIED_SYSCALLS.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_capabilities() {
+        assert_eq!(
+            "CAP_NET_BIND_SERVICE".parse::<Capability>().unwrap(),
+            Capability::NetBindService
+        );
+        assert_eq!(
+            "net_admin".parse::<Capability>().unwrap(),
+            Capability::NetAdmin
+        );
+        assert!("sys_admin".parse::<Capability>().is_err());
+        assert_eq!(Capability::NetAdmin.to_string(), "net_admin");
+    }
+
+    #[test]
+    fn seccomp_filter_jumps() {
+        let filter = seccomp_filter(0xc000_003e);
+        let last = filter.len() - 1;
+        assert_eq!(filter[last].k, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32);
+        assert_eq!(filter[last - 1].k, libc::SECCOMP_RET_ALLOW);
+        // Every syscall check, and the x32 one, jumps to the refusal
+        for (i, instruction) in filter.iter().enumerate().take(last - 1).skip(4) {
+            assert_eq!(i + 1 + instruction.jt as usize, last);
+        }
+        let denied: Vec<_> = filter[5..last - 1].iter().map(|i| i.k as i64).collect();
+        assert_eq!(denied, DENIED_SYSCALLS);
+    }
+}
@@ -0,0 +1,57 @@
+//! Key material that is wiped from memory once it is no longer used.
+
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// Secret bytes, zeroized on drop and redacted from `Debug` output
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct Secret<const N: usize = 32>([u8; N]);
+
+impl<const N: usize> Secret<N> {
+    pub fn new(bytes: [u8; N]) -> Self {
+        Secret(bytes)
+    }
+
+    /// Whether all bytes are zero, which stands for no key in the UAPI
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|b| *b == 0)
+    }
+
+    /// The secret bytes, keep copies of them short lived
+    pub fn expose(&self) -> &[u8; N] {
+        &self.0
+    }
+}
+
+impl<const N: usize> From<[u8; N]> for Secret<N> {
+    fn from(bytes: [u8; N]) -> Self {
+        Secret(bytes)
+    }
+}
+
+/// Compared in constant time
+impl<const N: usize> PartialEq for Secret<N> {
+    fn eq(&self, other: &Self) -> bool {
+        ring::constant_time::verify_slices_are_equal(&self.0, &other.0).is_ok()
+    }
+}
+
+impl<const N: usize> Eq for Secret<N> {}
+
+impl<const N: usize> std::fmt::Debug for Secret<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacted() {
+        let secret = Secret::new([7u8; 32]);
+        assert_eq!(format!("{:?}", Some(secret.clone())), "Some(<redacted>)");
+        assert_eq!(secret.expose(), &[7u8; 32]);
+        assert_ne!(secret, Secret::new([8u8; 32]));
+    }
+}
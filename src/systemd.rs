@@ -0,0 +1,182 @@
+//! Running under systemd: the sockets it binds for socket activation (`LISTEN_FDS`) and the
+//! notifications telling it the service is ready, reloading or stopping, and still alive.
+//!
+//! ```ini
+//! # wg-rs@.socket
+//! [Socket]
+//! ListenDatagram=51820
+//! ListenStream=/run/wireguard/%i.sock
+//! ```
+
+use std::{
+    ffi::OsStr,
+    io,
+    net::{TcpListener, UdpSocket},
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram, UnixListener},
+        },
+    },
+    time::Duration,
+};
+
+use socket2::{Domain, Socket, Type};
+
+use crate::error::{WgError, WgResult};
+
+/// The first descriptor passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The sockets systemd passed, by what the device uses them for
+#[derive(Debug, Default)]
+pub struct ActivatedSockets {
+    /// For [`Device::use_listen_sockets`](crate::device::Device::use_listen_sockets)
+    pub udp: Vec<UdpSocket>,
+    pub tcp: Vec<TcpListener>,
+    /// The control socket
+    pub uapi: Option<UnixListener>,
+}
+
+/// Take the sockets systemd passed to this process, none when it was not socket activated.
+/// The variables are removed so they do not apply to a child, which also means this must be
+/// called before forking.
+pub fn activated_sockets() -> WgResult<ActivatedSockets> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(ActivatedSockets::default());
+    };
+    if pid.parse() != Ok(std::process::id()) {
+        return Ok(ActivatedSockets::default());
+    }
+    let count: RawFd = fds
+        .parse()
+        .map_err(|_| WgError::InvalidValue(format!("LISTEN_FDS {fds}")))?;
+    let fds = (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: passed to this process for it to own
+            Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    classify(fds)
+}
+
+fn classify(fds: Vec<OwnedFd>) -> WgResult<ActivatedSockets> {
+    let mut sockets = ActivatedSockets::default();
+    for fd in fds {
+        let socket = Socket::from(fd);
+        let (domain, kind) = (socket.domain()?, socket.r#type()?);
+        match domain {
+            Domain::IPV4 | Domain::IPV6 if kind == Type::DGRAM => sockets.udp.push(socket.into()),
+            Domain::IPV4 | Domain::IPV6 if kind == Type::STREAM => sockets.tcp.push(socket.into()),
+            Domain::UNIX if kind == Type::STREAM && sockets.uapi.is_none() => {
+                sockets.uapi = Some(socket.into())
+            }
+            _ => {
+                return Err(WgError::InvalidValue(format!(
+                    "activated socket {domain:?} {kind:?}, not UDP, TCP or a single control socket"
+                )))
+            }
+        }
+    }
+    Ok(sockets)
+}
+
+/// Send `state` to systemd, such as `READY=1` or `STOPPING=1`. Does nothing when not started
+/// by systemd with `Type=notify`.
+pub fn notify(state: &str) -> io::Result<()> {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => notify_to(&path, state),
+        None => Ok(()),
+    }
+}
+
+fn notify_to(path: &OsStr, state: &str) -> io::Result<()> {
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/// How often systemd expects `WATCHDOG=1` when `WatchdogSec=` is set for this process
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec)).filter(|interval| !interval.is_zero())
+}
+
+/// `CLOCK_MONOTONIC` in microseconds, which systemd wants with `RELOADING=1`
+pub fn monotonic_usec() -> u64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_sockets() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dir = std::env::temp_dir().join(format!("wg-rs-activated-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wg0.sock");
+        let _ = std::fs::remove_file(&path);
+        let uapi = UnixListener::bind(&path).unwrap();
+
+        let sockets = classify(vec![udp.into(), tcp.into(), uapi.into()]).unwrap();
+        assert_eq!(sockets.udp.len(), 1);
+        assert_eq!(sockets.tcp.len(), 1);
+        let uapi = sockets.uapi.unwrap();
+        assert_eq!(
+            uapi.local_addr().unwrap().as_pathname(),
+            Some(path.as_path())
+        );
+
+        // Only one control socket
+        let other = UnixListener::bind(dir.join("wg1.sock")).unwrap();
+        assert!(classify(vec![uapi.into(), other.into()]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn notify_socket() {
+        let dir = std::env::temp_dir().join(format!("wg-rs-notify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        notify_to(path.as_os_str(), "READY=1\nSTATUS=up").unwrap();
+        let mut buf = [0; 64];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1\nSTATUS=up");
+
+        let abstract_name = format!("@wg-rs-notify-{}", std::process::id());
+        let receiver =
+            UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&abstract_name[1..]).unwrap())
+                .unwrap();
+        notify_to(OsStr::new(&abstract_name), "WATCHDOG=1").unwrap();
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"WATCHDOG=1");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
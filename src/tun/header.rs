@@ -47,6 +47,29 @@ use std::net::IpAddr;
 // |                                                               |
 // +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
 
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+pub const PROTOCOL_ICMPV6: u8 = 58;
+pub const PROTOCOL_SCTP: u8 = 132;
+
+// IPv6 extension headers
+const HOP_BY_HOP: u8 = 0;
+const ROUTING: u8 = 43;
+const FRAGMENT: u8 = 44;
+const AUTHENTICATION: u8 = 51;
+const DESTINATION_OPTIONS: u8 = 60;
+
+/// What follows the IP header and any IPv6 extension headers
+enum Payload<'a> {
+    /// The transport header and data
+    Transport(&'a [u8]),
+    /// A fragment other than the first, without the transport header
+    LaterFragment,
+    /// An extension header is cut short
+    Truncated,
+}
+
 #[derive(Debug)]
 pub struct IpHeader<'a> {
     pub version: u8,
@@ -80,6 +103,77 @@ impl<'a> IpHeader<'a> {
         }
     }
 
+    /// IPv4 protocol, or the IPv6 next header after the extension headers
+    pub fn protocol(&self) -> u8 {
+        self.upper_layer().0
+    }
+
+    /// The transport protocol and what follows the headers before it
+    fn upper_layer(&self) -> (u8, Payload<'a>) {
+        if self.version == 4 {
+            let offset = ((self.data[0] & 0x0f) as usize * 4).max(20);
+            let fragment_offset = u16::from_be_bytes([self.data[6], self.data[7]]) & 0x1fff;
+            let payload = match fragment_offset {
+                0 => Payload::Transport(self.data.get(offset..).unwrap_or_default()),
+                _ => Payload::LaterFragment,
+            };
+            return (self.data[9], payload);
+        }
+        let mut next = self.data[6];
+        let mut rest = &self.data[40..];
+        loop {
+            let len = match next {
+                HOP_BY_HOP | ROUTING | DESTINATION_OPTIONS if rest.len() >= 2 => {
+                    (rest[1] as usize + 1) * 8
+                }
+                FRAGMENT => 8,
+                AUTHENTICATION if rest.len() >= 2 => (rest[1] as usize + 2) * 4,
+                HOP_BY_HOP | ROUTING | DESTINATION_OPTIONS | AUTHENTICATION => {
+                    return (next, Payload::Truncated)
+                }
+                _ => return (next, Payload::Transport(rest)),
+            };
+            if rest.len() < len {
+                return (next, Payload::Truncated);
+            }
+            let fragment_offset = u16::from_be_bytes([rest[2], rest[3]]) >> 3;
+            let later_fragment = next == FRAGMENT && fragment_offset != 0;
+            next = rest[0];
+            rest = &rest[len..];
+            if later_fragment {
+                return (next, Payload::LaterFragment);
+            }
+        }
+    }
+
+    /// Source and destination port for TCP, UDP and SCTP, `None` for other protocols, for
+    /// fragments other than the first or when the transport header is cut short
+    pub fn ports(&self) -> Option<(u16, u16)> {
+        match self.upper_layer() {
+            (PROTOCOL_TCP | PROTOCOL_UDP | PROTOCOL_SCTP, Payload::Transport(payload))
+                if payload.len() >= 4 =>
+            {
+                Some((
+                    u16::from_be_bytes([payload[0], payload[1]]),
+                    u16::from_be_bytes([payload[2], payload[3]]),
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the packet may carry ports that cannot be read: a fragment other than the first,
+    /// or headers cut short
+    pub fn ports_hidden(&self) -> bool {
+        match self.upper_layer() {
+            (PROTOCOL_TCP | PROTOCOL_UDP | PROTOCOL_SCTP, Payload::Transport(payload)) => {
+                payload.len() < 4
+            }
+            (_, Payload::Transport(_)) => false,
+            (_, Payload::LaterFragment | Payload::Truncated) => true,
+        }
+    }
+
     pub fn computed_len(&self) -> u16 {
         match self.version {
             4 => u16::from_be_bytes(TryInto::<[u8; 2]>::try_into(&self.data[2..4]).unwrap()),
@@ -111,4 +205,54 @@ mod tests {
         let header = IpHeader::from_slice(&data);
         dbg!(header);
     }
+
+    #[test]
+    fn test_ports() {
+        let mut data = vec![0; 28];
+        data[0] = 0x45;
+        data[9] = PROTOCOL_UDP;
+        data[20..24].copy_from_slice(&[0x30, 0x39, 0x01, 0xbb]);
+        let header = IpHeader::from_slice(&data).unwrap();
+        assert_eq!(header.protocol(), PROTOCOL_UDP);
+        assert_eq!(header.ports(), Some((12345, 443)));
+
+        data[9] = PROTOCOL_ICMP;
+        assert_eq!(IpHeader::from_slice(&data).unwrap().ports(), None);
+        data[9] = PROTOCOL_TCP;
+        assert_eq!(IpHeader::from_slice(&data[..22]).unwrap().ports(), None);
+        assert!(IpHeader::from_slice(&data[..22]).unwrap().ports_hidden());
+
+        // A later fragment starts with data, not the transport header
+        data[6..8].copy_from_slice(&185u16.to_be_bytes());
+        let header = IpHeader::from_slice(&data).unwrap();
+        assert_eq!((header.protocol(), header.ports()), (PROTOCOL_TCP, None));
+        assert!(header.ports_hidden());
+    }
+
+    #[test]
+    fn ipv6_extension_headers() {
+        // Hop-by-hop options, then a first fragment, then TCP
+        let mut data = vec![0; 40 + 8 + 8 + 20];
+        data[0] = 0x60;
+        data[6] = HOP_BY_HOP;
+        data[40] = FRAGMENT;
+        data[48] = PROTOCOL_TCP;
+        data[56..60].copy_from_slice(&[0x9c, 0x40, 0x00, 0x16]);
+        let header = IpHeader::from_slice(&data).unwrap();
+        assert_eq!(header.protocol(), PROTOCOL_TCP);
+        assert_eq!(header.ports(), Some((40000, 22)));
+        assert!(!header.ports_hidden());
+
+        // The same as a later fragment
+        data[50..52].copy_from_slice(&(185u16 << 3).to_be_bytes());
+        let header = IpHeader::from_slice(&data).unwrap();
+        assert_eq!((header.protocol(), header.ports()), (PROTOCOL_TCP, None));
+        assert!(header.ports_hidden());
+
+        // Options longer than the packet
+        data[41] = 200;
+        let header = IpHeader::from_slice(&data).unwrap();
+        assert_eq!(header.ports(), None);
+        assert!(header.ports_hidden());
+    }
 }
@@ -73,7 +73,8 @@ mod tests {
     #[tokio::test]
     async fn test_io() {
         let tun = TunIo::open().expect("failed to open tun");
-        let async_fd = tokio::io::unix::AsyncFd::new(tun).expect("failed to get async_fd");
+        let async_fd =
+            unsafe { tokio::io::unix::AsyncFd::register(tun) }.expect("failed to get async_fd");
 
         dbg!(async_fd);
     }
@@ -2,7 +2,7 @@ use super::io::TunIo;
 use futures::ready;
 use libc::*;
 use std::io::{Read, Write};
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::io::unix::AsyncFd;
@@ -52,17 +52,46 @@ impl TunStream {
                 ifru_flags: (IFF_TUN | IFF_NO_PI | IFF_MULTI_QUEUE) as _,
             },
         };
-        req.ifr_name[..name.as_bytes().len()].copy_from_slice(name.as_bytes());
+        req.ifr_name[..name.len()].copy_from_slice(name.as_bytes());
         if unsafe { ioctl(io.as_raw_fd(), 0x4004_54ca as _, &req) } < 0 {
             return Err(std::io::Error::last_os_error());
         }
         // unsafe { tunsetiff(tun_io.as_raw_fd(), &req as *const _ as _) }?;
         Ok(TunStream {
-            fd: AsyncFd::new(io)?,
+            // SAFETY: `TunIo` owns the descriptor and only closes it on drop
+            fd: unsafe { AsyncFd::register(io) }?,
             name: name.to_string(),
         })
     }
 
+    /// Take over a TUN descriptor opened by someone else, such as a privileged helper, which
+    /// keeps the interface name it was created with
+    pub fn from_fd(fd: OwnedFd) -> std::io::Result<Self> {
+        // SAFETY: the descriptor is owned, `TunIo` closes it on drop
+        let io = unsafe { TunIo::from_raw_fd(fd.into_raw_fd()) };
+        let mut req = ifreq {
+            ifr_name: [0; IFNAMSIZ],
+            ifr_ifru: IfrIfru { ifru_flags: 0 },
+        };
+        // TUNGETIFF, fails on anything but a TUN device
+        if unsafe { ioctl(io.as_raw_fd(), 0x8004_54d2u32 as _, &mut req) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let flags = unsafe { fcntl(io.as_raw_fd(), F_GETFL) };
+        if flags < 0 || unsafe { fcntl(io.as_raw_fd(), F_SETFL, flags | O_NONBLOCK) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let len = req
+            .ifr_name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(IFNAMSIZ);
+        Ok(TunStream {
+            name: String::from_utf8_lossy(&req.ifr_name[..len]).into_owned(),
+            fd: unsafe { AsyncFd::register(io) }?,
+        })
+    }
+
     pub fn mtu(&self) -> std::io::Result<usize> {
         let fd = match unsafe { socket(AF_INET, SOCK_STREAM, IPPROTO_IP) } {
             -1 => return Err(std::io::Error::last_os_error()),
@@ -74,7 +103,7 @@ impl TunStream {
             ifr_ifru: IfrIfru { ifru_mtu: 0 },
         };
 
-        ifr.ifr_name[..self.name.as_bytes().len()].copy_from_slice(self.name.as_bytes());
+        ifr.ifr_name[..self.name.len()].copy_from_slice(self.name.as_bytes());
 
         if unsafe { ioctl(fd, SIOCGIFMTU as _, &ifr) } < 0 {
             return Err(std::io::Error::last_os_error());
@@ -148,7 +177,23 @@ mod tests {
     use tokio::io::AsyncReadExt;
 
     use super::*;
+
+    #[tokio::test]
+    async fn from_fd() {
+        let tun = TunStream::new("wgloop23").unwrap();
+        let fd = unsafe { OwnedFd::from_raw_fd(dup(tun.fd.as_raw_fd())) };
+        drop(tun);
+        // The duplicate keeps the interface alive
+        let tun = TunStream::from_fd(fd).unwrap();
+        assert_eq!(tun.name, "wgloop23");
+        assert!(tun.mtu().unwrap() > 0);
+
+        let not_tun = std::fs::File::open("/dev/null").unwrap();
+        assert!(TunStream::from_fd(not_tun.into()).is_err());
+    }
+
     #[tokio::test]
+    #[ignore = "reads from the interface forever"]
     async fn test_tun() {
         let tun = TunStream::new("utun106").unwrap();
         let mut tun = dbg!(tun);
@@ -0,0 +1,344 @@
+//! Client side of the control socket, see [`Client`].
+//!
+//! [`show`] formats what a device reports the way `wg show` and `wg showconf` do and
+//! [`set::set_args`] turns `wg set` arguments into a request, so that scripts written for
+//! `wg(8)` work the same against wg-rs.
+
+use std::{
+    io,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ip_network::IpNetwork;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpStream, UnixStream},
+};
+use zeroize::Zeroizing;
+
+use crate::{
+    device::{
+        allowed_ip::AllowedIP,
+        api::{self, SOCK_DIR},
+        endpoint::Endpoint,
+        DeviceConfig,
+    },
+    error::{WgError, WgResult},
+    key_bytes::KeyBytes,
+    secret::Secret,
+    x25519,
+};
+
+pub mod set;
+pub mod show;
+
+/// What a device reports to `get=1`
+#[derive(Debug, Default)]
+pub struct InterfaceState {
+    pub name: String,
+    pub private_key: Option<Secret>,
+    pub listen_port: u16,
+    pub fwmark: u32,
+    pub peers: Vec<PeerState>,
+}
+
+#[derive(Debug)]
+pub struct PeerState {
+    pub public_key: x25519::PublicKey,
+    pub preshared_key: Option<Secret>,
+    pub endpoint: Option<Endpoint>,
+    pub last_handshake: Option<SystemTime>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// In seconds, 0 when disabled
+    pub persistent_keepalive: u16,
+    pub allowed_ips: Vec<IpNetwork>,
+}
+
+impl PeerState {
+    fn new(public_key: x25519::PublicKey) -> Self {
+        PeerState {
+            public_key,
+            preshared_key: None,
+            endpoint: None,
+            last_handshake: None,
+            rx_bytes: 0,
+            tx_bytes: 0,
+            persistent_keepalive: 0,
+            allowed_ips: Vec::new(),
+        }
+    }
+}
+
+impl InterfaceState {
+    pub fn public_key(&self) -> Option<x25519::PublicKey> {
+        let private_key = x25519::StaticSecret::from(*self.private_key.as_ref()?.expose());
+        Some(x25519::PublicKey::from(&private_key))
+    }
+
+    /// Parse the lines of a `get=1` response, up to the `errno` line
+    pub fn from_uapi(name: &str, lines: &[String]) -> WgResult<Self> {
+        let mut state = InterfaceState {
+            name: name.to_owned(),
+            ..Default::default()
+        };
+        for line in lines {
+            let invalid = || WgError::Protocol(format!("invalid line {line}"));
+            let (key, val) = line.split_once('=').ok_or_else(invalid)?;
+            let key_bytes = || val.parse::<KeyBytes>().map_err(|_| invalid());
+            let number = || val.parse::<u64>().map_err(|_| invalid());
+            match (key, state.peers.last_mut()) {
+                ("public_key", _) => {
+                    let public_key = x25519::PublicKey::from(key_bytes()?.0);
+                    state.peers.push(PeerState::new(public_key));
+                }
+                ("private_key", None) => {
+                    state.private_key = Some(Secret::new(key_bytes()?.0)).filter(|k| !k.is_zero())
+                }
+                ("listen_port", None) => state.listen_port = val.parse().map_err(|_| invalid())?,
+                ("fwmark", None) => state.fwmark = val.parse().map_err(|_| invalid())?,
+                ("preshared_key", Some(peer)) => {
+                    peer.preshared_key = Some(Secret::new(key_bytes()?.0)).filter(|k| !k.is_zero())
+                }
+                ("endpoint", Some(peer)) => {
+                    peer.endpoint = Some(val.parse().map_err(|_| invalid())?)
+                }
+                ("last_handshake_time_sec", Some(peer)) => {
+                    peer.last_handshake = match number()? {
+                        0 => None,
+                        secs => Some(UNIX_EPOCH + Duration::from_secs(secs)),
+                    }
+                }
+                ("last_handshake_time_nsec", Some(peer)) => {
+                    let nanos = Duration::from_nanos(number()?);
+                    peer.last_handshake = peer.last_handshake.map(|time| time + nanos);
+                }
+                ("rx_bytes", Some(peer)) => peer.rx_bytes = number()?,
+                ("tx_bytes", Some(peer)) => peer.tx_bytes = number()?,
+                ("persistent_keepalive_interval", Some(peer)) => {
+                    peer.persistent_keepalive = val.parse().map_err(|_| invalid())?
+                }
+                ("allowed_ip", Some(peer)) => {
+                    let network = val
+                        .parse::<AllowedIP>()
+                        .ok()
+                        .and_then(|ip| ip.network())
+                        .ok_or_else(invalid)?;
+                    peer.allowed_ips.push(network);
+                }
+                // Extensions of this or other implementations
+                _ => {}
+            }
+        }
+        Ok(state)
+    }
+}
+
+/// A client of the control socket of one device
+pub struct Client {
+    name: String,
+    target: Target,
+}
+
+enum Target {
+    Unix(PathBuf),
+    Tcp {
+        addr: String,
+        token: Zeroizing<String>,
+    },
+}
+
+impl Client {
+    /// The client of device `name`, whose socket is in [`SOCK_DIR`]
+    pub fn new(name: &str) -> Self {
+        Self::with_path(name, api::socket_path(name))
+    }
+
+    pub fn with_path(name: &str, path: impl Into<PathBuf>) -> Self {
+        Client {
+            name: name.to_owned(),
+            target: Target::Unix(path.into()),
+        }
+    }
+
+    /// The client of device `name` serving the control protocol over TCP at `addr`, see
+    /// [`ApiTcp`](crate::device::api::ApiTcp)
+    pub fn tcp(name: &str, addr: impl Into<String>, token: impl Into<String>) -> Self {
+        Client {
+            name: name.to_owned(),
+            target: Target::Tcp {
+                addr: addr.into(),
+                token: Zeroizing::new(token.into()),
+            },
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The configuration and statistics of the device, keys included
+    pub async fn get(&self) -> WgResult<InterfaceState> {
+        let lines = self
+            .request("get=1", &["reveal_keys=true".to_owned()])
+            .await?;
+        InterfaceState::from_uapi(&self.name, &lines)
+    }
+
+    /// Send the lines of a `set=1` request
+    pub async fn set(&self, lines: &[String]) -> WgResult<()> {
+        self.request("set=1", lines).await.map(|_| ())
+    }
+
+    /// Apply `config` like `wg setconf` does, or like `wg addconf` without `replace_peers`
+    pub async fn set_config(&self, config: &DeviceConfig, replace_peers: bool) -> WgResult<()> {
+        self.set(&config.to_uapi(replace_peers)).await
+    }
+
+    /// Apply `config` like `wg syncconf` does: the peers it does not list are removed and the
+    /// others are changed in place, which keeps their sessions. The device works out the
+    /// difference, see [`Device::sync_config`](crate::device::Device::sync_config).
+    pub async fn sync_config(&self, config: &DeviceConfig) -> WgResult<()> {
+        self.request("sync=1", &config.to_uapi(false))
+            .await
+            .map(|_| ())
+    }
+
+    async fn request(&self, command: &str, lines: &[String]) -> WgResult<Vec<String>> {
+        let mut request = Zeroizing::new(format!("{command}\n"));
+        for line in lines {
+            request.push_str(line);
+            request.push('\n');
+        }
+        request.push('\n');
+        match &self.target {
+            Target::Unix(path) => exchange(UnixStream::connect(path).await?, &request).await,
+            Target::Tcp { addr, token } => {
+                let mut stream = TcpStream::connect(addr.as_str()).await?;
+                let line = Zeroizing::new(format!("token={}\n", token.as_str()));
+                stream.write_all(line.as_bytes()).await?;
+                exchange(stream, &request).await
+            }
+        }
+    }
+}
+
+/// Send `request` and read the response up to its errno
+async fn exchange<S>(mut stream: S, request: &str) -> WgResult<Vec<String>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    let mut reader = BufReader::new(stream).lines();
+    while let Some(line) = reader.next_line().await? {
+        if let Some(errno) = line.strip_prefix("errno=") {
+            return match errno.parse() {
+                Ok(0) => Ok(response),
+                Ok(errno) => Err(io::Error::from_raw_os_error(errno).into()),
+                Err(_) => Err(WgError::Protocol(format!("invalid line {line}"))),
+            };
+        }
+        response.push(line);
+    }
+    Err(WgError::Protocol(
+        "connection closed without errno".to_owned(),
+    ))
+}
+
+/// The names of the devices with a control socket in [`SOCK_DIR`]
+pub async fn interfaces() -> WgResult<Vec<String>> {
+    let mut entries = match tokio::fs::read_dir(SOCK_DIR).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut names = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        if let Some(name) = entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.strip_suffix(".sock"))
+        {
+            names.push(name.to_owned());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::{testing::device, transport::loopback::LoopbackNetwork};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn wg_tools_round_trip() {
+        let network = LoopbackNetwork::new();
+        let (a, _) = device("wgloop20", &network, "192.0.2.1:51820".parse().unwrap()).await;
+        let client = Client::new("wgloop20");
+        let private_key = KeyBytes::generate_private();
+        let (key_b, key_c) = (KeyBytes([9; 32]), KeyBytes([10; 32]));
+        let config: DeviceConfig = format!(
+            "[Interface]
+            PrivateKey = {private_key}
+
+            [Peer]
+            PublicKey = {key_b}
+            PresharedKey = {}
+            AllowedIPs = 10.0.0.2/32, 10.1.0.0/16
+            Endpoint = 192.0.2.2:51820
+
+            [Peer]
+            PublicKey = {key_c}
+            AllowedIPs = 10.0.0.3/32
+            PersistentKeepalive = 25",
+            KeyBytes([5; 32])
+        )
+        .parse()
+        .unwrap();
+        client.set_config(&config, true).await.unwrap();
+
+        let state = client.get().await.unwrap();
+        assert_eq!(
+            state.public_key().unwrap().to_bytes(),
+            private_key.public_key().0
+        );
+        assert_eq!(state.peers.len(), 2);
+        let conf = show::showconf(&state);
+        assert!(conf.contains(&format!("PrivateKey = {private_key}\n")));
+        assert!(conf.contains("AllowedIPs = 10.0.0.2/32, 10.1.0.0/16\n"));
+        assert!(show::show(&state).contains("preshared key: (hidden)"));
+        // What showconf prints is a config setconf takes back
+        let reparsed: DeviceConfig = conf.parse().unwrap();
+        assert_eq!(reparsed.peers.len(), 2);
+
+        // syncconf drops C and resets B's preshared key, which the file no longer sets
+        let synced: DeviceConfig = format!(
+            "[Interface]\nPrivateKey = {private_key}\n\n[Peer]\nPublicKey = {key_b}\nAllowedIPs = 10.0.0.2/32"
+        )
+        .parse()
+        .unwrap();
+        client.sync_config(&synced).await.unwrap();
+        let state = client.get().await.unwrap();
+        assert_eq!(state.peers.len(), 1);
+        assert!(state.peers[0].preshared_key.is_none());
+        assert_eq!(state.peers[0].allowed_ips.len(), 1);
+
+        let lines = set::set_args(&[
+            "peer".to_owned(),
+            key_b.to_string(),
+            "persistent-keepalive".to_owned(),
+            "15".to_owned(),
+        ])
+        .unwrap();
+        client.set(&lines).await.unwrap();
+        let field = show::show_field(&client.get().await.unwrap(), "persistent-keepalive", false);
+        assert_eq!(field.unwrap(), format!("{key_b}\t15\n"));
+
+        a.close();
+    }
+}
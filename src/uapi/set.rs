@@ -0,0 +1,127 @@
+//! The arguments of `wg set`.
+
+use crate::{
+    device::{allowed_ip::AllowedIP, endpoint::Endpoint},
+    error::{WgError, WgResult},
+    key_bytes::KeyBytes,
+};
+
+/// The `set=1` lines for the arguments following `wg set <interface>`, e.g.
+/// `listen-port 51820 peer <key> allowed-ips 10.0.0.2/32 endpoint 192.0.2.1:51820`
+pub fn set_args(args: &[String]) -> WgResult<Vec<String>> {
+    let mut lines = Vec::new();
+    let mut in_peer = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| WgError::InvalidValue(format!("Missing value for {arg}")))
+        };
+        match arg.as_str() {
+            "listen-port" if !in_peer => {
+                let port: u16 = value()?.parse().map_err(|_| invalid(arg))?;
+                lines.push(format!("listen_port={port}"));
+            }
+            "fwmark" if !in_peer => lines.push(format!("fwmark={}", parse_fwmark(value()?)?)),
+            "private-key" if !in_peer => {
+                lines.push(format!("private_key={}", read_key_file(value()?)?.to_hex()))
+            }
+            "peer" => {
+                let key: KeyBytes = value()?.parse().map_err(|_| invalid(arg))?;
+                lines.push(format!("public_key={}", key.to_hex()));
+                in_peer = true;
+            }
+            "remove" if in_peer => lines.push("remove=true".to_owned()),
+            "endpoint" if in_peer => {
+                let endpoint: Endpoint = value()?.parse().map_err(|_| invalid(arg))?;
+                lines.push(format!("endpoint={endpoint}"));
+            }
+            "persistent-keepalive" if in_peer => {
+                let interval: u16 = match value()?.as_str() {
+                    "off" => 0,
+                    interval => interval.parse().map_err(|_| invalid(arg))?,
+                };
+                lines.push(format!("persistent_keepalive_interval={interval}"));
+            }
+            "preshared-key" if in_peer => lines.push(format!(
+                "preshared_key={}",
+                read_key_file(value()?)?.to_hex()
+            )),
+            "allowed-ips" if in_peer => {
+                let ips = value()?;
+                lines.push("replace_allowed_ips=true".to_owned());
+                for ip in ips.split(',').map(str::trim).filter(|ip| !ip.is_empty()) {
+                    let ip: AllowedIP = ip.parse().map_err(|_| invalid(arg))?;
+                    lines.push(format!("allowed_ip={ip}"));
+                }
+            }
+            _ => return Err(WgError::InvalidValue(format!("Invalid argument: {arg}"))),
+        }
+    }
+    Ok(lines)
+}
+
+fn invalid(arg: &str) -> WgError {
+    WgError::InvalidValue(format!("Invalid value for {arg}"))
+}
+
+fn parse_fwmark(val: &str) -> WgResult<u32> {
+    let fwmark = match val.strip_prefix("0x") {
+        _ if val == "off" => Ok(0),
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => val.parse(),
+    };
+    fwmark.map_err(|_| invalid("fwmark"))
+}
+
+/// A key stored in a file like `wg genkey` writes it; an empty file such as `/dev/null` stands
+/// for no key
+fn read_key_file(path: &str) -> WgResult<KeyBytes> {
+    let contents = std::fs::read_to_string(path)?;
+    match contents.trim() {
+        "" => Ok(KeyBytes([0; 32])),
+        key => key
+            .parse()
+            .map_err(|e| WgError::InvalidValue(format!("Key in {path}: {e}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=";
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn parse_set_args() {
+        let lines = set_args(&args(&format!(
+            "listen-port 51820 fwmark 0x10 peer {PEER} endpoint 192.0.2.1:51820 \
+             persistent-keepalive off preshared-key /dev/null allowed-ips 10.0.0.2/32,fd00::/64"
+        )))
+        .unwrap();
+        let hex = PEER.parse::<KeyBytes>().unwrap().to_hex();
+        assert_eq!(
+            lines,
+            [
+                "listen_port=51820".to_owned(),
+                "fwmark=16".to_owned(),
+                format!("public_key={hex}"),
+                "endpoint=192.0.2.1:51820".to_owned(),
+                "persistent_keepalive_interval=0".to_owned(),
+                format!("preshared_key={}", "0".repeat(64)),
+                "replace_allowed_ips=true".to_owned(),
+                "allowed_ip=10.0.0.2/32".to_owned(),
+                "allowed_ip=fd00::/64".to_owned(),
+            ]
+        );
+
+        for invalid in ["remove", "listen-port", "peer nope", "bogus 1"] {
+            assert!(set_args(&args(invalid)).is_err(), "{invalid}");
+        }
+        assert!(set_args(&args(&format!("peer {PEER} listen-port 1"))).is_err());
+    }
+}
@@ -0,0 +1,354 @@
+//! The output formats of `wg show` and `wg showconf`.
+
+use std::{fmt::Write, time::SystemTime};
+
+use ip_network::IpNetwork;
+
+use super::{InterfaceState, PeerState};
+use crate::{
+    error::{WgError, WgResult},
+    key_bytes::KeyBytes,
+    secret::Secret,
+    x25519,
+};
+
+/// The fields `wg show <interface> <field>` prints
+pub const FIELDS: &[&str] = &[
+    "public-key",
+    "private-key",
+    "listen-port",
+    "fwmark",
+    "peers",
+    "preshared-keys",
+    "endpoints",
+    "allowed-ips",
+    "latest-handshakes",
+    "transfer",
+    "persistent-keepalive",
+    "dump",
+];
+
+/// The human readable report of `wg show <interface>`, keys are hidden
+pub fn show(state: &InterfaceState) -> String {
+    show_at(state, SystemTime::now())
+}
+
+fn show_at(state: &InterfaceState, now: SystemTime) -> String {
+    let mut out = format!("interface: {}\n", state.name);
+    if let Some(public_key) = state.public_key() {
+        let _ = writeln!(out, "  public key: {}", public(&public_key));
+    }
+    if state.private_key.is_some() {
+        out.push_str("  private key: (hidden)\n");
+    }
+    if state.listen_port != 0 {
+        let _ = writeln!(out, "  listening port: {}", state.listen_port);
+    }
+    if state.fwmark != 0 {
+        let _ = writeln!(out, "  fwmark: {:#x}", state.fwmark);
+    }
+
+    // Most recent handshake first, peers that never had one last
+    let mut peers: Vec<_> = state.peers.iter().collect();
+    peers.sort_by_key(|peer| std::cmp::Reverse(peer.last_handshake));
+    for peer in peers {
+        let _ = writeln!(out, "\npeer: {}", public(&peer.public_key));
+        if peer.preshared_key.is_some() {
+            out.push_str("  preshared key: (hidden)\n");
+        }
+        if let Some(endpoint) = &peer.endpoint {
+            let _ = writeln!(out, "  endpoint: {endpoint}");
+        }
+        let _ = writeln!(out, "  allowed ips: {}", allowed_ips(peer, ", "));
+        if let Some(handshake) = peer.last_handshake {
+            let _ = writeln!(out, "  latest handshake: {}", ago(handshake, now));
+        }
+        if peer.rx_bytes != 0 || peer.tx_bytes != 0 {
+            let _ = writeln!(
+                out,
+                "  transfer: {} received, {} sent",
+                bytes(peer.rx_bytes),
+                bytes(peer.tx_bytes)
+            );
+        }
+        if peer.persistent_keepalive != 0 {
+            let _ = writeln!(
+                out,
+                "  persistent keepalive: every {}",
+                pretty_time(peer.persistent_keepalive.into())
+            );
+        }
+    }
+    out
+}
+
+/// The configuration file of `wg showconf <interface>`, which `setconf` takes back
+pub fn showconf(state: &InterfaceState) -> String {
+    let mut out = "[Interface]\n".to_owned();
+    if state.listen_port != 0 {
+        let _ = writeln!(out, "ListenPort = {}", state.listen_port);
+    }
+    if state.fwmark != 0 {
+        let _ = writeln!(out, "FwMark = {:#x}", state.fwmark);
+    }
+    if let Some(private_key) = &state.private_key {
+        let _ = writeln!(out, "PrivateKey = {}", key(private_key));
+    }
+    for peer in &state.peers {
+        let _ = writeln!(out, "\n[Peer]\nPublicKey = {}", public(&peer.public_key));
+        if let Some(preshared_key) = &peer.preshared_key {
+            let _ = writeln!(out, "PresharedKey = {}", key(preshared_key));
+        }
+        if !peer.allowed_ips.is_empty() {
+            let _ = writeln!(out, "AllowedIPs = {}", allowed_ips(peer, ", "));
+        }
+        if let Some(endpoint) = &peer.endpoint {
+            let _ = writeln!(out, "Endpoint = {endpoint}");
+        }
+        if peer.persistent_keepalive != 0 {
+            let _ = writeln!(out, "PersistentKeepalive = {}", peer.persistent_keepalive);
+        }
+    }
+    out
+}
+
+/// One of the [`FIELDS`] in the tab separated format of `wg show <interface> <field>`. With
+/// `with_name` every line starts with the interface name, like `wg show all <field>` does.
+pub fn show_field(state: &InterfaceState, field: &str, with_name: bool) -> WgResult<String> {
+    let prefix = if with_name {
+        format!("{}\t", state.name)
+    } else {
+        String::new()
+    };
+    let public_key = state
+        .public_key()
+        .map_or("(none)".to_owned(), |k| public(&k));
+    let private_key = state.private_key.as_ref().map_or("(none)".to_owned(), key);
+    let fwmark = match state.fwmark {
+        0 => "off".to_owned(),
+        fwmark => format!("{fwmark:#x}"),
+    };
+    let per_peer = |line: &dyn Fn(&PeerState) -> String| {
+        state.peers.iter().fold(String::new(), |mut out, peer| {
+            let _ = writeln!(out, "{prefix}{}", line(peer));
+            out
+        })
+    };
+
+    Ok(match field {
+        "public-key" => format!("{prefix}{public_key}\n"),
+        "private-key" => format!("{prefix}{private_key}\n"),
+        "listen-port" => format!("{prefix}{}\n", state.listen_port),
+        "fwmark" => format!("{prefix}{fwmark}\n"),
+        "peers" => per_peer(&|peer| public(&peer.public_key)),
+        "preshared-keys" => {
+            per_peer(&|peer| format!("{}\t{}", public(&peer.public_key), preshared_key(peer)))
+        }
+        "endpoints" => {
+            per_peer(&|peer| format!("{}\t{}", public(&peer.public_key), endpoint(peer)))
+        }
+        "allowed-ips" => {
+            per_peer(&|peer| format!("{}\t{}", public(&peer.public_key), allowed_ips(peer, " ")))
+        }
+        "latest-handshakes" => {
+            per_peer(&|peer| format!("{}\t{}", public(&peer.public_key), handshake_secs(peer)))
+        }
+        "transfer" => per_peer(&|peer| {
+            format!(
+                "{}\t{}\t{}",
+                public(&peer.public_key),
+                peer.rx_bytes,
+                peer.tx_bytes
+            )
+        }),
+        "persistent-keepalive" => {
+            per_peer(&|peer| format!("{}\t{}", public(&peer.public_key), keepalive(peer)))
+        }
+        "dump" => {
+            let interface = format!(
+                "{prefix}{private_key}\t{public_key}\t{}\t{fwmark}\n",
+                state.listen_port
+            );
+            interface
+                + &per_peer(&|peer| {
+                    format!(
+                        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                        public(&peer.public_key),
+                        preshared_key(peer),
+                        endpoint(peer),
+                        allowed_ips(peer, ","),
+                        handshake_secs(peer),
+                        peer.rx_bytes,
+                        peer.tx_bytes,
+                        keepalive(peer)
+                    )
+                })
+        }
+        _ => {
+            return Err(WgError::InvalidValue(format!(
+                "Invalid parameter: `{field}'"
+            )))
+        }
+    })
+}
+
+fn key(secret: &Secret) -> String {
+    KeyBytes(*secret.expose()).to_string()
+}
+
+fn public(public_key: &x25519::PublicKey) -> String {
+    KeyBytes(public_key.to_bytes()).to_string()
+}
+
+fn preshared_key(peer: &PeerState) -> String {
+    peer.preshared_key.as_ref().map_or("(none)".to_owned(), key)
+}
+
+fn endpoint(peer: &PeerState) -> String {
+    peer.endpoint.map_or("(none)".to_owned(), |e| e.to_string())
+}
+
+fn allowed_ips(peer: &PeerState, separator: &str) -> String {
+    if peer.allowed_ips.is_empty() {
+        return "(none)".to_owned();
+    }
+    let networks: Vec<_> = peer.allowed_ips.iter().map(IpNetwork::to_string).collect();
+    networks.join(separator)
+}
+
+fn handshake_secs(peer: &PeerState) -> u64 {
+    peer.last_handshake
+        .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs())
+}
+
+fn keepalive(peer: &PeerState) -> String {
+    match peer.persistent_keepalive {
+        0 => "off".to_owned(),
+        interval => interval.to_string(),
+    }
+}
+
+fn ago(time: SystemTime, now: SystemTime) -> String {
+    match now.duration_since(time) {
+        Ok(since) if since.as_secs() == 0 => "Now".to_owned(),
+        Ok(since) => format!("{} ago", pretty_time(since.as_secs())),
+        Err(_) => "(System clock wound backward; connection problems may ensue.)".to_owned(),
+    }
+}
+
+fn pretty_time(mut secs: u64) -> String {
+    let mut parts = Vec::new();
+    for (unit, len) in [
+        ("year", 365 * 24 * 60 * 60),
+        ("day", 24 * 60 * 60),
+        ("hour", 60 * 60),
+        ("minute", 60),
+        ("second", 1),
+    ] {
+        let count = secs / len;
+        secs %= len;
+        if count != 0 {
+            let plural = if count == 1 { "" } else { "s" };
+            parts.push(format!("{count} {unit}{plural}"));
+        }
+    }
+    parts.join(", ")
+}
+
+fn bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.2} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    const PRIVATE_KEY: &str = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=";
+    const PEER: &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=";
+
+    fn state() -> InterfaceState {
+        let lines = [
+            format!(
+                "private_key={}",
+                PRIVATE_KEY.parse::<KeyBytes>().unwrap().to_hex()
+            ),
+            "listen_port=51820".to_owned(),
+            format!("public_key={}", PEER.parse::<KeyBytes>().unwrap().to_hex()),
+            "endpoint=192.0.2.1:51820".to_owned(),
+            "last_handshake_time_sec=1000".to_owned(),
+            "last_handshake_time_nsec=0".to_owned(),
+            "rx_bytes=2048".to_owned(),
+            "tx_bytes=100".to_owned(),
+            "persistent_keepalive_interval=25".to_owned(),
+            "allowed_ip=10.0.0.2/32".to_owned(),
+            "allowed_ip=fd00::/64".to_owned(),
+            "protocol_version=1".to_owned(),
+        ];
+        InterfaceState::from_uapi("wg0", &lines).unwrap()
+    }
+
+    #[test]
+    fn show_formats() {
+        let state = state();
+        let now = UNIX_EPOCH + Duration::from_secs(1000 + 3661);
+        assert_eq!(
+            show_at(&state, now),
+            format!(
+                "interface: wg0
+  public key: HIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw=
+  private key: (hidden)
+  listening port: 51820
+
+peer: {PEER}
+  endpoint: 192.0.2.1:51820
+  allowed ips: 10.0.0.2/32, fd00::/64
+  latest handshake: 1 hour, 1 minute, 1 second ago
+  transfer: 2.00 KiB received, 100 B sent
+  persistent keepalive: every 25 seconds
+"
+            )
+        );
+        assert_eq!(
+            showconf(&state),
+            format!(
+                "[Interface]
+ListenPort = 51820
+PrivateKey = {PRIVATE_KEY}
+
+[Peer]
+PublicKey = {PEER}
+AllowedIPs = 10.0.0.2/32, fd00::/64
+Endpoint = 192.0.2.1:51820
+PersistentKeepalive = 25
+"
+            )
+        );
+        assert_eq!(
+            show_field(&state, "transfer", true).unwrap(),
+            format!("wg0\t{PEER}\t2048\t100\n")
+        );
+        assert_eq!(
+            show_field(&state, "dump", false).unwrap(),
+            format!(
+                "{PRIVATE_KEY}\tHIgo9xNzJMWLKASShiTqIybxZ0U3wGLiUeJ1PKf8ykw=\t51820\toff\n\
+                 {PEER}\t(none)\t192.0.2.1:51820\t10.0.0.2/32,fd00::/64\t1000\t2048\t100\t25\n"
+            )
+        );
+        assert!(show_field(&state, "bogus", false).is_err());
+        assert_eq!(bytes(3 << 30), "3.00 GiB");
+        assert_eq!(pretty_time(2 * 24 * 60 * 60 + 1), "2 days, 1 second");
+    }
+}